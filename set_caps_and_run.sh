@@ -1,4 +1,4 @@
-#!/bin/sh
+#!/bin/bash
 set -euo pipefail
 
 if ! ps aux | grep $(ps -o ppid= $$) | grep -q 'cargo test' ; then
@@ -0,0 +1,247 @@
+use byteorder::{ByteOrder, NetworkEndian, WriteBytesExt};
+
+pub const OPT_PARAM_CAPABILITIES: u8 = 2;
+
+const CAPABILITY_MULTIPROTOCOL: u8 = 1;
+const CAPABILITY_ROUTE_REFRESH: u8 = 2;
+const CAPABILITY_GRACEFUL_RESTART: u8 = 64;
+const CAPABILITY_ADD_PATH: u8 = 69;
+const CAPABILITY_ENHANCED_ROUTE_REFRESH: u8 = 70;
+
+const ADD_PATH_RECEIVE: u8 = 1;
+const ADD_PATH_SEND: u8 = 2;
+
+const GRACEFUL_RESTART_STATE: u16 = 0x8000;
+const GRACEFUL_RESTART_TIME_MASK: u16 = 0x0FFF;
+const GRACEFUL_RESTART_FORWARDING: u8 = 0x80;
+
+/// Address family entry of the Graceful Restart capability
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct GracefulRestartFamily {
+    pub afi: u16,
+    pub safi: u8,
+    pub forwarding_preserved: bool,
+}
+
+/// Address family entry of the ADD-PATH capability (RFC 7911)
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct AddPathFamily {
+    pub afi: u16,
+    pub safi: u8,
+    pub receive: bool,
+    pub send: bool,
+}
+
+/// Capabilities advertised in the OPEN message (RFC 5492)
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Capability {
+    Multiprotocol { afi: u16, safi: u8 },
+    RouteRefresh,
+    /// RFC 4724, `restart_time` is in seconds and limited to 12 bits
+    GracefulRestart { restart_state: bool, restart_time: u16, families: Vec<GracefulRestartFamily> },
+    AddPath(Vec<AddPathFamily>),
+    EnhancedRouteRefresh,
+    Unknown { code: u8, value: Vec<u8> },
+}
+
+impl Capability {
+    fn code(&self) -> u8 {
+        match self {
+            Capability::Multiprotocol { .. } => CAPABILITY_MULTIPROTOCOL,
+            Capability::RouteRefresh => CAPABILITY_ROUTE_REFRESH,
+            Capability::GracefulRestart { .. } => CAPABILITY_GRACEFUL_RESTART,
+            Capability::AddPath(_) => CAPABILITY_ADD_PATH,
+            Capability::EnhancedRouteRefresh => CAPABILITY_ENHANCED_ROUTE_REFRESH,
+            Capability::Unknown { code, .. } => *code,
+        }
+    }
+
+    fn value(&self) -> Vec<u8> {
+        match self {
+            Capability::Multiprotocol { afi, safi } => {
+                let mut value = afi.to_be_bytes().to_vec();
+                value.push(0);
+                value.push(*safi);
+                value
+            },
+            Capability::GracefulRestart { restart_state, restart_time, families } => {
+                let mut flags_and_time = restart_time & GRACEFUL_RESTART_TIME_MASK;
+                if *restart_state {
+                    flags_and_time |= GRACEFUL_RESTART_STATE;
+                }
+                let mut value = flags_and_time.to_be_bytes().to_vec();
+                for family in families {
+                    value.write_u16::<NetworkEndian>(family.afi).unwrap();
+                    value.push(family.safi);
+                    value.push(if family.forwarding_preserved { GRACEFUL_RESTART_FORWARDING } else { 0 });
+                }
+                value
+            },
+            Capability::AddPath(families) => {
+                let mut value = Vec::new();
+                for family in families {
+                    value.write_u16::<NetworkEndian>(family.afi).unwrap();
+                    value.push(family.safi);
+                    let mut send_receive = 0;
+                    if family.receive {
+                        send_receive |= ADD_PATH_RECEIVE;
+                    }
+                    if family.send {
+                        send_receive |= ADD_PATH_SEND;
+                    }
+                    value.push(send_receive);
+                }
+                value
+            },
+            Capability::RouteRefresh | Capability::EnhancedRouteRefresh => vec![],
+            Capability::Unknown { value, .. } => value.clone(),
+        }
+    }
+
+    fn parse(code: u8, value: &[u8]) -> Capability {
+        match code {
+            CAPABILITY_MULTIPROTOCOL if value.len() == 4 => Capability::Multiprotocol {
+                afi: NetworkEndian::read_u16(&value[0..2]),
+                safi: value[3],
+            },
+            CAPABILITY_ROUTE_REFRESH => Capability::RouteRefresh,
+            CAPABILITY_GRACEFUL_RESTART if value.len() >= 2 => {
+                let flags_and_time = NetworkEndian::read_u16(&value[0..2]);
+                Capability::GracefulRestart {
+                    restart_state: flags_and_time & GRACEFUL_RESTART_STATE != 0,
+                    restart_time: flags_and_time & GRACEFUL_RESTART_TIME_MASK,
+                    families: value[2..].chunks_exact(4).map(|family| GracefulRestartFamily {
+                        afi: NetworkEndian::read_u16(&family[0..2]),
+                        safi: family[2],
+                        forwarding_preserved: family[3] & GRACEFUL_RESTART_FORWARDING != 0,
+                    }).collect(),
+                }
+            },
+            CAPABILITY_ADD_PATH => Capability::AddPath(value.chunks_exact(4).map(|family| AddPathFamily {
+                afi: NetworkEndian::read_u16(&family[0..2]),
+                safi: family[2],
+                receive: family[3] & ADD_PATH_RECEIVE != 0,
+                send: family[3] & ADD_PATH_SEND != 0,
+            }).collect()),
+            CAPABILITY_ENHANCED_ROUTE_REFRESH => Capability::EnhancedRouteRefresh,
+            code => Capability::Unknown { code, value: value.to_vec() },
+        }
+    }
+}
+
+/// Extracts capabilities from the OPEN optional parameters, other parameter types are ignored.
+/// `None` if a parameter or capability runs past the end.
+pub fn extract_capabilities(data: &[u8]) -> Option<Vec<Capability>> {
+    let mut capabilities = Vec::new();
+
+    let mut i = 0;
+    while i < data.len() {
+        let param_type = data[i];
+        let param_length = *data.get(i+1)? as usize;
+        let param = data.get(i+2 .. i+2 + param_length)?;
+        if param_type == OPT_PARAM_CAPABILITIES {
+            let mut j = 0;
+            while j < param.len() {
+                let code = param[j];
+                let length = *param.get(j+1)? as usize;
+                capabilities.push(Capability::parse(code, param.get(j+2 .. j+2 + length)?));
+                j += 2 + length;
+            }
+        }
+        i += 2 + param_length;
+    }
+
+    Some(capabilities)
+}
+
+/// Compiles the capabilities into a single Capabilities optional parameter
+pub fn compile_capabilities(capabilities: &[Capability]) -> Vec<u8> {
+    if capabilities.is_empty() {
+        return vec![];
+    }
+
+    let mut param = Vec::new();
+    for capability in capabilities {
+        let value = capability.value();
+        param.push(capability.code());
+        param.write_u8(value.len() as u8).unwrap();
+        param.extend(value);
+    }
+
+    let mut data = vec![OPT_PARAM_CAPABILITIES, param.len() as u8];
+    data.extend(param);
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extract_capabilities() {
+        assert_eq!(extract_capabilities(&[]), Some(vec![]));
+        assert_eq!(
+            extract_capabilities(&[
+                /* param type */ 2, /* param length */ 8,
+                /* code */ 1, /* length */ 4, /* AFI */ 0, 1, /* reserved */ 0, /* SAFI */ 1,
+                /* code */ 2, /* length */ 0,
+                /* param type */ 2, /* param length */ 5,
+                /* code */ 65, /* length */ 3, 0, 0, 1,
+            ]),
+            Some(vec![
+                Capability::Multiprotocol { afi: 1, safi: 1 },
+                Capability::RouteRefresh,
+                Capability::Unknown { code: 65, value: vec![0, 0, 1] },
+            ])
+        );
+        assert_eq!(extract_capabilities(&[/* param type */ 2, /* param length */ 4, /* code */ 1, /* length */ 4]), None);
+        assert_eq!(extract_capabilities(&[/* param type */ 2, /* param length */ 3, /* code */ 1, /* length */ 4, 0]), None);
+    }
+
+    #[test]
+    fn test_graceful_restart_capability() {
+        let capability = Capability::GracefulRestart {
+            restart_state: true,
+            restart_time: 120,
+            families: vec![GracefulRestartFamily { afi: 1, safi: 1, forwarding_preserved: true }],
+        };
+        let compiled = compile_capabilities(std::slice::from_ref(&capability));
+        assert_eq!(compiled, vec![
+            /* param type */ 2, /* param length */ 8,
+            /* code */ 64, /* length */ 6, /* flags and time */ 0x80, 120, /* AFI */ 0, 1, /* SAFI */ 1, /* flags */ 0x80,
+        ]);
+        assert_eq!(extract_capabilities(&compiled), Some(vec![capability]));
+    }
+
+    #[test]
+    fn test_add_path_capability() {
+        let capability = Capability::AddPath(vec![
+            AddPathFamily { afi: 1, safi: 1, receive: true, send: true },
+            AddPathFamily { afi: 2, safi: 1, receive: true, send: false },
+        ]);
+        let compiled = compile_capabilities(std::slice::from_ref(&capability));
+        assert_eq!(compiled, vec![
+            /* param type */ 2, /* param length */ 10,
+            /* code */ 69, /* length */ 8, /* AFI */ 0, 1, /* SAFI */ 1, /* send/receive */ 3, 0, 2, 1, 1,
+        ]);
+        assert_eq!(extract_capabilities(&compiled), Some(vec![capability]));
+    }
+
+    #[test]
+    fn test_compile_capabilities() {
+        assert_eq!(compile_capabilities(&[]), Vec::<u8>::new());
+        assert_eq!(
+            compile_capabilities(&[
+                Capability::Multiprotocol { afi: 1, safi: 1 },
+                Capability::RouteRefresh,
+                Capability::EnhancedRouteRefresh,
+            ]),
+            vec![
+                /* param type */ 2, /* param length */ 10,
+                /* code */ 1, /* length */ 4, /* AFI */ 0, 1, /* reserved */ 0, /* SAFI */ 1,
+                /* code */ 2, /* length */ 0,
+                /* code */ 70, /* length */ 0,
+            ]
+        );
+    }
+}
@@ -1,5 +1,79 @@
+use std::convert::TryFrom;
+use std::net::Ipv4Addr;
+
+use crate::bgp::{AFI_IPV4, BGP_TYPE_NOTIFICATION, SAFI_UNICAST};
+use crate::bgp::notification::{
+    BGPNotification, CEASE_ADMINISTRATIVE_RESET, CEASE_ADMINISTRATIVE_SHUTDOWN, CEASE_MAX_PREFIXES,
+    CEASE_OTHER_CONFIGURATION_CHANGE, CEASE_PEER_DECONFIGURED, ERROR_CEASE, ERROR_MESSAGE_HEADER, ERROR_OPEN_MESSAGE,
+    ERROR_UPDATE_MESSAGE, HEADER_BAD_MESSAGE_LENGTH, OPEN_BAD_PEER_AS, UPDATE_INVALID_NEXT_HOP,
+};
+use crate::bgp::utils::path_attribute::{AttributeType, PathAttribute, compile_path_attributes};
+
 #[derive(thiserror::Error, Debug)]
 pub enum BgpError {
     #[error("IO error {0}")]
     IoError(#[from] std::io::Error),
+    #[error("Peer AS {received} does not match configured remote AS {expected}")]
+    PeerAsMismatch { expected: u16, received: u16 },
+    #[error("Maximum number of prefixes ({limit}) exceeded")]
+    MaxPrefixExceeded { limit: u32 },
+    #[error("NOTIFICATION received, code {code} subcode {subcode}")]
+    NotificationReceived { code: u8, subcode: u8 },
+    #[error("Invalid NEXT_HOP {next_hop}")]
+    InvalidNextHop { next_hop: Ipv4Addr },
+    /// A message the decoder rejected, with the error code, subcode and data to report it with
+    #[error("Malformed message of type {message_type}, error code {code} subcode {subcode}")]
+    MalformedMessage { message_type: u8, code: u8, subcode: u8, data: Vec<u8> },
+    #[error("Administrative shutdown: {communication}")]
+    AdministrativeShutdown { communication: String },
+    #[error("Administrative reset: {communication}")]
+    AdministrativeReset { communication: String },
+    #[error("Neighbor was removed from the configuration")]
+    Deconfigured,
+    #[error("Neighbor configuration changed")]
+    ConfigurationChanged,
+    /// Closed without a NOTIFICATION so that the neighbor keeps our routes (RFC 4724)
+    #[error("Restarting, routes are kept by the neighbor")]
+    Restarting,
+}
+
+impl BgpError {
+    /// Decoding error reported with the given NOTIFICATION error code, subcode and data
+    pub fn malformed(message_type: u8, code: u8, subcode: u8, data: Vec<u8>) -> BgpError {
+        BgpError::MalformedMessage { message_type, code, subcode, data }
+    }
+
+    /// Message Header Error for a message of `length` bytes, the data is the erroneous Length
+    /// field (RFC 4271 section 6.1)
+    pub fn bad_message_length(message_type: u8, length: usize) -> BgpError {
+        let length = u16::try_from(length).unwrap_or(u16::MAX);
+        BgpError::malformed(message_type, ERROR_MESSAGE_HEADER, HEADER_BAD_MESSAGE_LENGTH, length.to_be_bytes().to_vec())
+    }
+
+    /// The NOTIFICATION to send to the peer before closing the session, if the error warrants one
+    pub fn notification(&self) -> Option<BGPNotification> {
+        match self {
+            BgpError::IoError(_) | BgpError::NotificationReceived { .. } | BgpError::Restarting => None,
+            // Errors in a NOTIFICATION cannot be reported back with another (RFC 4271 section 6.4)
+            BgpError::MalformedMessage { message_type: BGP_TYPE_NOTIFICATION, .. } => None,
+            BgpError::MalformedMessage { code, subcode, data, .. } => Some(BGPNotification::new(*code, *subcode, data.clone())),
+            BgpError::AdministrativeShutdown { communication } => Some(BGPNotification::cease(CEASE_ADMINISTRATIVE_SHUTDOWN, communication)),
+            BgpError::AdministrativeReset { communication } => Some(BGPNotification::cease(CEASE_ADMINISTRATIVE_RESET, communication)),
+            BgpError::Deconfigured => Some(BGPNotification::new(ERROR_CEASE, CEASE_PEER_DECONFIGURED, vec![])),
+            BgpError::ConfigurationChanged => Some(BGPNotification::new(ERROR_CEASE, CEASE_OTHER_CONFIGURATION_CHANGE, vec![])),
+            BgpError::PeerAsMismatch { .. } => Some(BGPNotification::new(ERROR_OPEN_MESSAGE, OPEN_BAD_PEER_AS, vec![])),
+            BgpError::InvalidNextHop { next_hop } => {
+                // The offending attribute as it appears on the wire
+                let attribute = PathAttribute::new(AttributeType::NextHop, next_hop.octets().to_vec());
+                Some(BGPNotification::new(ERROR_UPDATE_MESSAGE, UPDATE_INVALID_NEXT_HOP, compile_path_attributes(vec![attribute])))
+            },
+            BgpError::MaxPrefixExceeded { limit } => {
+                // RFC 4486: AFI, SAFI and the prefix upper bound
+                let mut data = AFI_IPV4.to_be_bytes().to_vec();
+                data.push(SAFI_UNICAST);
+                data.extend_from_slice(&limit.to_be_bytes());
+                Some(BGPNotification::new(ERROR_CEASE, CEASE_MAX_PREFIXES, data))
+            },
+        }
+    }
 }
\ No newline at end of file
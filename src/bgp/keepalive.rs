@@ -1,19 +1,34 @@
+use std::convert::TryFrom;
+
+use crate::bgp::errors::BgpError;
 use crate::bgp::{BGP_HEADER_SIZE, BGP_TYPE_KEEPALIVE, make_bgp_header};
 
-#[derive(Debug)]
+#[derive(Debug, Default, Clone)]
 pub struct BGPKeepalive {}
 
-impl From<&[u8]> for BGPKeepalive {
-    fn from(_buf: &[u8]) -> BGPKeepalive {
+impl BGPKeepalive {
+    pub fn new() -> BGPKeepalive {
         BGPKeepalive {}
     }
 }
 
-impl Into<Vec<u8>> for BGPKeepalive {
-    fn into(self) -> Vec<u8> {
+impl TryFrom<&[u8]> for BGPKeepalive {
+    type Error = BgpError;
+
+    /// A KEEPALIVE is only the header
+    fn try_from(buf: &[u8]) -> Result<BGPKeepalive, BgpError> {
+        if !buf.is_empty() {
+            return Err(BgpError::bad_message_length(BGP_TYPE_KEEPALIVE, BGP_HEADER_SIZE + buf.len()));
+        }
+        Ok(BGPKeepalive {})
+    }
+}
+
+impl From<BGPKeepalive> for Vec<u8> {
+    fn from(_keepalive: BGPKeepalive) -> Vec<u8> {
         let mut buf = Vec::with_capacity(BGP_HEADER_SIZE);
-        let header = make_bgp_header(0 as u16, BGP_TYPE_KEEPALIVE);
+        let header = make_bgp_header(0, BGP_TYPE_KEEPALIVE);
         buf.extend_from_slice(&header);
-        return buf
+        buf
     }
 }
\ No newline at end of file
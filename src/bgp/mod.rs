@@ -2,68 +2,186 @@ pub mod update;
 pub mod open;
 pub mod keepalive;
 pub mod notification;
+pub mod route_refresh;
+pub mod capability;
 pub mod errors;
 pub mod utils;
 
+use std::convert::TryFrom;
+
 use byteorder::{ByteOrder, NetworkEndian};
 
+use errors::BgpError;
+
 use open::BGPOpen;
 use keepalive::BGPKeepalive;
 use update::BGPUpdate;
-use notification::BGPNotification;
+use notification::{
+    BGPNotification, ERROR_MESSAGE_HEADER, HEADER_BAD_MESSAGE_LENGTH, HEADER_BAD_MESSAGE_TYPE, HEADER_CONNECTION_NOT_SYNCHRONIZED,
+};
+use route_refresh::BGPRouteRefresh;
 
+pub const BGP_VERSION: u8 = 4;
 pub const BGP_MAX_MSG_SIZE: usize = 4096;
 pub const BGP_HEADER_SIZE: usize = 19;
 pub const BGP_OPEN_SIZE: usize = 10;
 
-const BGP_TYPE_OPEN: u8 = 0x01;
-const BGP_TYPE_UPDATE: u8 = 0x02;
-const BGP_TYPE_NOTIFICATION: u8 = 0x03;
-const BGP_TYPE_KEEPALIVE: u8 = 0x04;
+pub const AFI_IPV4: u16 = 1;
+pub const AFI_IPV6: u16 = 2;
+pub const SAFI_UNICAST: u8 = 1;
+pub const SAFI_FLOWSPEC: u8 = 133;
 
-#[derive(Debug)]
+pub const BGP_TYPE_OPEN: u8 = 0x01;
+pub const BGP_TYPE_UPDATE: u8 = 0x02;
+pub const BGP_TYPE_NOTIFICATION: u8 = 0x03;
+pub const BGP_TYPE_KEEPALIVE: u8 = 0x04;
+pub const BGP_TYPE_ROUTE_REFRESH: u8 = 0x05;
+
+#[derive(Debug, Clone)]
 pub enum BGPMessage {
     Open(BGPOpen),
     Update(BGPUpdate),
     Notification(BGPNotification),
     Keepalive(BGPKeepalive),
+    RouteRefresh(BGPRouteRefresh),
 }
 
 fn make_bgp_header(length: u16, msg_type: u8) -> [u8; BGP_HEADER_SIZE] {
     let mut buf = [0xFF; BGP_HEADER_SIZE];
     NetworkEndian::write_u16(&mut buf[16..18], BGP_HEADER_SIZE as u16 + length);
     buf[18] = msg_type;
-    return buf
+    buf
 }
 
 pub fn message_length(message_buffer: &[u8]) -> usize {
     NetworkEndian::read_u16(&message_buffer[16..18]) as usize
 }
 
-impl From<&[u8]> for BGPMessage {
-    fn from(buf: &[u8]) -> BGPMessage {
-        let (header, rest) = buf.split_at(BGP_HEADER_SIZE);
-        let length = message_length(&header);
-        let msg_payload = &rest[0..length - BGP_HEADER_SIZE];
-        let msg_type = header[18];
-        match msg_type {
-            BGP_TYPE_OPEN => BGPMessage::Open(msg_payload.into()),
-            BGP_TYPE_UPDATE => BGPMessage::Update(msg_payload.into()),
-            BGP_TYPE_NOTIFICATION => BGPMessage::Notification(msg_payload.into()),
-            BGP_TYPE_KEEPALIVE => BGPMessage::Keepalive(msg_payload.into()),
-            _ => unimplemented!("BGP Message type: {:?}", msg_type)
-        }
+/// Wire format options negotiated for a session
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CodecOptions {
+    /// Received IPv4 unicast prefixes carry ADD-PATH path identifiers
+    pub add_path_receive: bool,
+    /// Sent IPv4 unicast prefixes carry ADD-PATH path identifiers
+    pub add_path_send: bool,
+}
+
+impl TryFrom<&[u8]> for BGPMessage {
+    type Error = BgpError;
+
+    fn try_from(buf: &[u8]) -> Result<BGPMessage, BgpError> {
+        BGPMessage::decode(buf, CodecOptions::default())
     }
 }
 
-impl Into<Vec<u8>> for BGPMessage {
-    fn into(self) -> Vec<u8> {
+impl From<BGPMessage> for Vec<u8> {
+    fn from(message: BGPMessage) -> Vec<u8> {
+        message.encode(CodecOptions::default())
+    }
+}
+
+impl BGPMessage {
+    /// Decodes one complete message, header included. Errors if the marker is broken, the length
+    /// field does not match the buffer, the type is unknown or the body is malformed.
+    pub fn decode(buf: &[u8], options: CodecOptions) -> Result<BGPMessage, BgpError> {
+        let msg_type = *buf.get(18).ok_or_else(|| BgpError::bad_message_length(0, buf.len()))?;
+        if buf[..16].iter().any(|&octet| octet != 0xFF) {
+            return Err(BgpError::malformed(msg_type, ERROR_MESSAGE_HEADER, HEADER_CONNECTION_NOT_SYNCHRONIZED, vec![]));
+        }
+        if message_length(buf) != buf.len() || buf.len() > BGP_MAX_MSG_SIZE {
+            return Err(BgpError::malformed(msg_type, ERROR_MESSAGE_HEADER, HEADER_BAD_MESSAGE_LENGTH, buf[16..18].to_vec()));
+        }
+        let msg_payload = &buf[BGP_HEADER_SIZE..];
+        Ok(match msg_type {
+            BGP_TYPE_OPEN => BGPMessage::Open(BGPOpen::try_from(msg_payload)?),
+            BGP_TYPE_UPDATE => BGPMessage::Update(BGPUpdate::decode(msg_payload, options.add_path_receive)?),
+            BGP_TYPE_NOTIFICATION => BGPMessage::Notification(BGPNotification::try_from(msg_payload)?),
+            BGP_TYPE_KEEPALIVE => BGPMessage::Keepalive(BGPKeepalive::try_from(msg_payload)?),
+            BGP_TYPE_ROUTE_REFRESH => BGPMessage::RouteRefresh(BGPRouteRefresh::try_from(msg_payload)?),
+            _ => return Err(BgpError::malformed(msg_type, ERROR_MESSAGE_HEADER, HEADER_BAD_MESSAGE_TYPE, vec![msg_type])),
+        })
+    }
+
+    /// Lowercase name of the message type, e.g. for metric labels
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            BGPMessage::Open(_) => "open",
+            BGPMessage::Update(_) => "update",
+            BGPMessage::Notification(_) => "notification",
+            BGPMessage::Keepalive(_) => "keepalive",
+            BGPMessage::RouteRefresh(_) => "route_refresh",
+        }
+    }
+
+    pub fn encode(self, options: CodecOptions) -> Vec<u8> {
         match self {
             BGPMessage::Open(open) => open.into(),
             BGPMessage::Keepalive(keepalive) => keepalive.into(),
-            BGPMessage::Update(update) => update.into(),
-            BGPMessage::Notification(_notification) => unimplemented!(),
+            BGPMessage::Update(update) => update.encode(options.add_path_send),
+            BGPMessage::Notification(notification) => notification.into(),
+            BGPMessage::RouteRefresh(route_refresh) => route_refresh.into(),
         }
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bgp::notification::{ERROR_OPEN_MESSAGE, ERROR_ROUTE_REFRESH_MESSAGE, ERROR_UPDATE_MESSAGE};
+
+    /// Error code, subcode and data of the NOTIFICATION reporting a message that fails to decode
+    fn notification(buf: &[u8]) -> (u8, u8, Vec<u8>) {
+        let notification = BGPMessage::try_from(buf).unwrap_err().notification().unwrap();
+        (notification.error_code, notification.error_subcode, notification.data)
+    }
+
+    #[test]
+    fn test_decode_malformed() {
+        let keepalive: Vec<u8> = BGPKeepalive::new().into();
+        assert!(matches!(BGPMessage::try_from(&keepalive[..]), Ok(BGPMessage::Keepalive(_))));
+
+        // Truncated header, length field not matching the buffer and a broken marker
+        assert_eq!(notification(&keepalive[..BGP_HEADER_SIZE - 1]), (1, 2, vec![0, 18]));
+        assert_eq!(notification(&[&keepalive[..], &[0]].concat()), (1, 2, vec![0, 19]));
+        let mut marker = keepalive.clone();
+        marker[0] = 0;
+        assert_eq!(notification(&marker), (1, 1, vec![]));
+
+        // Unknown type and a KEEPALIVE with a body
+        let mut unknown = keepalive.clone();
+        unknown[18] = 42;
+        assert_eq!(notification(&unknown), (1, 3, vec![42]));
+        let mut long_keepalive = keepalive.clone();
+        long_keepalive.push(0);
+        long_keepalive[17] = 20;
+        assert_eq!(notification(&long_keepalive), (1, 2, vec![0, 20]));
+
+        // UPDATE whose withdrawn routes length runs past the end, and one with a prefix longer
+        // than 32 bits
+        let mut update: Vec<u8> = BGPUpdate::withdraw(vec![]).into();
+        update[BGP_HEADER_SIZE + 1] = 10;
+        assert_eq!(notification(&update), (ERROR_UPDATE_MESSAGE, 1, vec![]));
+        let mut update: Vec<u8> = BGPUpdate::withdraw(vec![]).into();
+        update.push(33);
+        update[17] += 1;
+        assert_eq!(notification(&update), (ERROR_UPDATE_MESSAGE, 10, vec![]));
+
+        // OPEN whose optional parameters length runs past the end
+        let mut open: Vec<u8> = BGPOpen::new(65001, 90, 1, vec![]).into();
+        open[BGP_HEADER_SIZE + 9] = 1;
+        assert_eq!(notification(&open), (ERROR_OPEN_MESSAGE, 0, vec![]));
+
+        // ROUTE-REFRESH of the wrong length is reported with the whole message
+        let mut route_refresh: Vec<u8> = BGPRouteRefresh::new(AFI_IPV4, SAFI_UNICAST, 0).into();
+        route_refresh.pop();
+        route_refresh[17] -= 1;
+        assert_eq!(notification(&route_refresh), (ERROR_ROUTE_REFRESH_MESSAGE, 1, route_refresh.clone()));
+
+        // Errors in a NOTIFICATION are not reported back
+        let mut short_notification: Vec<u8> = BGPNotification::new(6, 2, vec![]).into();
+        short_notification.pop();
+        short_notification[17] -= 1;
+        assert!(BGPMessage::try_from(&short_notification[..]).unwrap_err().notification().is_none());
+    }
+}
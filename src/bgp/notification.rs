@@ -1,16 +1,134 @@
-#[derive(Debug)]
+use std::convert::TryFrom;
+
+use crate::bgp::errors::BgpError;
+use crate::bgp::{BGP_HEADER_SIZE, BGP_TYPE_NOTIFICATION, make_bgp_header};
+
+pub const ERROR_MESSAGE_HEADER: u8 = 1;
+pub const ERROR_OPEN_MESSAGE: u8 = 2;
+pub const ERROR_UPDATE_MESSAGE: u8 = 3;
+pub const ERROR_CEASE: u8 = 6;
+/// ROUTE-REFRESH Message Error (RFC 7313)
+pub const ERROR_ROUTE_REFRESH_MESSAGE: u8 = 7;
+
+/// Subcode for errors without a more specific one
+pub const SUBCODE_UNSPECIFIC: u8 = 0;
+
+pub const HEADER_CONNECTION_NOT_SYNCHRONIZED: u8 = 1;
+pub const HEADER_BAD_MESSAGE_LENGTH: u8 = 2;
+pub const HEADER_BAD_MESSAGE_TYPE: u8 = 3;
+
+pub const OPEN_BAD_PEER_AS: u8 = 2;
+
+pub const UPDATE_MALFORMED_ATTRIBUTE_LIST: u8 = 1;
+pub const UPDATE_INVALID_NEXT_HOP: u8 = 8;
+pub const UPDATE_INVALID_NETWORK_FIELD: u8 = 10;
+
+pub const ROUTE_REFRESH_INVALID_MESSAGE_LENGTH: u8 = 1;
+
+pub const CEASE_MAX_PREFIXES: u8 = 1;
+pub const CEASE_ADMINISTRATIVE_SHUTDOWN: u8 = 2;
+pub const CEASE_PEER_DECONFIGURED: u8 = 3;
+pub const CEASE_ADMINISTRATIVE_RESET: u8 = 4;
+pub const CEASE_OTHER_CONFIGURATION_CHANGE: u8 = 6;
+
+/// Longest Shutdown Communication in bytes (RFC 8203)
+pub const SHUTDOWN_COMMUNICATION_MAX_LENGTH: usize = 128;
+
+#[derive(Debug, Clone)]
 pub struct BGPNotification {
-    error_code: u8,
-    error_subcode: u8,
-    data: Vec<u8>,
+    pub error_code: u8,
+    pub error_subcode: u8,
+    pub data: Vec<u8>,
+}
+
+impl BGPNotification {
+    pub fn new(error_code: u8, error_subcode: u8, data: Vec<u8>) -> BGPNotification {
+        BGPNotification { error_code, error_subcode, data }
+    }
+
+    /// Cease with a Shutdown Communication (RFC 8203), `subcode` is Administrative Shutdown or
+    /// Administrative Reset. Longer messages are truncated at a character boundary.
+    pub fn cease(subcode: u8, communication: &str) -> BGPNotification {
+        let mut length = communication.len().min(SHUTDOWN_COMMUNICATION_MAX_LENGTH);
+        while !communication.is_char_boundary(length) {
+            length -= 1;
+        }
+        let mut data = vec![length as u8];
+        data.extend_from_slice(&communication.as_bytes()[..length]);
+        BGPNotification::new(ERROR_CEASE, subcode, data)
+    }
+
+    /// The Shutdown Communication of an Administrative Shutdown or Reset, if it has one
+    pub fn shutdown_communication(&self) -> Option<String> {
+        let administrative = self.error_subcode == CEASE_ADMINISTRATIVE_SHUTDOWN || self.error_subcode == CEASE_ADMINISTRATIVE_RESET;
+        if self.error_code != ERROR_CEASE || !administrative {
+            return None;
+        }
+        let (&length, communication) = self.data.split_first()?;
+        let communication = communication.get(..length as usize)?;
+        match std::str::from_utf8(communication) {
+            Ok(communication) if !communication.is_empty() => Some(communication.to_string()),
+            _ => None,
+        }
+    }
 }
 
-impl From<&[u8]> for BGPNotification {
-    fn from(buf: &[u8]) -> BGPNotification {
-        BGPNotification {
-            error_code: buf[0],
-            error_subcode: buf[1],
-            data: buf[2..].to_vec(),
+impl TryFrom<&[u8]> for BGPNotification {
+    type Error = BgpError;
+
+    fn try_from(buf: &[u8]) -> Result<BGPNotification, BgpError> {
+        match buf {
+            [error_code, error_subcode, data @ ..] => Ok(BGPNotification {
+                error_code: *error_code,
+                error_subcode: *error_subcode,
+                data: data.to_vec(),
+            }),
+            _ => Err(BgpError::bad_message_length(BGP_TYPE_NOTIFICATION, BGP_HEADER_SIZE + buf.len())),
         }
     }
-}
\ No newline at end of file
+}
+
+impl From<BGPNotification> for Vec<u8> {
+    fn from(notification: BGPNotification) -> Vec<u8> {
+        let header = make_bgp_header(2 + notification.data.len() as u16, BGP_TYPE_NOTIFICATION);
+        let mut buf = Vec::with_capacity(header.len() + 2 + notification.data.len());
+        buf.extend_from_slice(&header);
+        buf.push(notification.error_code);
+        buf.push(notification.error_subcode);
+        buf.extend(notification.data);
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_notification_roundtrip() {
+        let buf: Vec<u8> = BGPNotification { error_code: ERROR_CEASE, error_subcode: CEASE_MAX_PREFIXES, data: vec![0, 1, 1, 0, 0, 0, 100] }.into();
+        assert_eq!(&buf[16..], &[0, 28, BGP_TYPE_NOTIFICATION, 6, 1, 0, 1, 1, 0, 0, 0, 100]);
+
+        let notification = BGPNotification::try_from(&buf[19..]).unwrap();
+        assert_eq!(notification.error_code, ERROR_CEASE);
+        assert_eq!(notification.error_subcode, CEASE_MAX_PREFIXES);
+        assert_eq!(notification.data, vec![0, 1, 1, 0, 0, 0, 100]);
+        assert!(BGPNotification::try_from(&buf[19..20]).is_err());
+    }
+
+    #[test]
+    fn test_shutdown_communication() {
+        let notification = BGPNotification::cease(CEASE_ADMINISTRATIVE_SHUTDOWN, "Maintenance");
+        assert_eq!(notification.data, b"\x0bMaintenance".to_vec());
+        assert_eq!(notification.shutdown_communication().unwrap(), "Maintenance");
+
+        let long = "ä".repeat(100);
+        let notification = BGPNotification::cease(CEASE_ADMINISTRATIVE_RESET, &long);
+        assert_eq!(notification.data[0], 128);
+        assert_eq!(notification.shutdown_communication().unwrap(), "ä".repeat(64));
+
+        assert!(BGPNotification::cease(CEASE_ADMINISTRATIVE_RESET, "").shutdown_communication().is_none());
+        assert!(BGPNotification::new(ERROR_CEASE, CEASE_ADMINISTRATIVE_SHUTDOWN, vec![5, b'a']).shutdown_communication().is_none());
+        assert!(BGPNotification::new(ERROR_CEASE, CEASE_MAX_PREFIXES, vec![1, b'a']).shutdown_communication().is_none());
+    }
+}
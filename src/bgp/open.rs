@@ -1,42 +1,89 @@
+use std::convert::TryFrom;
+
 use byteorder::{ByteOrder, NetworkEndian, WriteBytesExt};
-use crate::bgp::{BGP_HEADER_SIZE, BGP_OPEN_SIZE, BGP_TYPE_OPEN, make_bgp_header};
+use crate::bgp::errors::BgpError;
+use crate::bgp::notification::{ERROR_OPEN_MESSAGE, SUBCODE_UNSPECIFIC};
+use crate::bgp::{BGP_HEADER_SIZE, BGP_OPEN_SIZE, BGP_TYPE_OPEN, BGP_VERSION, make_bgp_header};
+use crate::bgp::capability::{Capability, compile_capabilities, extract_capabilities};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct BGPOpen {
     pub version: u8,
     pub sender_as: u16,
     pub hold_time: u16,
     pub bgp_id: u32,
-    pub opt_params_len: u8,
-    pub opt_params: ()
+    pub capabilities: Vec<Capability>,
+}
+
+impl BGPOpen {
+    /// OPEN for BGP version 4
+    pub fn new(sender_as: u16, hold_time: u16, bgp_id: u32, capabilities: Vec<Capability>) -> BGPOpen {
+        BGPOpen { version: BGP_VERSION, sender_as, hold_time, bgp_id, capabilities }
+    }
 }
 
-impl From<&[u8]> for BGPOpen {
-    fn from(buf: &[u8]) -> BGPOpen {
-        BGPOpen {
+impl TryFrom<&[u8]> for BGPOpen {
+    type Error = BgpError;
+
+    fn try_from(buf: &[u8]) -> Result<BGPOpen, BgpError> {
+        if buf.len() < BGP_OPEN_SIZE {
+            return Err(BgpError::bad_message_length(BGP_TYPE_OPEN, BGP_HEADER_SIZE + buf.len()));
+        }
+        // Malformed optional parameters have no subcode of their own (RFC 4271 section 6.2)
+        let malformed = || BgpError::malformed(BGP_TYPE_OPEN, ERROR_OPEN_MESSAGE, SUBCODE_UNSPECIFIC, vec![]);
+        if buf.len() != BGP_OPEN_SIZE + buf[9] as usize {
+            return Err(malformed());
+        }
+        Ok(BGPOpen {
             version: buf[0],
             sender_as: NetworkEndian::read_u16(&buf[1..3]),
             hold_time: NetworkEndian::read_u16(&buf[3..5]),
             bgp_id: NetworkEndian::read_u32(&buf[5..9]),
-            opt_params_len: buf[9],
-            opt_params: (),
-        }
+            capabilities: extract_capabilities(&buf[BGP_OPEN_SIZE..]).ok_or_else(malformed)?,
+        })
     }
 }
 
-impl Into<Vec<u8>> for BGPOpen {
-    fn into(self) -> Vec<u8> {
-        let mut buf = Vec::with_capacity(BGP_HEADER_SIZE + BGP_OPEN_SIZE);
+impl From<BGPOpen> for Vec<u8> {
+    fn from(open: BGPOpen) -> Vec<u8> {
+        let opt_params = compile_capabilities(&open.capabilities);
+        let mut buf = Vec::with_capacity(BGP_HEADER_SIZE + BGP_OPEN_SIZE + opt_params.len());
 
-        let header = make_bgp_header(BGP_OPEN_SIZE as u16, BGP_TYPE_OPEN);
+        let header = make_bgp_header((BGP_OPEN_SIZE + opt_params.len()) as u16, BGP_TYPE_OPEN);
         buf.extend_from_slice(&header);
-        buf.push(self.version);
-        buf.write_u16::<NetworkEndian>(self.sender_as).unwrap();
-        buf.write_u16::<NetworkEndian>(self.hold_time).unwrap();
-        buf.write_u32::<NetworkEndian>(self.bgp_id).unwrap();
-        buf.push(self.opt_params_len);
-        if self.opt_params_len > 0 { unimplemented!(); }
-
-        return buf
+        buf.push(open.version);
+        buf.write_u16::<NetworkEndian>(open.sender_as).unwrap();
+        buf.write_u16::<NetworkEndian>(open.hold_time).unwrap();
+        buf.write_u32::<NetworkEndian>(open.bgp_id).unwrap();
+        buf.push(opt_params.len() as u8);
+        buf.extend(opt_params);
+
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_open_roundtrip() {
+        let buf: Vec<u8> = BGPOpen {
+            version: 4,
+            sender_as: 65002,
+            hold_time: 30,
+            bgp_id: 0x0A000001,
+            capabilities: vec![Capability::RouteRefresh],
+        }.into();
+        assert_eq!(&buf[16..], &[0, 33, BGP_TYPE_OPEN, 4, 0xFD, 0xEA, 0, 30, 10, 0, 0, 1, 4, 2, 2, 2, 0]);
+
+        let open = BGPOpen::try_from(&buf[BGP_HEADER_SIZE..]).unwrap();
+        assert_eq!(open.sender_as, 65002);
+        assert_eq!(open.bgp_id, 0x0A000001);
+        assert_eq!(open.capabilities, vec![Capability::RouteRefresh]);
+
+        // Optional parameters length past the end
+        assert!(BGPOpen::try_from(&buf[BGP_HEADER_SIZE..buf.len() - 1]).is_err());
+        assert!(BGPOpen::try_from(&buf[BGP_HEADER_SIZE..BGP_HEADER_SIZE + 5]).is_err());
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,72 @@
+use std::convert::TryFrom;
+
+use byteorder::{ByteOrder, NetworkEndian, WriteBytesExt};
+
+use crate::bgp::errors::BgpError;
+use crate::bgp::notification::{ERROR_ROUTE_REFRESH_MESSAGE, ROUTE_REFRESH_INVALID_MESSAGE_LENGTH};
+use crate::bgp::{BGP_HEADER_SIZE, BGP_TYPE_ROUTE_REFRESH, make_bgp_header};
+
+const BGP_ROUTE_REFRESH_SIZE: usize = 4;
+
+/// Plain refresh request (RFC 2918)
+pub const ROUTE_REFRESH_REQUEST: u8 = 0;
+/// Beginning and End of Route Refresh markers (RFC 7313)
+pub const ROUTE_REFRESH_BORR: u8 = 1;
+pub const ROUTE_REFRESH_EORR: u8 = 2;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct BGPRouteRefresh {
+    pub afi: u16,
+    pub subtype: u8,
+    pub safi: u8,
+}
+
+impl BGPRouteRefresh {
+    /// Message of the given subtype, e.g. `ROUTE_REFRESH_REQUEST`
+    pub fn new(afi: u16, safi: u8, subtype: u8) -> BGPRouteRefresh {
+        BGPRouteRefresh { afi, subtype, safi }
+    }
+}
+
+impl TryFrom<&[u8]> for BGPRouteRefresh {
+    type Error = BgpError;
+
+    fn try_from(buf: &[u8]) -> Result<BGPRouteRefresh, BgpError> {
+        if buf.len() != BGP_ROUTE_REFRESH_SIZE {
+            // The data is the complete message (RFC 7313 section 5)
+            let mut message = make_bgp_header(buf.len() as u16, BGP_TYPE_ROUTE_REFRESH).to_vec();
+            message.extend_from_slice(buf);
+            return Err(BgpError::malformed(BGP_TYPE_ROUTE_REFRESH, ERROR_ROUTE_REFRESH_MESSAGE, ROUTE_REFRESH_INVALID_MESSAGE_LENGTH, message));
+        }
+        Ok(BGPRouteRefresh {
+            afi: NetworkEndian::read_u16(&buf[0..2]),
+            subtype: buf[2],
+            safi: buf[3],
+        })
+    }
+}
+
+impl From<BGPRouteRefresh> for Vec<u8> {
+    fn from(route_refresh: BGPRouteRefresh) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(BGP_HEADER_SIZE + BGP_ROUTE_REFRESH_SIZE);
+        let header = make_bgp_header(BGP_ROUTE_REFRESH_SIZE as u16, BGP_TYPE_ROUTE_REFRESH);
+        buf.extend_from_slice(&header);
+        buf.write_u16::<NetworkEndian>(route_refresh.afi).unwrap();
+        buf.push(route_refresh.subtype);
+        buf.push(route_refresh.safi);
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_route_refresh_roundtrip() {
+        let buf: Vec<u8> = BGPRouteRefresh { afi: 1, subtype: ROUTE_REFRESH_EORR, safi: 1 }.into();
+        assert_eq!(&buf[16..], &[0, 23, BGP_TYPE_ROUTE_REFRESH, 0, 1, 2, 1]);
+        assert_eq!(BGPRouteRefresh::try_from(&buf[19..]).unwrap(), BGPRouteRefresh { afi: 1, subtype: ROUTE_REFRESH_EORR, safi: 1 });
+        assert!(BGPRouteRefresh::try_from(&buf[19..22]).is_err());
+    }
+}
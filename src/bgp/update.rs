@@ -1,53 +1,161 @@
+use std::convert::TryFrom;
+
 use byteorder::{ByteOrder, NetworkEndian, WriteBytesExt};
 
-use super::{BGP_TYPE_UPDATE, make_bgp_header};
-use super::utils::prefix::{Prefix, compile_prefixes, extract_prefixes};
-use super::utils::path_attribute::{PathAttribute, extract_path_attributes};
+use super::errors::BgpError;
+use super::notification::{ERROR_UPDATE_MESSAGE, UPDATE_INVALID_NETWORK_FIELD, UPDATE_MALFORMED_ATTRIBUTE_LIST};
+use super::{AFI_IPV4, BGP_HEADER_SIZE, BGP_MAX_MSG_SIZE, BGP_TYPE_UPDATE, SAFI_UNICAST, make_bgp_header};
+use super::utils::prefix::{Nlri, compile_prefixes, extract_prefixes};
+use super::utils::path_attribute::{AttributeType, PathAttribute, extract_path_attributes};
 use crate::bgp::utils::path_attribute::compile_path_attributes;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct BGPUpdate {
-    pub withdrawn_routes: Vec<Prefix>,
+    pub withdrawn_routes: Vec<Nlri>,
     pub path_attributes: Vec<PathAttribute>,
-    pub network_layer_reachability_information: Vec<Prefix>,
+    pub network_layer_reachability_information: Vec<Nlri>,
 }
 
 const U16_LENGTH_FIELD: usize = 2;
 
-impl From<&[u8]> for BGPUpdate {
-    fn from(buf: &[u8]) -> BGPUpdate {
+impl BGPUpdate {
+    /// Announces the IPv4 unicast prefixes with the attributes
+    pub fn announce(path_attributes: Vec<PathAttribute>, nlri: Vec<Nlri>) -> BGPUpdate {
+        BGPUpdate { withdrawn_routes: vec![], path_attributes, network_layer_reachability_information: nlri }
+    }
+
+    /// Withdraws the IPv4 unicast prefixes
+    pub fn withdraw(withdrawn_routes: Vec<Nlri>) -> BGPUpdate {
+        BGPUpdate { withdrawn_routes, path_attributes: vec![], network_layer_reachability_information: vec![] }
+    }
+
+    /// End-of-RIB marker (RFC 4724). For IPv4 unicast this is an UPDATE without any routes or
+    /// attributes, other families use an UPDATE with only an empty MP_UNREACH_NLRI.
+    pub fn end_of_rib(afi: u16, safi: u8) -> BGPUpdate {
+        let path_attributes = if afi == AFI_IPV4 && safi == SAFI_UNICAST {
+            vec![]
+        } else {
+            let mut value = afi.to_be_bytes().to_vec();
+            value.push(safi);
+            vec![PathAttribute::new(AttributeType::MpUnreachNlri, value)]
+        };
+        BGPUpdate::announce(path_attributes, vec![])
+    }
+
+    /// Splits the UPDATE so that each encoded message fits in the BGP maximum message size.
+    /// Withdrawn routes go into UPDATEs of their own, the prefixes are spread over UPDATEs that
+    /// each carry the path attributes.
+    pub fn split(self, add_path: bool) -> Vec<BGPUpdate> {
+        let space = BGP_MAX_MSG_SIZE - BGP_HEADER_SIZE - 2 * U16_LENGTH_FIELD;
+        let attributes_length = compile_path_attributes(self.path_attributes.clone()).len();
+        let length = |prefixes: &[Nlri]| prefixes.iter().map(|nlri| nlri.encoded_length(add_path)).sum::<usize>();
+        if length(&self.withdrawn_routes) + attributes_length + length(&self.network_layer_reachability_information) <= space {
+            return vec![self];
+        }
+        let mut updates: Vec<BGPUpdate> = chunk_prefixes(self.withdrawn_routes, space, add_path)
+            .into_iter()
+            .map(BGPUpdate::withdraw)
+            .collect();
+        let nlri_space = space.saturating_sub(attributes_length);
+        for nlri in chunk_prefixes(self.network_layer_reachability_information, nlri_space, add_path) {
+            updates.push(BGPUpdate::announce(self.path_attributes.clone(), nlri));
+        }
+        updates
+    }
+
+    /// The AFI/SAFI this UPDATE is the End-of-RIB marker for, if it is one
+    pub fn end_of_rib_family(&self) -> Option<(u16, u8)> {
+        if !self.withdrawn_routes.is_empty() || !self.network_layer_reachability_information.is_empty() {
+            return None;
+        }
+        match &self.path_attributes[..] {
+            [] => Some((AFI_IPV4, SAFI_UNICAST)),
+            [attribute] if attribute.type_code == AttributeType::MpUnreachNlri && attribute.value.len() == 3 => {
+                Some((NetworkEndian::read_u16(&attribute.value[0..2]), attribute.value[2]))
+            },
+            _ => None,
+        }
+    }
+}
+
+/// Groups prefixes so that each group takes at most `space` bytes, every group holds at least one
+fn chunk_prefixes(prefixes: Vec<Nlri>, space: usize, add_path: bool) -> Vec<Vec<Nlri>> {
+    let mut chunks: Vec<Vec<Nlri>> = Vec::new();
+    let mut used = 0;
+    for nlri in prefixes {
+        let length = nlri.encoded_length(add_path);
+        match chunks.last_mut() {
+            Some(chunk) if used + length <= space => chunk.push(nlri),
+            _ => {
+                chunks.push(vec![nlri]);
+                used = 0;
+            },
+        }
+        used += length;
+    }
+    chunks
+}
+
+impl TryFrom<&[u8]> for BGPUpdate {
+    type Error = BgpError;
+
+    fn try_from(buf: &[u8]) -> Result<BGPUpdate, BgpError> {
+        BGPUpdate::decode(buf, false)
+    }
+}
+
+impl From<BGPUpdate> for Vec<u8> {
+    fn from(update: BGPUpdate) -> Vec<u8> {
+        update.encode(false)
+    }
+}
+
+impl BGPUpdate {
+    /// Decodes the UPDATE, with `add_path` the prefixes carry ADD-PATH path identifiers
+    pub fn decode(buf: &[u8], add_path: bool) -> Result<BGPUpdate, BgpError> {
+        if buf.len() < 2 * U16_LENGTH_FIELD {
+            return Err(BgpError::bad_message_length(BGP_TYPE_UPDATE, BGP_HEADER_SIZE + buf.len()));
+        }
+        let malformed = |subcode| move || BgpError::malformed(BGP_TYPE_UPDATE, ERROR_UPDATE_MESSAGE, subcode, vec![]);
+        let attribute_list = malformed(UPDATE_MALFORMED_ATTRIBUTE_LIST);
+        let network_field = malformed(UPDATE_INVALID_NETWORK_FIELD);
+        let length_field = |start: usize| {
+            buf.get(start .. start + U16_LENGTH_FIELD).map(|field| NetworkEndian::read_u16(field) as usize).ok_or_else(attribute_list)
+        };
+
         let withdrawn_routes_start = 0;
-        let withdrawn_length = NetworkEndian::read_u16(&buf[withdrawn_routes_start .. withdrawn_routes_start + U16_LENGTH_FIELD]);
-        let withdrawn_routes = extract_prefixes(&buf[withdrawn_routes_start + U16_LENGTH_FIELD .. withdrawn_routes_start + U16_LENGTH_FIELD + withdrawn_length as usize]);
+        let withdrawn_length = length_field(withdrawn_routes_start)?;
+        let withdrawn_routes = buf
+            .get(withdrawn_routes_start + U16_LENGTH_FIELD .. withdrawn_routes_start + U16_LENGTH_FIELD + withdrawn_length)
+            .ok_or_else(attribute_list)?;
+        let withdrawn_routes = extract_prefixes(withdrawn_routes, add_path).ok_or_else(network_field)?;
 
-        let path_attributes_start = withdrawn_routes_start + U16_LENGTH_FIELD + withdrawn_length as usize;
-        let path_attribute_length = NetworkEndian::read_u16(&buf[path_attributes_start .. path_attributes_start + U16_LENGTH_FIELD]);
-        let path_attributes = extract_path_attributes(
-            &buf[path_attributes_start + U16_LENGTH_FIELD .. path_attributes_start + U16_LENGTH_FIELD + path_attribute_length as usize]
-        );
+        let path_attributes_start = withdrawn_routes_start + U16_LENGTH_FIELD + withdrawn_length;
+        let path_attribute_length = length_field(path_attributes_start)?;
+        let path_attributes = buf
+            .get(path_attributes_start + U16_LENGTH_FIELD .. path_attributes_start + U16_LENGTH_FIELD + path_attribute_length)
+            .and_then(extract_path_attributes)
+            .ok_or_else(attribute_list)?;
 
-        let prefixes_start = path_attributes_start + U16_LENGTH_FIELD + path_attribute_length as usize;
-        let prefixes_length = buf.len() - prefixes_start;
-        let prefixes = extract_prefixes(&buf[prefixes_start .. prefixes_start + prefixes_length]);
+        let prefixes_start = path_attributes_start + U16_LENGTH_FIELD + path_attribute_length;
+        let prefixes = extract_prefixes(&buf[prefixes_start..], add_path).ok_or_else(network_field)?;
 
-        BGPUpdate {
+        Ok(BGPUpdate {
             withdrawn_routes,
             path_attributes,
             network_layer_reachability_information: prefixes,
-        }
+        })
     }
-}
 
-
-impl Into<Vec<u8>> for BGPUpdate {
-    fn into(self) -> Vec<u8> {
+    /// Encodes the UPDATE, with `add_path` the prefixes carry ADD-PATH path identifiers
+    pub fn encode(self, add_path: bool) -> Vec<u8> {
         let mut buf = Vec::new();
 
         // Size is a placeholder, fill later
-        let header = make_bgp_header(0 as u16, BGP_TYPE_UPDATE);
+        let header = make_bgp_header(0, BGP_TYPE_UPDATE);
         buf.extend_from_slice(&header);
 
-        let mut withdrawn_routes = compile_prefixes(self.withdrawn_routes);
+        let mut withdrawn_routes = compile_prefixes(self.withdrawn_routes, add_path);
         buf.write_u16::<NetworkEndian>(withdrawn_routes.len() as u16).unwrap();
         buf.append(&mut withdrawn_routes);
 
@@ -55,13 +163,85 @@ impl Into<Vec<u8>> for BGPUpdate {
         buf.write_u16::<NetworkEndian>(path_attributes.len() as u16).unwrap();
         buf.append(&mut path_attributes);
 
-        let mut prefixes = compile_prefixes(self.network_layer_reachability_information);
+        let mut prefixes = compile_prefixes(self.network_layer_reachability_information, add_path);
         buf.append(&mut prefixes);
 
-        // Fix size in header
+        // Fix size in header, `split` keeps UPDATEs we build within the maximum
+        debug_assert!(buf.len() <= BGP_MAX_MSG_SIZE, "UPDATE of {} bytes", buf.len());
         let message_length = buf.len() as u16;
         NetworkEndian::write_u16(&mut buf[16 .. 18],message_length);
 
-        return buf
+        buf
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bgp::utils::prefix::Prefix;
+
+    #[test]
+    fn test_end_of_rib() {
+        let ipv4: Vec<u8> = BGPUpdate::end_of_rib(AFI_IPV4, SAFI_UNICAST).into();
+        assert_eq!(&ipv4[16..], &[0, 23, BGP_TYPE_UPDATE, 0, 0, 0, 0]);
+        assert_eq!(BGPUpdate::try_from(&ipv4[BGP_HEADER_SIZE..]).unwrap().end_of_rib_family(), Some((AFI_IPV4, SAFI_UNICAST)));
+
+        let ipv6: Vec<u8> = BGPUpdate::end_of_rib(2, SAFI_UNICAST).into();
+        assert_eq!(&ipv6[16..], &[0, 29, BGP_TYPE_UPDATE, 0, 0, 0, 6, 0x80, 15, 3, 0, 2, 1]);
+        assert_eq!(BGPUpdate::try_from(&ipv6[BGP_HEADER_SIZE..]).unwrap().end_of_rib_family(), Some((2, SAFI_UNICAST)));
+
+        let update = BGPUpdate {
+            withdrawn_routes: vec!["10.0.0.1/32".parse::<Prefix>().unwrap().into()],
+            path_attributes: vec![],
+            network_layer_reachability_information: vec![],
+        };
+        assert_eq!(update.end_of_rib_family(), None);
+    }
+
+    #[test]
+    fn test_add_path_roundtrip() {
+        let update = BGPUpdate {
+            withdrawn_routes: vec![Nlri { path_id: 1, prefix: "10.0.0.1/32".parse().unwrap() }],
+            path_attributes: vec![],
+            network_layer_reachability_information: vec![
+                Nlri { path_id: 1, prefix: "10.0.0.2/32".parse().unwrap() },
+                Nlri { path_id: 2, prefix: "10.0.0.2/32".parse().unwrap() },
+            ],
+        };
+        let buf = update.encode(true);
+        let decoded = BGPUpdate::decode(&buf[BGP_HEADER_SIZE..], true).unwrap();
+        assert_eq!(decoded.withdrawn_routes, vec![Nlri { path_id: 1, prefix: "10.0.0.1/32".parse().unwrap() }]);
+        assert_eq!(decoded.network_layer_reachability_information.len(), 2);
+        assert_eq!(decoded.network_layer_reachability_information[1].path_id, 2);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_split() {
+        let prefixes = |count: u32| -> Vec<Nlri> {
+            (0..count).map(|i| Prefix { length: 32, prefix: (0x0A00_0000 + i).to_be_bytes() }.into()).collect()
+        };
+        let small = BGPUpdate::announce(vec![PathAttribute::new(AttributeType::Origin, vec![0])], prefixes(10));
+        assert_eq!(small.split(false).len(), 1);
+
+        // 1500 /32s take 7500 bytes, with ADD-PATH 13500
+        for add_path in [false, true] {
+            let update = BGPUpdate {
+                withdrawn_routes: prefixes(1500),
+                path_attributes: vec![PathAttribute::new(AttributeType::Origin, vec![0])],
+                network_layer_reachability_information: prefixes(1500),
+            };
+            let updates = update.split(add_path);
+            assert!(updates.len() >= if add_path { 8 } else { 4 });
+            let mut withdrawn = 0;
+            let mut announced = 0;
+            for update in updates {
+                assert!(update.withdrawn_routes.is_empty() || update.network_layer_reachability_information.is_empty());
+                let buf = update.encode(add_path);
+                assert!(buf.len() <= BGP_MAX_MSG_SIZE);
+                let decoded = BGPUpdate::decode(&buf[BGP_HEADER_SIZE..], add_path).unwrap();
+                withdrawn += decoded.withdrawn_routes.len();
+                announced += decoded.network_layer_reachability_information.len();
+            }
+            assert_eq!((withdrawn, announced), (1500, 1500));
+        }
+    }
+}
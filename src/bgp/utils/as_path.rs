@@ -0,0 +1,158 @@
+use byteorder::{ByteOrder, NetworkEndian, WriteBytesExt};
+use std::fmt;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SegmentType {
+    Set = 1,
+    Sequence = 2,
+    /// Member ASes of a confederation the route passed through (RFC 5065)
+    ConfedSequence = 3,
+    ConfedSet = 4,
+}
+
+impl SegmentType {
+    pub fn is_confed(self) -> bool {
+        matches!(self, SegmentType::ConfedSequence | SegmentType::ConfedSet)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct AsPathSegment {
+    pub segment_type: SegmentType,
+    pub asns: Vec<u16>,
+}
+
+/// Renders the path the way it is usually shown by routers, e.g. `65001 65002 {65003 65004}`,
+/// with confederation segments as `(65010 65011)` and `[65010 65011]`. This is also the form AS
+/// path regexes are matched against.
+pub struct AsPathDisplay<'a>(pub &'a [AsPathSegment]);
+
+impl fmt::Display for AsPathDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut first = true;
+        for segment in self.0 {
+            let asns: Vec<String> = segment.asns.iter().map(|asn| asn.to_string()).collect();
+            if !first {
+                f.write_str(" ")?;
+            }
+            match segment.segment_type {
+                SegmentType::Sequence => f.write_str(&asns.join(" "))?,
+                SegmentType::Set => f.write_fmt(format_args!("{{{}}}", asns.join(" ")))?,
+                SegmentType::ConfedSequence => f.write_fmt(format_args!("({})", asns.join(" ")))?,
+                SegmentType::ConfedSet => f.write_fmt(format_args!("[{}]", asns.join(" ")))?,
+            }
+            first = false;
+        }
+        Ok(())
+    }
+}
+
+/// Extracts the AS_PATH segments, a truncated segment at the end is dropped
+pub fn extract_as_path(data: &[u8]) -> Vec<AsPathSegment> {
+    let mut segments = Vec::new();
+
+    let mut i = 0;
+    while i + 2 <= data.len() {
+        let segment_type = match data[i] {
+            1 => SegmentType::Set,
+            3 => SegmentType::ConfedSequence,
+            4 => SegmentType::ConfedSet,
+            _ => SegmentType::Sequence,
+        };
+        let count = data[i+1] as usize;
+        let asns = match data.get(i+2 .. i+2 + count * 2) {
+            Some(asns) => asns.chunks(2).map(NetworkEndian::read_u16).collect(),
+            None => break,
+        };
+        segments.push(AsPathSegment { segment_type, asns });
+        i += 2 + count * 2;
+    }
+
+    segments
+}
+
+pub fn compile_as_path(segments: &[AsPathSegment]) -> Vec<u8> {
+    let mut data = Vec::new();
+
+    for segment in segments {
+        data.push(segment.segment_type as u8);
+        data.push(segment.asns.len() as u8);
+        for asn in &segment.asns {
+            data.write_u16::<NetworkEndian>(*asn).unwrap();
+        }
+    }
+
+    data
+}
+
+/// AS_PATH with 4-byte AS numbers, as used in MRT TABLE_DUMP_V2 entries (RFC 6396)
+pub fn compile_as4_path(segments: &[AsPathSegment]) -> Vec<u8> {
+    let mut data = Vec::new();
+
+    for segment in segments {
+        data.push(segment.segment_type as u8);
+        data.push(segment.asns.len() as u8);
+        for asn in &segment.asns {
+            data.write_u32::<NetworkEndian>(*asn as u32).unwrap();
+        }
+    }
+
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extract_as_path() {
+        assert_eq!(extract_as_path(&[]), vec![]);
+        assert_eq!(
+            extract_as_path(&[/* type */ 2, /* count */ 2, 0xFD, 0xE9, 0xFD, 0xEA, /* type */ 1, /* count */ 1, 0, 100]),
+            vec![
+                AsPathSegment { segment_type: SegmentType::Sequence, asns: vec![65001, 65002] },
+                AsPathSegment { segment_type: SegmentType::Set, asns: vec![100] },
+            ]
+        );
+        assert_eq!(
+            extract_as_path(&[/* type */ 3, /* count */ 1, 0xFD, 0xF2, /* type */ 4, /* count */ 1, 0xFD, 0xF3]),
+            vec![
+                AsPathSegment { segment_type: SegmentType::ConfedSequence, asns: vec![65010] },
+                AsPathSegment { segment_type: SegmentType::ConfedSet, asns: vec![65011] },
+            ]
+        );
+        assert_eq!(
+            extract_as_path(&[/* type */ 2, /* count */ 1, 0, 100, /* type */ 2, /* count */ 3, 0, 200]),
+            vec![AsPathSegment { segment_type: SegmentType::Sequence, asns: vec![100] }]
+        );
+    }
+
+    #[test]
+    fn test_compile_as_path() {
+        assert_eq!(compile_as_path(&[]), Vec::<u8>::new());
+        assert_eq!(
+            compile_as_path(&[AsPathSegment { segment_type: SegmentType::Sequence, asns: vec![65001, 65002] }]),
+            vec![/* type */ 2, /* count */ 2, 0xFD, 0xE9, 0xFD, 0xEA]
+        );
+        assert_eq!(
+            compile_as4_path(&[AsPathSegment { segment_type: SegmentType::Set, asns: vec![65001] }]),
+            vec![/* type */ 1, /* count */ 1, 0, 0, 0xFD, 0xE9]
+        );
+    }
+
+    #[test]
+    fn test_display_as_path() {
+        let path = vec![
+            AsPathSegment { segment_type: SegmentType::Sequence, asns: vec![65001, 65002] },
+            AsPathSegment { segment_type: SegmentType::Set, asns: vec![100, 200] },
+        ];
+        assert_eq!(AsPathDisplay(&path).to_string(), "65001 65002 {100 200}");
+        let confed = vec![
+            AsPathSegment { segment_type: SegmentType::ConfedSequence, asns: vec![65010, 65011] },
+            AsPathSegment { segment_type: SegmentType::ConfedSet, asns: vec![65012] },
+            AsPathSegment { segment_type: SegmentType::Sequence, asns: vec![65001] },
+        ];
+        assert_eq!(AsPathDisplay(&confed).to_string(), "(65010 65011) [65012] 65001");
+        assert_eq!(AsPathDisplay(&[]).to_string(), "");
+    }
+}
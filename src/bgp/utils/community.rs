@@ -0,0 +1,102 @@
+use byteorder::{ByteOrder, NetworkEndian, WriteBytesExt};
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+/// RFC 1997 community, stored as the raw 32-bit value
+#[derive(PartialEq, Eq, Hash, Clone, Copy, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub struct Community(pub u32);
+
+const WELL_KNOWN: [(&str, u32); 4] = [
+    ("no-export", 0xFFFF_FF01),
+    ("no-advertise", 0xFFFF_FF02),
+    ("no-export-subconfed", 0xFFFF_FF03),
+    ("blackhole", 0xFFFF_029A), // RFC 7999, 65535:666
+];
+
+impl Community {
+    pub fn new(asn: u16, value: u16) -> Community {
+        Community((asn as u32) << 16 | value as u32)
+    }
+}
+
+impl fmt::Debug for Community {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_fmt(format_args!("{}:{}", self.0 >> 16, self.0 & 0xFFFF))
+    }
+}
+
+impl fmt::Display for Community {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+#[error("Invalid community: {0}")]
+pub struct CommunityParseError(String);
+
+impl FromStr for Community {
+    type Err = CommunityParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some((_, value)) = WELL_KNOWN.iter().find(|(name, _)| *name == s) {
+            return Ok(Community(*value));
+        }
+        let error = || CommunityParseError(s.to_string());
+        let (asn, value) = s.split_once(':').ok_or_else(error)?;
+        Ok(Community::new(asn.parse().map_err(|_| error())?, value.parse().map_err(|_| error())?))
+    }
+}
+
+impl TryFrom<String> for Community {
+    type Error = CommunityParseError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+pub fn extract_communities(data: &[u8]) -> Vec<Community> {
+    data.chunks_exact(4).map(|chunk| Community(NetworkEndian::read_u32(chunk))).collect()
+}
+
+pub fn compile_communities(communities: &[Community]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(communities.len() * 4);
+    for community in communities {
+        data.write_u32::<NetworkEndian>(community.0).unwrap();
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_community() {
+        assert_eq!("65001:666".parse(), Ok(Community(0xFDE9_029A)));
+        assert_eq!("blackhole".parse(), Ok(Community::new(65535, 666)));
+        assert_eq!("no-export".parse(), Ok(Community(0xFFFF_FF01)));
+        assert!("65001".parse::<Community>().is_err());
+        assert!("70000:1".parse::<Community>().is_err());
+    }
+
+    #[test]
+    fn test_extract_communities() {
+        assert_eq!(extract_communities(&[]), vec![]);
+        assert_eq!(
+            extract_communities(&[0xFD, 0xE9, 0x02, 0x9A, 0xFF, 0xFF, 0xFF, 0x01]),
+            vec![Community::new(65001, 666), Community(0xFFFF_FF01)]
+        );
+    }
+
+    #[test]
+    fn test_compile_communities() {
+        assert_eq!(
+            compile_communities(&[Community::new(65001, 666), Community(0xFFFF_FF01)]),
+            vec![0xFD, 0xE9, 0x02, 0x9A, 0xFF, 0xFF, 0xFF, 0x01]
+        );
+    }
+}
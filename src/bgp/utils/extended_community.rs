@@ -0,0 +1,74 @@
+use std::fmt;
+
+/// RFC 4360 extended community, stored as the raw 8-byte value
+#[derive(PartialEq, Eq, Hash, Clone, Copy)]
+pub struct ExtendedCommunity(pub [u8; 8]);
+
+/// Transitive experimental type used by the FlowSpec traffic actions (RFC 8955)
+const TYPE_FLOWSPEC: u8 = 0x80;
+const SUBTYPE_TRAFFIC_RATE: u8 = 0x06;
+const SUBTYPE_REDIRECT: u8 = 0x08;
+const SUBTYPE_TRAFFIC_MARKING: u8 = 0x09;
+
+impl ExtendedCommunity {
+    /// Limits matching traffic to `bytes_per_second`, 0 discards it
+    pub fn traffic_rate(asn: u16, bytes_per_second: f32) -> ExtendedCommunity {
+        let mut value = [TYPE_FLOWSPEC, SUBTYPE_TRAFFIC_RATE, 0, 0, 0, 0, 0, 0];
+        value[2..4].copy_from_slice(&asn.to_be_bytes());
+        value[4..8].copy_from_slice(&bytes_per_second.to_be_bytes());
+        ExtendedCommunity(value)
+    }
+
+    /// Redirects matching traffic to the VRF with the route target `asn:target`
+    pub fn redirect(asn: u16, target: u32) -> ExtendedCommunity {
+        let mut value = [TYPE_FLOWSPEC, SUBTYPE_REDIRECT, 0, 0, 0, 0, 0, 0];
+        value[2..4].copy_from_slice(&asn.to_be_bytes());
+        value[4..8].copy_from_slice(&target.to_be_bytes());
+        ExtendedCommunity(value)
+    }
+
+    /// Rewrites the DSCP of matching traffic
+    pub fn traffic_marking(dscp: u8) -> ExtendedCommunity {
+        ExtendedCommunity([TYPE_FLOWSPEC, SUBTYPE_TRAFFIC_MARKING, 0, 0, 0, 0, 0, dscp & 0x3F])
+    }
+}
+
+impl fmt::Debug for ExtendedCommunity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let asn = u16::from_be_bytes([self.0[2], self.0[3]]);
+        let value = [self.0[4], self.0[5], self.0[6], self.0[7]];
+        match (self.0[0], self.0[1]) {
+            (TYPE_FLOWSPEC, SUBTYPE_TRAFFIC_RATE) => f.write_fmt(format_args!("traffic-rate:{}:{}", asn, f32::from_be_bytes(value))),
+            (TYPE_FLOWSPEC, SUBTYPE_REDIRECT) => f.write_fmt(format_args!("redirect:{}:{}", asn, u32::from_be_bytes(value))),
+            (TYPE_FLOWSPEC, SUBTYPE_TRAFFIC_MARKING) => f.write_fmt(format_args!("traffic-marking:{}", self.0[7])),
+            _ => f.write_fmt(format_args!("{:02x?}", self.0)),
+        }
+    }
+}
+
+pub fn compile_extended_communities(communities: &[ExtendedCommunity]) -> Vec<u8> {
+    communities.iter().flat_map(|community| community.0.iter().copied()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_traffic_actions() {
+        assert_eq!(ExtendedCommunity::traffic_rate(0, 0.0).0, [0x80, 0x06, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(ExtendedCommunity::traffic_rate(65002, 1250.0).0, [0x80, 0x06, 0xFD, 0xEA, 0x44, 0x9C, 0x40, 0x00]);
+        assert_eq!(ExtendedCommunity::redirect(65002, 666).0, [0x80, 0x08, 0xFD, 0xEA, 0, 0, 0x02, 0x9A]);
+        assert_eq!(ExtendedCommunity::traffic_marking(46).0, [0x80, 0x09, 0, 0, 0, 0, 0, 46]);
+        assert_eq!(format!("{:?}", ExtendedCommunity::redirect(65002, 666)), "redirect:65002:666");
+    }
+
+    #[test]
+    fn test_compile_extended_communities() {
+        assert_eq!(compile_extended_communities(&[]), Vec::<u8>::new());
+        assert_eq!(
+            compile_extended_communities(&[ExtendedCommunity::traffic_marking(10), ExtendedCommunity::traffic_rate(0, 0.0)]),
+            vec![0x80, 0x09, 0, 0, 0, 0, 0, 10, 0x80, 0x06, 0, 0, 0, 0, 0, 0]
+        );
+    }
+}
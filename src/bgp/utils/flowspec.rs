@@ -0,0 +1,624 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::marker::PhantomData;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use super::extended_community::ExtendedCommunity;
+use crate::bgp::{AFI_IPV4, AFI_IPV6};
+
+const COMPONENT_DESTINATION_PREFIX: u8 = 1;
+const COMPONENT_SOURCE_PREFIX: u8 = 2;
+const COMPONENT_PROTOCOL: u8 = 3;
+const COMPONENT_PORT: u8 = 4;
+const COMPONENT_DESTINATION_PORT: u8 = 5;
+const COMPONENT_SOURCE_PORT: u8 = 6;
+const COMPONENT_ICMP_TYPE: u8 = 7;
+const COMPONENT_ICMP_CODE: u8 = 8;
+const COMPONENT_TCP_FLAGS: u8 = 9;
+const COMPONENT_PACKET_LENGTH: u8 = 10;
+const COMPONENT_DSCP: u8 = 11;
+const COMPONENT_FRAGMENT: u8 = 12;
+
+const OPERATOR_END_OF_LIST: u8 = 0x80;
+const OPERATOR_AND: u8 = 0x40;
+const NUMERIC_LESS: u8 = 0x04;
+const NUMERIC_GREATER: u8 = 0x02;
+const NUMERIC_EQUAL: u8 = 0x01;
+const BITMASK_NOT: u8 = 0x02;
+const BITMASK_MATCH: u8 = 0x01;
+
+/// Longest NLRI that fits the two byte length encoding
+const MAX_NLRI_LENGTH: usize = 0xFFF;
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum FlowSpecError {
+    #[error("Invalid FlowSpec prefix: {0}")]
+    Prefix(String),
+    #[error("Invalid FlowSpec numeric match: {0}")]
+    Numeric(String),
+    #[error("Invalid FlowSpec bitmask match: {0}")]
+    Bitmask(String),
+    #[error("Invalid route target: {0}")]
+    RouteTarget(String),
+    #[error("FlowSpec rule mixes IPv4 and IPv6 prefixes")]
+    MixedFamilies,
+    #[error("FlowSpec rule has no match components")]
+    Empty,
+    #[error("FlowSpec rule is too long to encode")]
+    TooLong,
+}
+
+/// IPv4 or IPv6 prefix of a destination or source prefix component
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct FlowPrefix {
+    pub address: IpAddr,
+    pub length: u8,
+}
+
+impl FlowPrefix {
+    fn compile(&self, data: &mut Vec<u8>) {
+        let octets = match self.address {
+            IpAddr::V4(address) => address.octets().to_vec(),
+            IpAddr::V6(address) => address.octets().to_vec(),
+        };
+        data.push(self.length);
+        if self.address.is_ipv6() {
+            // Pattern offset (RFC 8956), we always match from the first bit
+            data.push(0);
+        }
+        data.extend_from_slice(&octets[..(self.length as usize).div_ceil(8)]);
+    }
+}
+
+impl fmt::Display for FlowPrefix {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_fmt(format_args!("{}/{}", self.address, self.length))
+    }
+}
+
+impl FromStr for FlowPrefix {
+    type Err = FlowSpecError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || FlowSpecError::Prefix(s.to_string());
+        let address: IpAddr = s.split('/').next().unwrap_or_default().parse().map_err(|_| error())?;
+        let max_length = if address.is_ipv4() { 32 } else { 128 };
+        let length = match s.split_once('/') {
+            Some((_, length)) => length.parse::<u8>().map_err(|_| error())?,
+            None => max_length,
+        };
+        if length > max_length {
+            return Err(error());
+        }
+        let address = match address {
+            IpAddr::V4(address) => {
+                let mask = if length == 0 { 0 } else { u32::MAX << (32 - length as u32) };
+                IpAddr::from((u32::from(address) & mask).to_be_bytes())
+            },
+            IpAddr::V6(address) => {
+                let mask = if length == 0 { 0 } else { u128::MAX << (128 - length as u32) };
+                IpAddr::from((u128::from(address) & mask).to_be_bytes())
+            },
+        };
+        Ok(FlowPrefix { address, length })
+    }
+}
+
+impl TryFrom<String> for FlowPrefix {
+    type Error = FlowSpecError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<FlowPrefix> for String {
+    fn from(prefix: FlowPrefix) -> String {
+        prefix.to_string()
+    }
+}
+
+/// Length bits of an operator byte for the smallest encoding of `value`
+fn value_length(value: u64) -> (u8, usize) {
+    match value {
+        0 ..= 0xFF => (0x00, 1),
+        0x100 ..= 0xFFFF => (0x10, 2),
+        0x1_0000 ..= 0xFFFF_FFFF => (0x20, 4),
+        _ => (0x30, 8),
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+impl Comparison {
+    const SYMBOLS: [(&'static str, Comparison); 7] = [
+        ("!=", Comparison::NotEqual),
+        ("<=", Comparison::LessOrEqual),
+        (">=", Comparison::GreaterOrEqual),
+        ("<", Comparison::Less),
+        (">", Comparison::Greater),
+        ("==", Comparison::Equal),
+        ("=", Comparison::Equal),
+    ];
+
+    fn bits(self) -> u8 {
+        match self {
+            Comparison::Equal => NUMERIC_EQUAL,
+            Comparison::NotEqual => NUMERIC_LESS | NUMERIC_GREATER,
+            Comparison::Less => NUMERIC_LESS,
+            Comparison::LessOrEqual => NUMERIC_LESS | NUMERIC_EQUAL,
+            Comparison::Greater => NUMERIC_GREATER,
+            Comparison::GreaterOrEqual => NUMERIC_GREATER | NUMERIC_EQUAL,
+        }
+    }
+}
+
+/// Numeric comparisons that all have to hold, e.g. `>=1024&<=2048`. A bare number compares for
+/// equality. A component matches if any of its numeric matches does.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct NumericMatch(pub Vec<(Comparison, u64)>);
+
+impl NumericMatch {
+    pub fn equal(value: u64) -> NumericMatch {
+        NumericMatch(vec![(Comparison::Equal, value)])
+    }
+}
+
+impl fmt::Display for NumericMatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let terms: Vec<String> = self.0.iter().map(|(comparison, value)| match comparison {
+            Comparison::Equal => value.to_string(),
+            _ => {
+                let (symbol, _) = Comparison::SYMBOLS.iter().find(|(_, other)| other == comparison).unwrap();
+                format!("{}{}", symbol, value)
+            },
+        }).collect();
+        f.write_str(&terms.join("&"))
+    }
+}
+
+impl FromStr for NumericMatch {
+    type Err = FlowSpecError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || FlowSpecError::Numeric(s.to_string());
+        let terms = s.split('&').map(|term| {
+            let term = term.trim();
+            let (comparison, value) = Comparison::SYMBOLS.iter()
+                .find_map(|(symbol, comparison)| term.strip_prefix(symbol).map(|value| (*comparison, value)))
+                .unwrap_or((Comparison::Equal, term));
+            Ok((comparison, value.trim().parse::<u64>().map_err(|_| error())?))
+        }).collect::<Result<Vec<_>, _>>()?;
+        Ok(NumericMatch(terms))
+    }
+}
+
+impl TryFrom<String> for NumericMatch {
+    type Error = FlowSpecError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<NumericMatch> for String {
+    fn from(numeric: NumericMatch) -> String {
+        numeric.to_string()
+    }
+}
+
+/// Names of the bits in a bitmask component
+pub trait BitmaskFlags: Clone {
+    const NAMES: &'static [(&'static str, u8)];
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct TcpFlags;
+
+impl BitmaskFlags for TcpFlags {
+    const NAMES: &'static [(&'static str, u8)] = &[
+        ("fin", 0x01), ("syn", 0x02), ("rst", 0x04), ("psh", 0x08),
+        ("ack", 0x10), ("urg", 0x20), ("ece", 0x40), ("cwr", 0x80),
+    ];
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct FragmentFlags;
+
+impl BitmaskFlags for FragmentFlags {
+    const NAMES: &'static [(&'static str, u8)] = &[
+        ("dont-fragment", 0x01), ("is-fragment", 0x02), ("first-fragment", 0x04), ("last-fragment", 0x08),
+    ];
+}
+
+/// Bit test of a bitmask component. Without `exact` any of the bits has to be set, with it
+/// exactly these bits. `not` negates the result.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct BitmaskTerm {
+    pub not: bool,
+    pub exact: bool,
+    pub bits: u8,
+}
+
+/// Bit tests that all have to hold, written as `[!][=]flag[+flag]...` joined by `&`,
+/// e.g. `syn&!ack` or `=syn+ack`. A component matches if any of its bitmask matches does.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+#[serde(bound = "")]
+pub struct BitmaskMatch<F: BitmaskFlags> {
+    pub terms: Vec<BitmaskTerm>,
+    flags: PhantomData<F>,
+}
+
+impl<F: BitmaskFlags> BitmaskMatch<F> {
+    pub fn new(terms: Vec<BitmaskTerm>) -> BitmaskMatch<F> {
+        BitmaskMatch { terms, flags: PhantomData }
+    }
+}
+
+impl<F: BitmaskFlags> fmt::Display for BitmaskMatch<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let terms: Vec<String> = self.terms.iter().map(|term| {
+            let names: Vec<&str> = F::NAMES.iter()
+                .filter(|(_, bit)| term.bits & bit != 0)
+                .map(|(name, _)| *name)
+                .collect();
+            format!("{}{}{}", if term.not { "!" } else { "" }, if term.exact { "=" } else { "" }, names.join("+"))
+        }).collect();
+        f.write_str(&terms.join("&"))
+    }
+}
+
+impl<F: BitmaskFlags> FromStr for BitmaskMatch<F> {
+    type Err = FlowSpecError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || FlowSpecError::Bitmask(s.to_string());
+        let terms = s.split('&').map(|term| {
+            let term = term.trim();
+            let (not, term) = term.strip_prefix('!').map_or((false, term), |term| (true, term));
+            let (exact, term) = term.strip_prefix('=').map_or((false, term), |term| (true, term));
+            let mut bits = 0;
+            for name in term.split('+') {
+                let (_, bit) = F::NAMES.iter().find(|(known, _)| *known == name).ok_or_else(error)?;
+                bits |= bit;
+            }
+            Ok(BitmaskTerm { not, exact, bits })
+        }).collect::<Result<Vec<_>, _>>()?;
+        Ok(BitmaskMatch::new(terms))
+    }
+}
+
+impl<F: BitmaskFlags> TryFrom<String> for BitmaskMatch<F> {
+    type Error = FlowSpecError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl<F: BitmaskFlags> From<BitmaskMatch<F>> for String {
+    fn from(bitmask: BitmaskMatch<F>) -> String {
+        bitmask.to_string()
+    }
+}
+
+/// Route target `asn:value` of a redirect action
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct RouteTarget {
+    pub asn: u16,
+    pub value: u32,
+}
+
+impl fmt::Display for RouteTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_fmt(format_args!("{}:{}", self.asn, self.value))
+    }
+}
+
+impl FromStr for RouteTarget {
+    type Err = FlowSpecError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || FlowSpecError::RouteTarget(s.to_string());
+        let (asn, value) = s.split_once(':').ok_or_else(error)?;
+        Ok(RouteTarget { asn: asn.parse().map_err(|_| error())?, value: value.parse().map_err(|_| error())? })
+    }
+}
+
+impl TryFrom<String> for RouteTarget {
+    type Error = FlowSpecError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<RouteTarget> for String {
+    fn from(target: RouteTarget) -> String {
+        target.to_string()
+    }
+}
+
+/// Traffic action applied to matching traffic, advertised as an extended community
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FlowSpecAction {
+    Discard,
+    /// Bytes per second
+    RateLimit(f32),
+    Redirect(RouteTarget),
+    /// DSCP value
+    Mark(u8),
+}
+
+impl From<FlowSpecAction> for ExtendedCommunity {
+    fn from(action: FlowSpecAction) -> ExtendedCommunity {
+        match action {
+            FlowSpecAction::Discard => ExtendedCommunity::traffic_rate(0, 0.0),
+            FlowSpecAction::RateLimit(bytes_per_second) => ExtendedCommunity::traffic_rate(0, bytes_per_second),
+            FlowSpecAction::Redirect(target) => ExtendedCommunity::redirect(target.asn, target.value),
+            FlowSpecAction::Mark(dscp) => ExtendedCommunity::traffic_marking(dscp),
+        }
+    }
+}
+
+/// Flow specification (RFC 8955, RFC 8956 for IPv6). The rule is IPv6 if its prefixes are,
+/// rules without prefixes are IPv4.
+#[derive(Debug, PartialEq, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct FlowSpecRule {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub destination: Option<FlowPrefix>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<FlowPrefix>,
+    /// IP protocol, or next header for IPv6
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub protocol: Vec<NumericMatch>,
+    /// Source or destination port
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub port: Vec<NumericMatch>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub destination_port: Vec<NumericMatch>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub source_port: Vec<NumericMatch>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub icmp_type: Vec<NumericMatch>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub icmp_code: Vec<NumericMatch>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tcp_flags: Vec<BitmaskMatch<TcpFlags>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub packet_length: Vec<NumericMatch>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dscp: Vec<NumericMatch>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fragment: Vec<BitmaskMatch<FragmentFlags>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub actions: Vec<FlowSpecAction>,
+}
+
+fn compile_numeric(type_code: u8, matches: &[NumericMatch], data: &mut Vec<u8>) {
+    if matches.is_empty() {
+        return;
+    }
+    data.push(type_code);
+    let terms: Vec<(bool, Comparison, u64)> = matches.iter()
+        .flat_map(|numeric| numeric.0.iter().enumerate().map(|(i, (comparison, value))| (i > 0, *comparison, *value)))
+        .collect();
+    for (i, (and, comparison, value)) in terms.iter().enumerate() {
+        let (length_bits, length) = value_length(*value);
+        let mut operator = length_bits | comparison.bits();
+        if *and {
+            operator |= OPERATOR_AND;
+        }
+        if i == terms.len() - 1 {
+            operator |= OPERATOR_END_OF_LIST;
+        }
+        data.push(operator);
+        data.extend_from_slice(&value.to_be_bytes()[8 - length..]);
+    }
+}
+
+fn compile_bitmask<F: BitmaskFlags>(type_code: u8, matches: &[BitmaskMatch<F>], data: &mut Vec<u8>) {
+    if matches.is_empty() {
+        return;
+    }
+    data.push(type_code);
+    let terms: Vec<(bool, BitmaskTerm)> = matches.iter()
+        .flat_map(|bitmask| bitmask.terms.iter().enumerate().map(|(i, term)| (i > 0, *term)))
+        .collect();
+    for (i, (and, term)) in terms.iter().enumerate() {
+        let mut operator = 0;
+        if *and {
+            operator |= OPERATOR_AND;
+        }
+        if term.not {
+            operator |= BITMASK_NOT;
+        }
+        if term.exact {
+            operator |= BITMASK_MATCH;
+        }
+        if i == terms.len() - 1 {
+            operator |= OPERATOR_END_OF_LIST;
+        }
+        data.push(operator);
+        data.push(term.bits);
+    }
+}
+
+impl FlowSpecRule {
+    pub fn afi(&self) -> u16 {
+        let ipv6 = self.destination.iter().chain(self.source.iter()).any(|prefix| prefix.address.is_ipv6());
+        if ipv6 { AFI_IPV6 } else { AFI_IPV4 }
+    }
+
+    /// Checks that the rule can be advertised
+    pub fn validate(&self) -> Result<(), FlowSpecError> {
+        let prefixes: Vec<&FlowPrefix> = self.destination.iter().chain(self.source.iter()).collect();
+        if prefixes.windows(2).any(|pair| pair[0].address.is_ipv6() != pair[1].address.is_ipv6()) {
+            return Err(FlowSpecError::MixedFamilies);
+        }
+        if self.compile_components().is_empty() {
+            return Err(FlowSpecError::Empty);
+        }
+        if self.compile_components().len() > MAX_NLRI_LENGTH {
+            return Err(FlowSpecError::TooLong);
+        }
+        Ok(())
+    }
+
+    /// Whether both rules match the same traffic, regardless of their actions
+    pub fn same_match(&self, other: &FlowSpecRule) -> bool {
+        self.compile_components() == other.compile_components()
+    }
+
+    pub fn extended_communities(&self) -> Vec<ExtendedCommunity> {
+        self.actions.iter().map(|action| (*action).into()).collect()
+    }
+
+    fn compile_components(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        if let Some(destination) = &self.destination {
+            data.push(COMPONENT_DESTINATION_PREFIX);
+            destination.compile(&mut data);
+        }
+        if let Some(source) = &self.source {
+            data.push(COMPONENT_SOURCE_PREFIX);
+            source.compile(&mut data);
+        }
+        compile_numeric(COMPONENT_PROTOCOL, &self.protocol, &mut data);
+        compile_numeric(COMPONENT_PORT, &self.port, &mut data);
+        compile_numeric(COMPONENT_DESTINATION_PORT, &self.destination_port, &mut data);
+        compile_numeric(COMPONENT_SOURCE_PORT, &self.source_port, &mut data);
+        compile_numeric(COMPONENT_ICMP_TYPE, &self.icmp_type, &mut data);
+        compile_numeric(COMPONENT_ICMP_CODE, &self.icmp_code, &mut data);
+        compile_bitmask(COMPONENT_TCP_FLAGS, &self.tcp_flags, &mut data);
+        compile_numeric(COMPONENT_PACKET_LENGTH, &self.packet_length, &mut data);
+        compile_numeric(COMPONENT_DSCP, &self.dscp, &mut data);
+        compile_bitmask(COMPONENT_FRAGMENT, &self.fragment, &mut data);
+        data
+    }
+
+    /// The NLRI carried in MP_REACH_NLRI/MP_UNREACH_NLRI, components prefixed by their length
+    pub fn compile_nlri(&self) -> Vec<u8> {
+        let components = self.compile_components();
+        let mut data = Vec::with_capacity(components.len() + 2);
+        if components.len() < 240 {
+            data.push(components.len() as u8);
+        } else {
+            data.extend_from_slice(&(0xF000 | components.len() as u16).to_be_bytes());
+        }
+        data.extend(components);
+        data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_matches() {
+        assert_eq!("123".parse(), Ok(NumericMatch::equal(123)));
+        assert_eq!(
+            ">=1024&<=2048".parse(),
+            Ok(NumericMatch(vec![(Comparison::GreaterOrEqual, 1024), (Comparison::LessOrEqual, 2048)]))
+        );
+        assert_eq!(NumericMatch(vec![(Comparison::NotEqual, 1), (Comparison::Equal, 2)]).to_string(), "!=1&2");
+        assert!("<=x".parse::<NumericMatch>().is_err());
+
+        let flags: BitmaskMatch<TcpFlags> = "syn&!=ack+rst".parse().unwrap();
+        assert_eq!(flags.terms, vec![
+            BitmaskTerm { not: false, exact: false, bits: 0x02 },
+            BitmaskTerm { not: true, exact: true, bits: 0x14 },
+        ]);
+        assert_eq!(flags.to_string(), "syn&!=rst+ack");
+        assert!("syn".parse::<BitmaskMatch<FragmentFlags>>().is_err());
+
+        assert_eq!(
+            "2001:db8::1/32".parse(),
+            Ok(FlowPrefix { address: "2001:db8::".parse().unwrap(), length: 32 })
+        );
+        assert!("10.0.0.0/33".parse::<FlowPrefix>().is_err());
+    }
+
+    #[test]
+    fn test_compile_ipv4_nlri() {
+        // Destination 10.0.1/24, protocol 6 or 17, port 80 or 8080
+        let rule = FlowSpecRule {
+            destination: Some("10.0.1.0/24".parse().unwrap()),
+            protocol: vec![NumericMatch::equal(6), NumericMatch::equal(17)],
+            port: vec![NumericMatch::equal(80), NumericMatch::equal(8080)],
+            ..Default::default()
+        };
+        assert_eq!(rule.compile_nlri(), vec![
+            0x10,
+            0x01, 0x18, 0x0a, 0x00, 0x01,
+            0x03, 0x01, 0x06, 0x81, 0x11,
+            0x04, 0x01, 0x50, 0x91, 0x1f, 0x90,
+        ]);
+        assert_eq!(rule.afi(), AFI_IPV4);
+    }
+
+    #[test]
+    fn test_compile_ipv6_nlri() {
+        let rule = FlowSpecRule {
+            source: Some("2001:db8::/32".parse().unwrap()),
+            destination_port: vec![">=1024&<=2048".parse().unwrap()],
+            fragment: vec!["is-fragment".parse().unwrap()],
+            ..Default::default()
+        };
+        assert_eq!(rule.compile_nlri(), vec![
+            0x11,
+            0x02, 0x20, 0x00, 0x20, 0x01, 0x0d, 0xb8,
+            0x05, 0x13, 0x04, 0x00, 0xd5, 0x08, 0x00,
+            0x0c, 0x80, 0x02,
+        ]);
+        assert_eq!(rule.afi(), AFI_IPV6);
+    }
+
+    #[test]
+    fn test_validate() {
+        assert_eq!(FlowSpecRule::default().validate(), Err(FlowSpecError::Empty));
+        let mixed = FlowSpecRule {
+            destination: Some("10.0.0.1/32".parse().unwrap()),
+            source: Some("2001:db8::/32".parse().unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(mixed.validate(), Err(FlowSpecError::MixedFamilies));
+    }
+
+    #[test]
+    fn test_rule_json() {
+        let rule: FlowSpecRule = serde_json::from_str(r#"{
+            "destination": "192.0.2.1/32",
+            "source": "198.51.100.0/24",
+            "protocol": ["17"],
+            "source-port": ["123"],
+            "actions": ["discard", {"rate-limit": 1000}, {"redirect": "65002:666"}]
+        }"#).unwrap();
+        assert!(rule.validate().is_ok());
+        assert_eq!(rule.extended_communities(), vec![
+            ExtendedCommunity::traffic_rate(0, 0.0),
+            ExtendedCommunity::traffic_rate(0, 1000.0),
+            ExtendedCommunity::redirect(65002, 666),
+        ]);
+        let reencoded: FlowSpecRule = serde_json::from_value(serde_json::to_value(&rule).unwrap()).unwrap();
+        assert_eq!(reencoded, rule);
+    }
+}
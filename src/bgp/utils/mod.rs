@@ -1,2 +1,6 @@
-pub(crate) mod prefix;
-pub(crate) mod path_attribute;
\ No newline at end of file
+pub mod prefix;
+pub mod path_attribute;
+pub mod as_path;
+pub mod community;
+pub mod extended_community;
+pub mod flowspec;
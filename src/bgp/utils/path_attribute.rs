@@ -5,7 +5,7 @@ use std::fmt::Formatter;
 
 macro_rules! extended_enum {
     ($name:ident, [$($value:literal: $label:ident),+ $(,)?]) => {
-        #[derive(Debug, PartialEq)]
+        #[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
         pub enum $name {
             $(
                 $label,
@@ -24,9 +24,9 @@ macro_rules! extended_enum {
             }
         }
 
-        impl Into<u8> for $name {
-            fn into(self) -> u8 {
-                match self {
+        impl From<$name> for u8 {
+            fn from(value: $name) -> u8 {
+                match value {
                     $(
                         $name::$label => $value,
                     )+
@@ -53,15 +53,40 @@ extended_enum!(AttributeType, [
     5: LocalPref,
     6: AtomicAggregate,
     7: Aggregator,
+    8: Communities,
+    9: OriginatorId,
+    10: ClusterList,
+    14: MpReachNlri,
+    15: MpUnreachNlri,
+    16: ExtendedCommunities,
 ]);
 
-#[derive(PartialEq)]
+#[derive(PartialEq, Clone)]
 pub struct PathAttribute {
     pub flags: Vec<AttributeFlag>,
     pub type_code: AttributeType,
     pub value: Vec<u8>,
 }
 
+impl PathAttribute {
+    /// Attribute with the flags its type requires, the extended length flag is set for values
+    /// longer than 255 bytes. Unknown types are optional transitive.
+    pub fn new(type_code: AttributeType, value: Vec<u8>) -> PathAttribute {
+        let mut flags = match type_code {
+            AttributeType::Origin | AttributeType::ASPath | AttributeType::NextHop
+                | AttributeType::LocalPref | AttributeType::AtomicAggregate => vec![AttributeFlag::Transitive],
+            AttributeType::MultiExitDisc | AttributeType::OriginatorId | AttributeType::ClusterList
+                | AttributeType::MpReachNlri | AttributeType::MpUnreachNlri => vec![AttributeFlag::Optional],
+            AttributeType::Aggregator | AttributeType::Communities | AttributeType::ExtendedCommunities
+                | AttributeType::Unknown(_) => vec![AttributeFlag::Optional, AttributeFlag::Transitive],
+        };
+        if value.len() > u8::MAX as usize {
+            flags.push(AttributeFlag::ExtendedLength);
+        }
+        PathAttribute { flags, type_code, value }
+    }
+}
+
 impl std::fmt::Debug for PathAttribute {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.write_fmt(format_args!("[PathAttribute] {:?}: {:?} (Flags: {:?})", self.type_code, self.value, self.flags))
@@ -73,15 +98,12 @@ fn extract_attribute_flags(flags_bitfield: u8) -> Vec<AttributeFlag> {
     for offset in 4 .. 8 {
         let flag_bit = 1 << offset;
         if flags_bitfield & flag_bit != 0 {
+            // Every one of the upper four bits is a known flag
             let flag: Option<AttributeFlag> = FromPrimitive::from_u8(flag_bit);
-            if let Some(flag) = flag {
-                flags.push(flag);
-            } else {
-                panic!(format!("Bad attribute flags: {}", flags_bitfield));
-            }
+            flags.extend(flag);
         }
     }
-    return flags
+    flags
 }
 
 fn compile_attribute_flags(flags: &Vec<AttributeFlag>) -> u8 {
@@ -89,28 +111,28 @@ fn compile_attribute_flags(flags: &Vec<AttributeFlag>) -> u8 {
     for flag in flags {
         bitfield |= *flag as u8;
     }
-    return bitfield
+    bitfield
 }
 
-pub(crate) fn extract_path_attributes(data: &[u8]) -> Vec<PathAttribute> {
+/// Extracts the path attributes, `None` if an attribute runs past the end
+pub fn extract_path_attributes(data: &[u8]) -> Option<Vec<PathAttribute>> {
     let mut path_attributes = Vec::new();
 
-    let mut bytes_left = data.len();
     let mut i = 0;
-    while bytes_left > 0 {
+    while i < data.len() {
         let flags = extract_attribute_flags(data[i]);
-        let type_code = data[i+1];
+        let type_code = *data.get(i+1)?;
 
         let attribute_length;
         let attribute_header_length;
         if flags.contains(&AttributeFlag::ExtendedLength) { // Extended length
-            attribute_length = NetworkEndian::read_u16(&data[i+2..i+4]) as usize;
+            attribute_length = NetworkEndian::read_u16(data.get(i+2..i+4)?) as usize;
             attribute_header_length = 4;
         } else { // Normal length
-            attribute_length = data[i+2] as usize;
+            attribute_length = *data.get(i+2)? as usize;
             attribute_header_length = 3;
         }
-        let attribute_value = data[i + attribute_header_length .. i + attribute_header_length + attribute_length].to_vec();
+        let attribute_value = data.get(i + attribute_header_length .. i + attribute_header_length + attribute_length)?.to_vec();
         path_attributes.push(
             PathAttribute {
                 flags,
@@ -119,12 +141,11 @@ pub(crate) fn extract_path_attributes(data: &[u8]) -> Vec<PathAttribute> {
             }
         );
         i += attribute_header_length + attribute_length;
-        bytes_left -= attribute_header_length + attribute_length;
     }
-    return path_attributes;
+    Some(path_attributes)
 }
 
-pub(crate) fn compile_path_attributes(attributes: Vec<PathAttribute>) -> Vec<u8> {
+pub fn compile_path_attributes(attributes: Vec<PathAttribute>) -> Vec<u8> {
     let mut buffer = Vec::new();
     for attribute in attributes {
         buffer.push(compile_attribute_flags(&attribute.flags));
@@ -136,7 +157,7 @@ pub(crate) fn compile_path_attributes(attributes: Vec<PathAttribute>) -> Vec<u8>
         }
         buffer.extend(&attribute.value);
     }
-    return buffer;
+    buffer
 }
 
 #[cfg(test)]
@@ -175,16 +196,16 @@ mod tests {
 
     #[test]
     fn test_extract_path_attributes() {
-        assert_eq!(extract_path_attributes(&[]), vec![]);
+        assert_eq!(extract_path_attributes(&[]), Some(vec![]));
 
         assert_eq!(
             extract_path_attributes(&[/* flags */ 0, /* type code */ 0, /* length */ 0]),
-            vec![PathAttribute { type_code: AttributeType::Unknown(0), value: vec![], flags: vec![] }]
+            Some(vec![PathAttribute { type_code: AttributeType::Unknown(0), value: vec![], flags: vec![] }])
         );
 
         assert_eq!(
             extract_path_attributes(&[/* flags */ 0b0100 << 4, /* type code */ 1, /* length */ 1, /* value */ 2]),
-            vec![PathAttribute { type_code: AttributeType::Origin, value: vec![2], flags: vec![AttributeFlag::Transitive] }]
+            Some(vec![PathAttribute { type_code: AttributeType::Origin, value: vec![2], flags: vec![AttributeFlag::Transitive] }])
         );
 
         assert_eq!(
@@ -192,16 +213,20 @@ mod tests {
                 /* flags */ 0b0101 << 4, /* type code */ 2, /* length */ 0, 0,
                 /* flags */ 0b1000 << 4, /* type code */ 4, /* length */ 4, /* value */ 0, 0, 0, 0
             ]),
-            vec![
+            Some(vec![
                 PathAttribute { type_code: AttributeType::ASPath, value: vec![], flags: vec![AttributeFlag::ExtendedLength, AttributeFlag::Transitive] },
                 PathAttribute { type_code: AttributeType::MultiExitDisc, value: vec![0, 0, 0, 0], flags: vec![AttributeFlag::Optional]},
-            ]
+            ])
         );
+
+        // Length past the end, and a header cut short
+        assert_eq!(extract_path_attributes(&[/* flags */ 0b0100 << 4, /* type code */ 1, /* length */ 2, /* value */ 2]), None);
+        assert_eq!(extract_path_attributes(&[/* flags */ 0b0101 << 4, /* type code */ 2, /* length */ 0]), None);
     }
 
     #[test]
     fn test_compile_path_attributes() {
-        assert_eq!(compile_path_attributes(vec![]), vec![]);
+        assert_eq!(compile_path_attributes(vec![]), Vec::<u8>::new());
 
         assert_eq!(
             compile_path_attributes(vec![PathAttribute { type_code: AttributeType::Unknown(0), value: vec![], flags: vec![] }]),
@@ -224,4 +249,15 @@ mod tests {
             ]
         )
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_new_path_attribute() {
+        assert_eq!(PathAttribute::new(AttributeType::NextHop, vec![192, 0, 2, 1]).flags, vec![AttributeFlag::Transitive]);
+        assert_eq!(PathAttribute::new(AttributeType::Communities, vec![]).flags, vec![AttributeFlag::Optional, AttributeFlag::Transitive]);
+        assert_eq!(PathAttribute::new(AttributeType::ClusterList, vec![192, 0, 2, 1]).flags, vec![AttributeFlag::Optional]);
+        assert_eq!(
+            PathAttribute::new(AttributeType::MpReachNlri, vec![0; 256]).flags,
+            vec![AttributeFlag::Optional, AttributeFlag::ExtendedLength]
+        );
+    }
+}
@@ -1,50 +1,154 @@
+use byteorder::{ByteOrder, NetworkEndian, WriteBytesExt};
+use std::convert::TryFrom;
 use std::fmt;
+use std::net::Ipv4Addr;
+use std::str::FromStr;
 
-#[derive(PartialEq)]
+#[derive(PartialEq, Eq, Hash, Clone, Copy, serde::Deserialize, serde::Serialize)]
+#[serde(try_from = "String", into = "String")]
 pub struct Prefix {
     pub length: u8,
     pub prefix: [u8; 4],
 }
 
+impl Prefix {
+    fn mask(length: u8) -> u32 {
+        if length == 0 { 0 } else { u32::MAX << (32 - length as u32) }
+    }
+
+    /// Whether `other` is equal to or more specific than this prefix
+    pub fn contains(&self, other: &Prefix) -> bool {
+        let mask = Prefix::mask(self.length);
+        other.length >= self.length
+            && u32::from_be_bytes(self.prefix) & mask == u32::from_be_bytes(other.prefix) & mask
+    }
+}
+
 impl fmt::Debug for Prefix {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.write_fmt(format_args!("{}.{}.{}.{}/{}", self.prefix[0], self.prefix[1], self.prefix[2], self.prefix[3], self.length))
     }
 }
 
-pub(crate) fn extract_prefixes(data: &[u8]) -> Vec<Prefix> {
-    let mut routes: Vec<Prefix> = Vec::new();
+impl fmt::Display for Prefix {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+#[error("Invalid prefix: {0}")]
+pub struct PrefixParseError(String);
+
+impl FromStr for Prefix {
+    type Err = PrefixParseError;
 
-    let mut bytes_left = data.len();
-    let mut i = 0; // Start after the "withdrawn length" field
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || PrefixParseError(s.to_string());
+        let (address, length) = match s.split_once('/') {
+            Some((address, length)) => (address, length.parse::<u8>().map_err(|_| error())?),
+            None => (s, 32),
+        };
+        let address: Ipv4Addr = address.parse().map_err(|_| error())?;
+        if length > 32 {
+            return Err(error());
+        }
+        let masked = u32::from(address) & Prefix::mask(length);
+        Ok(Prefix { length, prefix: masked.to_be_bytes() })
+    }
+}
+
+impl TryFrom<String> for Prefix {
+    type Error = PrefixParseError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Prefix> for String {
+    fn from(prefix: Prefix) -> String {
+        prefix.to_string()
+    }
+}
+
+/// Prefix in an UPDATE together with its path identifier. The identifier is only carried on the
+/// wire when ADD-PATH (RFC 7911) is in use and is 0 otherwise.
+#[derive(PartialEq, Eq, Hash, Clone, Copy)]
+pub struct Nlri {
+    pub path_id: u32,
+    pub prefix: Prefix,
+}
+
+impl From<Prefix> for Nlri {
+    fn from(prefix: Prefix) -> Nlri {
+        Nlri { path_id: 0, prefix }
+    }
+}
 
-    while bytes_left > 0 {
-        let prefix_length = data[i];
-        let prefix_octets = (prefix_length as f32 / 8f32).ceil() as usize;
-        let mut prefix = [0 as u8; 4];
-        prefix[0..prefix_octets].copy_from_slice(&data[i+1 .. i+1+prefix_octets]);
-        routes.push(Prefix {
-            prefix: prefix,
-            length: prefix_length,
+impl Nlri {
+    /// Length of the prefix as written in an UPDATE
+    pub fn encoded_length(&self, add_path: bool) -> usize {
+        let path_id = if add_path { 4 } else { 0 };
+        path_id + 1 + (self.prefix.length as usize).div_ceil(8)
+    }
+}
+
+impl fmt::Debug for Nlri {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.path_id == 0 {
+            fmt::Debug::fmt(&self.prefix, f)
+        } else {
+            f.write_fmt(format_args!("{:?} (path {})", self.prefix, self.path_id))
+        }
+    }
+}
+
+/// Extracts IPv4 prefixes, `None` if a prefix is longer than 32 bits or runs past the end
+pub fn extract_prefixes(data: &[u8], add_path: bool) -> Option<Vec<Nlri>> {
+    let mut routes: Vec<Nlri> = Vec::new();
+
+    let mut i = 0;
+    while i < data.len() {
+        let mut path_id = 0;
+        if add_path {
+            path_id = NetworkEndian::read_u32(data.get(i .. i+4)?);
+            i += 4;
+        }
+        let prefix_length = *data.get(i)?;
+        if prefix_length > 32 {
+            return None;
+        }
+        let prefix_octets = (prefix_length as usize).div_ceil(8);
+        let mut prefix = [0u8; 4];
+        prefix[0..prefix_octets].copy_from_slice(data.get(i+1 .. i+1+prefix_octets)?);
+        routes.push(Nlri {
+            path_id,
+            prefix: Prefix {
+                prefix,
+                length: prefix_length,
+            },
         });
         i += 1 + prefix_octets;
-        bytes_left -= 1 + prefix_octets as usize;
     }
 
-    return routes;
+    Some(routes)
 }
 
-pub(crate) fn compile_prefixes(prefixes: Vec<Prefix>) -> Vec<u8> {
+pub fn compile_prefixes(prefixes: Vec<Nlri>, add_path: bool) -> Vec<u8> {
     let mut data = Vec::new();
 
-    for prefix in prefixes {
+    for Nlri { path_id, prefix } in prefixes {
+        if add_path {
+            data.write_u32::<NetworkEndian>(path_id).unwrap();
+        }
         data.push(prefix.length);
         for octet in 0 .. (prefix.length as f32 / 8f32).ceil() as usize {
             data.push(prefix.prefix[octet]);
         }
     }
 
-    return data
+    data
 }
 
 #[cfg(test)]
@@ -54,28 +158,67 @@ mod tests {
     #[test]
     fn test_extract_prefixes() {
         assert_eq!(
-            extract_prefixes(&[32 as u8, 1, 2, 3, 4]),
-            vec![Prefix { length: 32, prefix: [1, 2, 3, 4]}]
+            extract_prefixes(&[32u8, 1, 2, 3, 4], false),
+            Some(vec![Prefix { length: 32, prefix: [1, 2, 3, 4]}.into()])
         );
         assert_eq!(
-            extract_prefixes(&[32 as u8, 1, 2, 3, 4, 12, 172, 16]),
-            vec![Prefix { length: 32, prefix: [1, 2, 3, 4]}, Prefix { length: 12, prefix: [172, 16, 0, 0]}]
+            extract_prefixes(&[32u8, 1, 2, 3, 4, 12, 172, 16], false),
+            Some(vec![Prefix { length: 32, prefix: [1, 2, 3, 4]}.into(), Prefix { length: 12, prefix: [172, 16, 0, 0]}.into()])
         );
+        assert_eq!(
+            extract_prefixes(&[/* path id */ 0, 0, 0, 1, 32u8, 1, 2, 3, 4, /* path id */ 0, 0, 0, 2, 12, 172, 16], true),
+            Some(vec![
+                Nlri { path_id: 1, prefix: Prefix { length: 32, prefix: [1, 2, 3, 4]} },
+                Nlri { path_id: 2, prefix: Prefix { length: 12, prefix: [172, 16, 0, 0]} },
+            ])
+        );
+        // Truncated, too long and ADD-PATH identifier cut short
+        assert_eq!(extract_prefixes(&[24u8, 10, 0], false), None);
+        assert_eq!(extract_prefixes(&[33u8, 1, 2, 3, 4, 5], false), None);
+        assert_eq!(extract_prefixes(&[0, 0, 1], true), None);
     }
 
     #[test]
     fn test_compile_prefixes() {
         assert_eq!(
-            compile_prefixes(vec![Prefix { length: 32, prefix: [1, 2, 3, 4]}]),
-            vec![32 as u8, 1, 2, 3, 4]
+            compile_prefixes(vec![Prefix { length: 32, prefix: [1, 2, 3, 4]}.into()], false),
+            vec![32u8, 1, 2, 3, 4]
+        );
+
+        assert_eq!(
+            compile_prefixes(vec![
+                Prefix { length: 32, prefix: [1, 2, 3, 4]}.into(),
+                Prefix { length: 12, prefix: [172, 16, 0, 0]}.into(),
+            ], false),
+            vec![32u8, 1, 2, 3, 4, 12, 172, 16]
         );
 
         assert_eq!(
             compile_prefixes(vec![
-                Prefix { length: 32, prefix: [1, 2, 3, 4]},
-                Prefix { length: 12, prefix: [172, 16, 0, 0]},
-            ]),
-            vec![32 as u8, 1, 2, 3, 4, 12, 172, 16]
+                Nlri { path_id: 1, prefix: Prefix { length: 32, prefix: [1, 2, 3, 4]} },
+                Nlri { path_id: 2, prefix: Prefix { length: 12, prefix: [172, 16, 0, 0]} },
+            ], true),
+            vec![/* path id */ 0, 0, 0, 1, 32u8, 1, 2, 3, 4, /* path id */ 0, 0, 0, 2, 12, 172, 16]
         );
     }
+
+    #[test]
+    fn test_parse_prefix() {
+        assert_eq!("10.1.2.3/32".parse(), Ok(Prefix { length: 32, prefix: [10, 1, 2, 3]}));
+        assert_eq!("10.1.2.3".parse(), Ok(Prefix { length: 32, prefix: [10, 1, 2, 3]}));
+        assert_eq!("172.16.99.1/12".parse(), Ok(Prefix { length: 12, prefix: [172, 16, 0, 0]}));
+        assert_eq!("0.0.0.0/0".parse(), Ok(Prefix { length: 0, prefix: [0, 0, 0, 0]}));
+        assert!("10.0.0.0/33".parse::<Prefix>().is_err());
+        assert!("10.0.0/8".parse::<Prefix>().is_err());
+    }
+
+    #[test]
+    fn test_prefix_contains() {
+        let net: Prefix = "10.0.0.0/8".parse().unwrap();
+        assert!(net.contains(&"10.0.0.0/8".parse().unwrap()));
+        assert!(net.contains(&"10.20.30.40/32".parse().unwrap()));
+        assert!(!net.contains(&"0.0.0.0/0".parse().unwrap()));
+        assert!(!net.contains(&"11.0.0.0/8".parse().unwrap()));
+        assert!("0.0.0.0/0".parse::<Prefix>().unwrap().contains(&net));
+    }
 }
\ No newline at end of file
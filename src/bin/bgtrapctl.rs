@@ -0,0 +1,248 @@
+//! Command-line client for the BGtraP control API
+
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bgtrap::control::{Request, Response, SoftResetDirection};
+
+const DEFAULT_SOCKET: &str = "/run/bgtrap/control.sock";
+
+const USAGE: &str = "\
+Usage: bgtrapctl [--socket <path>] [--json] <command>
+
+Commands:
+    show neighbors
+    show route [prefix]
+    show trap
+    trap add <prefix> [--ttl <duration>] [--reason <text>]
+    trap del <prefix>
+    neighbor reset <address> [--message <text>]
+    neighbor disable <address> [--message <text>]
+    neighbor enable <address>
+    neighbor soft-reset <address> in|out
+    reload
+
+Durations are seconds or a number followed by s, m, h or d, e.g. 90s or 1h.";
+
+#[derive(Debug, PartialEq)]
+struct Options {
+    socket: String,
+    json: bool,
+}
+
+/// Parses `90`, `90s`, `30m`, `1h` or `2d` into seconds
+fn parse_duration(s: &str) -> Result<u64, String> {
+    let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => s.split_at(index),
+        None => (s, "s"),
+    };
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(format!("invalid duration {}", s)),
+    };
+    number.parse::<u64>().ok()
+        .and_then(|number| number.checked_mul(multiplier))
+        .ok_or_else(|| format!("invalid duration {}", s))
+}
+
+fn parse<T: std::str::FromStr>(s: &str, what: &str) -> Result<T, String> {
+    s.parse().map_err(|_| format!("invalid {} {}", what, s))
+}
+
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<(Options, Request), String> {
+    let mut options = Options { socket: DEFAULT_SOCKET.to_string(), json: false };
+    let (mut ttl, mut reason, mut message) = (None, None, String::new());
+    let mut words = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{} requires a value", arg));
+        match arg.as_str() {
+            "--socket" => options.socket = value()?,
+            "--json" => options.json = true,
+            "--ttl" => ttl = Some(parse_duration(&value()?)?),
+            "--reason" => reason = Some(value()?),
+            "--message" => message = value()?,
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+            _ => words.push(arg),
+        }
+    }
+    let words: Vec<&str> = words.iter().map(String::as_str).collect();
+    let request = match words.as_slice() {
+        ["show", "neighbors"] => Request::NeighborList,
+        ["show", "route"] => Request::RouteList { prefix: None },
+        ["show", "route", prefix] => Request::RouteList { prefix: Some(parse(prefix, "prefix")?) },
+        ["show", "trap"] => Request::TrapList,
+        ["trap", "add", prefix] => Request::TrapAdd { prefix: parse(prefix, "prefix")?, reason, ttl },
+        ["trap", "del", prefix] => Request::TrapDel { prefix: parse(prefix, "prefix")? },
+        ["neighbor", "reset", address] => Request::NeighborReset { address: parse(address, "address")?, message },
+        ["neighbor", "disable", address] => Request::NeighborDisable { address: parse(address, "address")?, message },
+        ["neighbor", "enable", address] => Request::NeighborEnable { address: parse(address, "address")? },
+        ["neighbor", "soft-reset", address, direction] => {
+            let direction = match *direction {
+                "in" => SoftResetDirection::In,
+                "out" => SoftResetDirection::Out,
+                _ => return Err(format!("invalid direction {}, expected in or out", direction)),
+            };
+            Request::NeighborSoftReset { address: parse(address, "address")?, direction }
+        },
+        ["reload"] => Request::Reload,
+        _ => return Err(USAGE.to_string()),
+    };
+    Ok((options, request))
+}
+
+fn send(socket: &str, request: &Request) -> Result<Response, String> {
+    let mut stream = UnixStream::connect(socket).map_err(|e| format!("failed to connect to {}: {}", socket, e))?;
+    let mut line = serde_json::to_string(request).map_err(|e| e.to_string())?;
+    line.push('\n');
+    stream.write_all(line.as_bytes()).map_err(|e| e.to_string())?;
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line).map_err(|e| e.to_string())?;
+    serde_json::from_str(&line).map_err(|e| format!("invalid response: {}", e))
+}
+
+/// Formats seconds as the two largest units, e.g. `1d02h` or `3m20s`
+fn format_duration(seconds: u64) -> String {
+    let (days, hours, minutes) = (seconds / 86400, seconds / 3600 % 24, seconds / 60 % 60);
+    if days > 0 {
+        format!("{}d{:02}h", days, hours)
+    } else if hours > 0 {
+        format!("{}h{:02}m", hours, minutes)
+    } else if minutes > 0 {
+        format!("{}m{:02}s", minutes, seconds % 60)
+    } else {
+        format!("{}s", seconds)
+    }
+}
+
+/// Prints rows in columns aligned to the widest cell
+fn print_table(headers: &[&str], rows: Vec<Vec<String>>) {
+    let mut widths: Vec<usize> = headers.iter().map(|header| header.len()).collect();
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let print_row = |cells: Vec<&str>| {
+        let line: Vec<String> = cells.iter().zip(&widths).map(|(cell, width)| format!("{:width$}", cell, width = width)).collect();
+        println!("{}", line.join("  ").trim_end());
+    };
+    print_row(headers.to_vec());
+    for row in &rows {
+        print_row(row.iter().map(String::as_str).collect());
+    }
+}
+
+fn print_response(request: &Request, response: Response) {
+    match request {
+        Request::NeighborList => {
+            let rows = response.neighbors.unwrap_or_default().into_iter().map(|neighbor| vec![
+                neighbor.address.to_string(),
+                if neighbor.remote_as == 0 { "-".to_string() } else { neighbor.remote_as.to_string() },
+                neighbor.state,
+                if neighbor.uptime == 0 { "-".to_string() } else { format_duration(neighbor.uptime) },
+                neighbor.prefixes_received.to_string(),
+                neighbor.prefixes_accepted.to_string(),
+                neighbor.prefixes_advertised.to_string(),
+            ]).collect();
+            print_table(&["NEIGHBOR", "AS", "STATE", "UPTIME", "RECEIVED", "ACCEPTED", "ADVERTISED"], rows);
+        },
+        Request::RouteList { .. } => {
+            let rows = response.routes.unwrap_or_default().into_iter().map(|route| vec![
+                route.prefix.to_string(),
+                route.neighbor.to_string(),
+                route.path_id.to_string(),
+                route.next_hop.map_or("-".to_string(), |next_hop| next_hop.to_string()),
+                route.as_path,
+                route.communities.join(" "),
+            ]).collect();
+            print_table(&["PREFIX", "NEIGHBOR", "PATH ID", "NEXT HOP", "AS PATH", "COMMUNITIES"], rows);
+        },
+        Request::TrapList => {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+            let details = response.details.unwrap_or_default();
+            let rows = response.traps.unwrap_or_default().into_iter().map(|prefix| {
+                let details = details.iter().find(|details| details.prefix == prefix);
+                let expires = details.and_then(|details| details.expires)
+                    .map_or("-".to_string(), |expires| format!("in {}", format_duration(expires.saturating_sub(now))));
+                let reason = details.and_then(|details| details.reason.clone()).unwrap_or_default();
+                vec![prefix.to_string(), expires, reason]
+            }).collect();
+            print_table(&["PREFIX", "EXPIRES", "REASON"], rows);
+        },
+        _ => {},
+    }
+}
+
+fn main() {
+    let (options, request) = match parse_args(std::env::args().skip(1)) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        },
+    };
+    let response = match send(&options.socket, &request) {
+        Ok(response) => response,
+        Err(e) => {
+            eprintln!("bgtrapctl: {}", e);
+            std::process::exit(1);
+        },
+    };
+    let ok = response.ok;
+    if options.json {
+        println!("{}", serde_json::to_string_pretty(&response).unwrap());
+    } else if let Some(error) = &response.error {
+        eprintln!("bgtrapctl: {}", error);
+    } else {
+        print_response(&request, response);
+    }
+    if !ok {
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Result<(Options, Request), String> {
+        parse_args(line.split_whitespace().map(String::from))
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90"), Ok(90));
+        assert_eq!(parse_duration("30m"), Ok(1800));
+        assert_eq!(parse_duration("1h"), Ok(3600));
+        assert_eq!(parse_duration("2d"), Ok(172_800));
+        assert!(parse_duration("1w").is_err());
+        assert!(parse_duration("h").is_err());
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let (options, request) = args("--json trap add 192.0.2.1/32 --ttl 1h --reason DDoS").unwrap();
+        assert_eq!(options, Options { socket: DEFAULT_SOCKET.to_string(), json: true });
+        match request {
+            Request::TrapAdd { prefix, reason, ttl } => {
+                assert_eq!(prefix, "192.0.2.1/32".parse().unwrap());
+                assert_eq!((reason.as_deref(), ttl), (Some("DDoS"), Some(3600)));
+            },
+            request => panic!("expected trap-add, parsed {:?}", request),
+        }
+        let (options, request) = args("--socket /tmp/bgtrap.sock show route 10.0.0.0/8").unwrap();
+        assert_eq!(options.socket, "/tmp/bgtrap.sock");
+        assert!(matches!(request, Request::RouteList { prefix: Some(_) }));
+        assert!(matches!(args("neighbor soft-reset 192.0.2.10 in").unwrap().1, Request::NeighborSoftReset { .. }));
+
+        assert_eq!(args("trap add 192.0.2.1/33").unwrap_err(), "invalid prefix 192.0.2.1/33");
+        assert_eq!(args("show trap --ttl").unwrap_err(), "--ttl requires a value");
+        assert_eq!(args("show").unwrap_err(), USAGE);
+    }
+}
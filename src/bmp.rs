@@ -0,0 +1,381 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::sync::{broadcast, mpsc};
+use tracing::{info, warn};
+
+use crate::config::BmpConfig;
+use crate::session::SessionCommand;
+
+const BMP_VERSION: u8 = 3;
+const BMP_HEADER_SIZE: usize = 6;
+
+const TYPE_ROUTE_MONITORING: u8 = 0;
+const TYPE_STATISTICS_REPORT: u8 = 1;
+const TYPE_PEER_DOWN: u8 = 2;
+const TYPE_PEER_UP: u8 = 3;
+const TYPE_INITIATION: u8 = 4;
+
+const PEER_FLAG_IPV6: u8 = 0x80;
+const PEER_FLAG_POST_POLICY: u8 = 0x40;
+/// AS_PATHs use 2-byte AS numbers
+const PEER_FLAG_LEGACY_AS_PATH: u8 = 0x20;
+/// Adj-RIB-Out instead of Adj-RIB-In (RFC 8671)
+const PEER_FLAG_ADJ_RIB_OUT: u8 = 0x10;
+
+const INFORMATION_SYS_DESCR: u16 = 1;
+const INFORMATION_SYS_NAME: u16 = 2;
+
+const STAT_REJECTED_PREFIXES: u16 = 0;
+const STAT_ADJ_RIB_IN_ROUTES: u16 = 7;
+
+/// Seconds between attempts to connect to the monitoring station
+const RECONNECT_INTERVAL: u64 = 10;
+/// Seconds the monitoring station gets to accept a message
+const BMP_WRITE_TIMEOUT: u64 = 10;
+/// Messages waiting for the monitoring station, further ones are dropped
+const BMP_QUEUE_SIZE: usize = 10_000;
+
+/// Neighbor a BMP message is about
+#[derive(Debug, Clone, Copy)]
+pub struct PeerHeader {
+    pub address: IpAddr,
+    pub asn: u16,
+    pub bgp_id: u32,
+    /// The message reports our Adj-RIB-Out towards the neighbor, after the export policy
+    pub adj_rib_out: bool,
+    pub timestamp: SystemTime,
+}
+
+impl PeerHeader {
+    fn compile(&self, buf: &mut Vec<u8>) {
+        let mut flags = PEER_FLAG_LEGACY_AS_PATH;
+        if self.address.is_ipv6() {
+            flags |= PEER_FLAG_IPV6;
+        }
+        if self.adj_rib_out {
+            flags |= PEER_FLAG_ADJ_RIB_OUT | PEER_FLAG_POST_POLICY;
+        }
+        buf.push(0); // Global instance peer
+        buf.push(flags);
+        buf.extend_from_slice(&[0; 8]); // Peer distinguisher
+        buf.extend_from_slice(&address_octets(self.address));
+        buf.extend_from_slice(&(self.asn as u32).to_be_bytes());
+        buf.extend_from_slice(&self.bgp_id.to_be_bytes());
+        let timestamp = self.timestamp.duration_since(UNIX_EPOCH).unwrap_or_default();
+        buf.extend_from_slice(&(timestamp.as_secs() as u32).to_be_bytes());
+        buf.extend_from_slice(&timestamp.subsec_micros().to_be_bytes());
+    }
+}
+
+/// Addresses are always 16 bytes, IPv4 addresses in the last 4
+fn address_octets(address: IpAddr) -> [u8; 16] {
+    match address {
+        IpAddr::V4(address) => {
+            let mut octets = [0; 16];
+            octets[12..].copy_from_slice(&address.octets());
+            octets
+        },
+        IpAddr::V6(address) => address.octets(),
+    }
+}
+
+fn make_bmp_message(msg_type: u8, body: Vec<u8>) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(BMP_HEADER_SIZE + body.len());
+    buf.push(BMP_VERSION);
+    buf.extend_from_slice(&((BMP_HEADER_SIZE + body.len()) as u32).to_be_bytes());
+    buf.push(msg_type);
+    buf.extend(body);
+    buf
+}
+
+fn compile_tlv(buf: &mut Vec<u8>, tlv_type: u16, value: &[u8]) {
+    buf.extend_from_slice(&tlv_type.to_be_bytes());
+    buf.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    buf.extend_from_slice(value);
+}
+
+pub fn initiation(sys_name: &str) -> Vec<u8> {
+    let mut body = Vec::new();
+    compile_tlv(&mut body, INFORMATION_SYS_DESCR, format!("BGtraP {}", env!("CARGO_PKG_VERSION")).as_bytes());
+    compile_tlv(&mut body, INFORMATION_SYS_NAME, sys_name.as_bytes());
+    make_bmp_message(TYPE_INITIATION, body)
+}
+
+/// Peer Up with the full OPEN messages exchanged with the neighbor
+pub fn peer_up(peer: &PeerHeader, local: SocketAddr, remote_port: u16, sent_open: &[u8], received_open: &[u8]) -> Vec<u8> {
+    let mut body = Vec::new();
+    peer.compile(&mut body);
+    body.extend_from_slice(&address_octets(local.ip()));
+    body.extend_from_slice(&local.port().to_be_bytes());
+    body.extend_from_slice(&remote_port.to_be_bytes());
+    body.extend_from_slice(sent_open);
+    body.extend_from_slice(received_open);
+    make_bmp_message(TYPE_PEER_UP, body)
+}
+
+/// Why a session went down, NOTIFICATIONs are the full messages
+#[derive(Debug)]
+pub enum PeerDownReason {
+    LocalNotification(Vec<u8>),
+    RemoteNotification(Vec<u8>),
+    RemoteNoData,
+}
+
+pub fn peer_down(peer: &PeerHeader, reason: PeerDownReason) -> Vec<u8> {
+    let mut body = Vec::new();
+    peer.compile(&mut body);
+    match reason {
+        PeerDownReason::LocalNotification(notification) => {
+            body.push(1);
+            body.extend(notification);
+        },
+        PeerDownReason::RemoteNotification(notification) => {
+            body.push(3);
+            body.extend(notification);
+        },
+        PeerDownReason::RemoteNoData => body.push(4),
+    }
+    make_bmp_message(TYPE_PEER_DOWN, body)
+}
+
+/// Route Monitoring wrapping a full UPDATE message as it was sent or received
+pub fn route_monitoring(peer: &PeerHeader, update: &[u8]) -> Vec<u8> {
+    let mut body = Vec::new();
+    peer.compile(&mut body);
+    body.extend_from_slice(update);
+    make_bmp_message(TYPE_ROUTE_MONITORING, body)
+}
+
+pub fn statistics_report(peer: &PeerHeader, rejected_prefixes: u32, adj_rib_in_routes: u64) -> Vec<u8> {
+    let mut body = Vec::new();
+    peer.compile(&mut body);
+    body.extend_from_slice(&2u32.to_be_bytes()); // Number of statistics
+    compile_tlv(&mut body, STAT_REJECTED_PREFIXES, &rejected_prefixes.to_be_bytes());
+    compile_tlv(&mut body, STAT_ADJ_RIB_IN_ROUTES, &adj_rib_in_routes.to_be_bytes());
+    make_bmp_message(TYPE_STATISTICS_REPORT, body)
+}
+
+/// Encoded BMP message from a session. Peer Up messages are kept and repeated whenever the
+/// station reconnects.
+#[derive(Debug)]
+pub enum BmpEvent {
+    PeerUp(IpAddr, Vec<u8>),
+    PeerDown(IpAddr, Vec<u8>),
+    Message(Vec<u8>),
+}
+
+/// Peer Up messages of the established sessions. Kept by the senders so that they stay accurate
+/// when messages are dropped.
+type PeersUp = Arc<Mutex<HashMap<IpAddr, Vec<u8>>>>;
+
+/// Handle for sessions to pass messages to the exporter, does nothing if BMP is not configured.
+/// The queue is bounded so that a slow station cannot make us buffer without limit, messages
+/// that do not fit are dropped and the exporter reconnects to the station to start over.
+#[derive(Default)]
+pub struct BmpSender(Option<(mpsc::Sender<Vec<u8>>, PeersUp, Arc<AtomicBool>)>);
+
+/// The exporter's end of the queue
+pub struct BmpEvents {
+    messages: mpsc::Receiver<Vec<u8>>,
+    peers_up: PeersUp,
+    overflowed: Arc<AtomicBool>,
+}
+
+/// Queue from the sessions to the exporter
+pub fn queue() -> (BmpSender, BmpEvents) {
+    queue_with_capacity(BMP_QUEUE_SIZE)
+}
+
+fn queue_with_capacity(capacity: usize) -> (BmpSender, BmpEvents) {
+    let (sender, messages) = mpsc::channel(capacity);
+    let peers_up = PeersUp::default();
+    let overflowed = Arc::new(AtomicBool::new(false));
+    let events = BmpEvents { messages, peers_up: peers_up.clone(), overflowed: overflowed.clone() };
+    (BmpSender(Some((sender, peers_up, overflowed))), events)
+}
+
+impl BmpSender {
+    pub fn enabled(&self) -> bool {
+        self.0.is_some()
+    }
+
+    pub fn send(&self, event: BmpEvent) {
+        let (sender, peers_up, overflowed) = match &self.0 {
+            Some(queue) => queue,
+            None => return,
+        };
+        let message = match event {
+            BmpEvent::PeerUp(address, message) => {
+                peers_up.lock().unwrap().insert(address, message.clone());
+                message
+            },
+            BmpEvent::PeerDown(address, message) => {
+                peers_up.lock().unwrap().remove(&address);
+                message
+            },
+            BmpEvent::Message(message) => message,
+        };
+        if let Err(mpsc::error::TrySendError::Full(_)) = sender.try_send(message) {
+            overflowed.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+async fn connect(config: &BmpConfig, sys_name: &str, peers_up: Vec<Vec<u8>>) -> std::io::Result<TcpStream> {
+    let mut stream = TcpStream::connect(config.station).await?;
+    stream.write_all(&initiation(sys_name)).await?;
+    for message in peers_up {
+        stream.write_all(&message).await?;
+    }
+    Ok(stream)
+}
+
+/// Sends the sessions' messages to the monitoring station, reconnecting whenever the connection
+/// is lost. Messages are dropped while there is no connection. A station that takes longer than
+/// the write timeout or lets the queue overflow is disconnected. Statistics are requested from all
+/// sessions every statistics interval.
+pub async fn run(
+    config: BmpConfig,
+    sys_name: String,
+    mut events: BmpEvents,
+    commands: broadcast::Sender<SessionCommand>,
+) {
+    let mut stream: Option<TcpStream> = None;
+    let mut reconnect = tokio::time::interval(Duration::from_secs(RECONNECT_INTERVAL));
+    let mut statistics = tokio::time::interval(Duration::from_secs(config.statistics_interval));
+    let write_timeout = Duration::from_secs(BMP_WRITE_TIMEOUT);
+    loop {
+        tokio::select! {
+            message = events.messages.recv() => {
+                let message = match message {
+                    Some(message) => message,
+                    None => return,
+                };
+                if events.overflowed.swap(false, Ordering::Relaxed) && stream.is_some() {
+                    warn!(station = %config.station, "BMP messages were dropped, reconnecting to the station");
+                    stream = None;
+                }
+                if let Some(connection) = &mut stream {
+                    match tokio::time::timeout(write_timeout, connection.write_all(&message)).await {
+                        Ok(Ok(())) => {},
+                        Ok(Err(e)) => {
+                            warn!(station = %config.station, error = %e, "Lost connection to BMP station");
+                            stream = None;
+                        },
+                        Err(_) => {
+                            warn!(station = %config.station, "BMP station is not keeping up, disconnecting");
+                            stream = None;
+                        },
+                    }
+                }
+            },
+            _ = reconnect.tick(), if stream.is_none() => {
+                let peers_up = events.peers_up.lock().unwrap().values().cloned().collect();
+                match tokio::time::timeout(write_timeout, connect(&config, &sys_name, peers_up)).await {
+                    Ok(Ok(connection)) => {
+                        info!(station = %config.station, "Connected to BMP station");
+                        events.overflowed.store(false, Ordering::Relaxed);
+                        stream = Some(connection);
+                    },
+                    Ok(Err(e)) => warn!(station = %config.station, error = %e, "Failed to connect to BMP station"),
+                    Err(_) => warn!(station = %config.station, "Timed out connecting to BMP station"),
+                }
+            },
+            _ = statistics.tick(), if stream.is_some() => {
+                let _ = commands.send(SessionCommand::ReportStatistics);
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(adj_rib_out: bool) -> PeerHeader {
+        PeerHeader {
+            address: "192.0.2.10".parse().unwrap(),
+            asn: 65001,
+            bgp_id: 0xC000_020A,
+            adj_rib_out,
+            timestamp: UNIX_EPOCH + Duration::new(1_000_000, 5_000),
+        }
+    }
+
+    #[test]
+    fn test_peer_header() {
+        let mut buf = Vec::new();
+        peer(false).compile(&mut buf);
+        assert_eq!(buf, vec![
+            0, PEER_FLAG_LEGACY_AS_PATH, 0, 0, 0, 0, 0, 0, 0, 0,
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 192, 0, 2, 10,
+            0, 0, 0xFD, 0xE9,
+            192, 0, 2, 10,
+            0, 0x0F, 0x42, 0x40,
+            0, 0, 0, 5,
+        ]);
+        buf.clear();
+        peer(true).compile(&mut buf);
+        assert_eq!(buf[1], PEER_FLAG_LEGACY_AS_PATH | PEER_FLAG_ADJ_RIB_OUT | PEER_FLAG_POST_POLICY);
+    }
+
+    #[test]
+    fn test_messages() {
+        let initiation = initiation("bgtrap");
+        assert_eq!(&initiation[..6], &[BMP_VERSION, 0, 0, 0, initiation.len() as u8, TYPE_INITIATION]);
+        assert_eq!(&initiation[initiation.len() - 10..], &[0, 2, 0, 6, b'b', b'g', b't', b'r', b'a', b'p']);
+
+        let update = [0xFF; 23];
+        let monitoring = route_monitoring(&peer(false), &update);
+        assert_eq!(monitoring.len(), BMP_HEADER_SIZE + 42 + 23);
+        assert_eq!(monitoring[5], TYPE_ROUTE_MONITORING);
+        assert_eq!(&monitoring[BMP_HEADER_SIZE + 42..], &update[..]);
+
+        let down = peer_down(&peer(false), PeerDownReason::RemoteNoData);
+        assert_eq!(down.len(), BMP_HEADER_SIZE + 42 + 1);
+        assert_eq!(down[down.len() - 1], 4);
+
+        let statistics = statistics_report(&peer(false), 3, 10);
+        assert_eq!(&statistics[BMP_HEADER_SIZE + 42..], &[
+            0, 0, 0, 2,
+            0, 0, 0, 4, 0, 0, 0, 3,
+            0, 7, 0, 8, 0, 0, 0, 0, 0, 0, 0, 10,
+        ]);
+    }
+
+    #[test]
+    fn test_peer_up() {
+        let local: SocketAddr = "192.0.2.1:179".parse().unwrap();
+        let up = peer_up(&peer(false), local, 50000, &[1, 2], &[3]);
+        assert_eq!(up[5], TYPE_PEER_UP);
+        assert_eq!(&up[BMP_HEADER_SIZE + 42..], &[
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 192, 0, 2, 1,
+            0, 179, 0xC3, 0x50,
+            1, 2, 3,
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_queue_overflow() {
+        let (sender, mut events) = queue_with_capacity(1);
+        let address: IpAddr = "192.0.2.10".parse().unwrap();
+        sender.send(BmpEvent::PeerUp(address, vec![1]));
+        sender.send(BmpEvent::Message(vec![2]));
+        assert!(events.overflowed.load(Ordering::Relaxed));
+        assert_eq!(events.peers_up.lock().unwrap().len(), 1);
+
+        // Peer Up messages stay accurate for the next connection even if the Peer Down was dropped
+        sender.send(BmpEvent::PeerDown(address, vec![3]));
+        assert!(events.peers_up.lock().unwrap().is_empty());
+
+        drop(sender);
+        assert_eq!(events.messages.recv().await, Some(vec![1]));
+        assert_eq!(events.messages.recv().await, None);
+    }
+}
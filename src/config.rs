@@ -0,0 +1,496 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::bgp::utils::prefix::Prefix;
+use crate::policy::Policy;
+use crate::socket::TCP_MD5SIG_MAXKEYLEN;
+
+#[derive(thiserror::Error, Debug)]
+pub enum ConfigError {
+    #[error("Failed to read configuration: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Failed to parse configuration: {0}")]
+    Parse(#[from] toml::de::Error),
+    #[error("Neighbor {neighbor} refers to unknown policy '{policy}'")]
+    UnknownPolicy { neighbor: IpAddr, policy: String },
+    #[error("Invalid configuration: {0}")]
+    Invalid(String),
+}
+
+fn default_listen() -> SocketAddr {
+    SocketAddr::from(([0, 0, 0, 0], 179))
+}
+
+fn default_hold_time() -> u16 {
+    30
+}
+
+fn default_warning_threshold() -> u8 {
+    75
+}
+
+fn default_restart_time() -> u16 {
+    120
+}
+
+fn default_trap_next_hop() -> Ipv4Addr {
+    Ipv4Addr::new(192, 0, 2, 1)
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct MaxPrefixConfig {
+    /// Accepted prefixes above which the session is torn down
+    pub limit: u32,
+    /// Percentage of the limit at which a warning is logged
+    #[serde(default = "default_warning_threshold")]
+    pub warning_threshold: u8,
+    /// Seconds after which the neighbor may reconnect. Without it the neighbor stays down.
+    pub restart_time: Option<u64>,
+}
+
+impl MaxPrefixConfig {
+    pub fn warning_limit(&self) -> u32 {
+        (self.limit as u64 * self.warning_threshold as u64 / 100) as u32
+    }
+
+    pub fn restart_time(&self) -> Option<Duration> {
+        self.restart_time.map(Duration::from_secs)
+    }
+}
+
+/// Generalized TTL Security Mechanism (RFC 5082)
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct TtlSecurityConfig {
+    /// Routers allowed between us and the neighbor, 0 if it is directly connected
+    #[serde(default)]
+    pub hops: u8,
+}
+
+impl TtlSecurityConfig {
+    /// Lowest TTL accepted from the neighbor, which sends with TTL 255
+    pub fn min_ttl(&self) -> u8 {
+        255 - self.hops
+    }
+}
+
+/// Directions in which multiple paths per prefix are exchanged with a neighbor (RFC 7911)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AddPathMode {
+    Receive,
+    Send,
+    Both,
+}
+
+impl AddPathMode {
+    pub fn receive(self) -> bool {
+        self != AddPathMode::Send
+    }
+
+    pub fn send(self) -> bool {
+        self != AddPathMode::Receive
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct NeighborConfig {
+    pub address: IpAddr,
+    /// Any AS is accepted from the neighbor if not set
+    pub remote_as: Option<u16>,
+    pub import_policy: Option<String>,
+    /// Everything is advertised to the neighbor if not set
+    pub export_policy: Option<String>,
+    pub max_prefix: Option<MaxPrefixConfig>,
+    /// ADD-PATH for IPv4 unicast
+    pub add_path: Option<AddPathMode>,
+    /// Advertise FlowSpec rules to the neighbor
+    #[serde(default)]
+    pub flowspec: bool,
+    /// TCP MD5 signature key (RFC 2385)
+    pub password: Option<String>,
+    /// Send with TTL 255 and drop segments from further than the allowed hops
+    pub ttl_security: Option<TtlSecurityConfig>,
+    /// TTL of sent segments when TTL security is not used, the system default if not set
+    pub ebgp_multihop: Option<u8>,
+    /// Redistribute routes between this neighbor and the other route server clients (RFC 7947)
+    #[serde(default)]
+    pub route_server_client: bool,
+    /// Reflect iBGP routes from other neighbors to this one (RFC 4456), the neighbor has to be in
+    /// our AS
+    #[serde(default)]
+    pub route_reflector_client: bool,
+    /// The neighbor is in another member AS of our confederation (RFC 5065)
+    #[serde(default)]
+    pub confederation_member: bool,
+    /// Advertise every route with our address as the next hop
+    #[serde(default)]
+    pub next_hop_self: bool,
+}
+
+impl NeighborConfig {
+    /// Settings used for peers connecting from an address without a `[[neighbor]]` section
+    pub fn new(address: IpAddr) -> NeighborConfig {
+        NeighborConfig {
+            address,
+            remote_as: None,
+            import_policy: None,
+            export_policy: None,
+            max_prefix: None,
+            add_path: None,
+            flowspec: false,
+            password: None,
+            ttl_security: None,
+            ebgp_multihop: None,
+            route_server_client: false,
+            route_reflector_client: false,
+            confederation_member: false,
+            next_hop_self: false,
+        }
+    }
+}
+
+/// Locally originated blackhole routes
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct TrapConfig {
+    #[serde(default = "default_trap_next_hop")]
+    pub next_hop: Ipv4Addr,
+    #[serde(default)]
+    pub prefixes: Vec<Prefix>,
+    /// Where the trap table is persisted so it survives restarts
+    pub state_file: Option<PathBuf>,
+}
+
+impl Default for TrapConfig {
+    fn default() -> Self {
+        TrapConfig {
+            next_hop: default_trap_next_hop(),
+            prefixes: vec![],
+            state_file: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct GracefulRestartConfig {
+    /// Seconds neighbors should keep our routes while we restart, at most 4095
+    #[serde(default = "default_restart_time")]
+    pub restart_time: u16,
+}
+
+fn default_statistics_interval() -> u64 {
+    60
+}
+
+/// BGP Monitoring Protocol station all sessions are reported to
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct BmpConfig {
+    pub station: SocketAddr,
+    /// Seconds between Statistics Reports
+    #[serde(default = "default_statistics_interval")]
+    pub statistics_interval: u64,
+}
+
+/// MRT recording of all BGP messages and periodic table dumps (RFC 6396)
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct MrtConfig {
+    /// Where the updates and RIB files are written
+    pub directory: PathBuf,
+    /// Bytes after which a new updates file is started
+    pub rotate_size: Option<u64>,
+    /// Seconds after which a new updates file is started
+    pub rotate_interval: Option<u64>,
+    /// Seconds between table dumps, no dumps are written if not set
+    pub table_dump_interval: Option<u64>,
+}
+
+/// HTTP endpoint serving Prometheus metrics at /metrics
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct MetricsConfig {
+    pub listen: SocketAddr,
+}
+
+fn default_log_level() -> String {
+    "info".to_string()
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    /// One JSON object per line, for log collectors
+    Json,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct LogConfig {
+    /// Level or filter directives like "info,bgtrap::session=debug", overridden by RUST_LOG.
+    /// BGP messages are logged at debug level.
+    #[serde(default = "default_log_level")]
+    pub level: String,
+    #[serde(default)]
+    pub format: LogFormat,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig { level: default_log_level(), format: LogFormat::default() }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Config {
+    #[serde(default = "default_listen")]
+    pub listen: SocketAddr,
+    pub local_as: u16,
+    pub router_id: Ipv4Addr,
+    /// Cluster ID when reflecting routes, the router ID if not set
+    pub cluster_id: Option<Ipv4Addr>,
+    /// AS of the confederation `local-as` is a member of, as seen by neighbors outside of it
+    pub confederation_id: Option<u16>,
+    #[serde(default = "default_hold_time")]
+    pub hold_time: u16,
+    #[serde(default, rename = "neighbor")]
+    pub neighbors: Vec<NeighborConfig>,
+    #[serde(default, rename = "policy")]
+    pub policies: HashMap<String, Policy>,
+    #[serde(default)]
+    pub trap: TrapConfig,
+    pub graceful_restart: Option<GracefulRestartConfig>,
+    /// Unix socket of the control API, disabled if not set
+    pub control_socket: Option<PathBuf>,
+    pub bmp: Option<BmpConfig>,
+    pub mrt: Option<MrtConfig>,
+    pub metrics: Option<MetricsConfig>,
+    #[serde(default)]
+    pub log: LogConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen: default_listen(),
+            local_as: 65002,
+            router_id: Ipv4Addr::from(1234567890),
+            cluster_id: None,
+            confederation_id: None,
+            hold_time: default_hold_time(),
+            neighbors: vec![],
+            policies: HashMap::new(),
+            trap: TrapConfig {
+                next_hop: Ipv4Addr::new(192, 168, 10, 5),
+                prefixes: vec![Prefix { length: 32, prefix: [10, 10, 100, 200] }],
+                state_file: None,
+            },
+            graceful_restart: None,
+            control_socket: None,
+            bmp: None,
+            mrt: None,
+            metrics: None,
+            log: LogConfig::default(),
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, ConfigError> {
+        let config: Config = toml::from_str(&std::fs::read_to_string(path)?)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if let Some(graceful_restart) = &self.graceful_restart {
+            if graceful_restart.restart_time > 4095 {
+                return Err(ConfigError::Invalid("graceful-restart restart-time must be at most 4095".to_string()));
+            }
+        }
+        if let Some(bmp) = &self.bmp {
+            if bmp.statistics_interval == 0 {
+                return Err(ConfigError::Invalid("bmp statistics-interval must not be 0".to_string()));
+            }
+        }
+        if let Err(e) = tracing_subscriber::EnvFilter::try_new(&self.log.level) {
+            return Err(ConfigError::Invalid(format!("log level '{}': {}", self.log.level, e)));
+        }
+        if let Some(mrt) = &self.mrt {
+            if mrt.rotate_interval == Some(0) || mrt.table_dump_interval == Some(0) {
+                return Err(ConfigError::Invalid("mrt intervals must not be 0".to_string()));
+            }
+        }
+        for neighbor in &self.neighbors {
+            if neighbor.password.as_ref().is_some_and(|password| password.len() > TCP_MD5SIG_MAXKEYLEN) {
+                return Err(ConfigError::Invalid(format!(
+                    "password of neighbor {} is longer than {} bytes", neighbor.address, TCP_MD5SIG_MAXKEYLEN,
+                )));
+            }
+            if let Some(ttl_security) = &neighbor.ttl_security {
+                if ttl_security.hops == 255 {
+                    return Err(ConfigError::Invalid(format!("ttl-security hops of neighbor {} must be at most 254", neighbor.address)));
+                }
+                if neighbor.ebgp_multihop.is_some() {
+                    return Err(ConfigError::Invalid(format!(
+                        "neighbor {} can not use both ttl-security and ebgp-multihop", neighbor.address,
+                    )));
+                }
+            }
+            if neighbor.route_reflector_client && neighbor.remote_as.is_some_and(|remote_as| remote_as != self.local_as) {
+                return Err(ConfigError::Invalid(format!("route reflector client {} must be in AS {}", neighbor.address, self.local_as)));
+            }
+            if neighbor.route_reflector_client && neighbor.route_server_client {
+                return Err(ConfigError::Invalid(format!(
+                    "neighbor {} can not be both a route reflector and a route server client", neighbor.address,
+                )));
+            }
+            if neighbor.confederation_member && self.confederation_id.is_none() {
+                return Err(ConfigError::Invalid(format!("neighbor {} is a confederation member without a confederation-id", neighbor.address)));
+            }
+            if neighbor.confederation_member && neighbor.route_server_client {
+                return Err(ConfigError::Invalid(format!(
+                    "neighbor {} can not be both a confederation member and a route server client", neighbor.address,
+                )));
+            }
+            if neighbor.ebgp_multihop == Some(0) {
+                return Err(ConfigError::Invalid(format!("ebgp-multihop of neighbor {} must not be 0", neighbor.address)));
+            }
+            for policy in neighbor.import_policy.iter().chain(neighbor.export_policy.iter()) {
+                if !self.policies.contains_key(policy) {
+                    return Err(ConfigError::UnknownPolicy { neighbor: neighbor.address, policy: policy.clone() });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn neighbor(&self, address: IpAddr) -> NeighborConfig {
+        self.neighbors.iter()
+            .find(|neighbor| neighbor.address == address)
+            .cloned()
+            .unwrap_or_else(|| NeighborConfig::new(address))
+    }
+
+    pub fn cluster_id(&self) -> Ipv4Addr {
+        self.cluster_id.unwrap_or(self.router_id)
+    }
+
+    /// Whether routes are reflected between iBGP neighbors, which is the case once any neighbor is
+    /// a route reflector client
+    pub fn route_reflector(&self) -> bool {
+        self.neighbors.iter().any(|neighbor| neighbor.route_reflector_client)
+    }
+
+    pub fn policy(&self, name: &Option<String>) -> Option<&Policy> {
+        name.as_ref().and_then(|name| self.policies.get(name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        let config: Config = toml::from_str(r#"
+            local-as = 65002
+            router-id = "192.0.2.1"
+
+            [[neighbor]]
+            address = "192.168.10.1"
+            remote-as = 65001
+            import-policy = "from-core"
+            max-prefix = { limit = 1000, restart-time = 300 }
+            add-path = "receive"
+
+            [trap]
+            prefixes = ["10.10.100.200/32"]
+
+            [policy.from-core]
+            default-action = "reject"
+
+            [[policy.from-core.term]]
+            name = "rtbh"
+            match = { prefix = ["10.0.0.0/8 ge 32"], community = ["65001:666"], as-path = "^65001$" }
+            then = { local-pref = 200, add-community = ["blackhole"], action = "accept" }
+        "#).unwrap();
+        config.validate().unwrap();
+
+        assert_eq!(config.listen, default_listen());
+        assert_eq!(config.trap.next_hop, default_trap_next_hop());
+        assert_eq!(config.trap.prefixes, vec!["10.10.100.200/32".parse().unwrap()]);
+        assert_eq!(config.hold_time, 30);
+        assert_eq!(config.neighbor("192.168.10.1".parse().unwrap()).remote_as, Some(65001));
+        assert_eq!(config.neighbor("192.168.10.2".parse().unwrap()).remote_as, None);
+        let max_prefix = config.neighbor("192.168.10.1".parse().unwrap()).max_prefix.unwrap();
+        assert_eq!(max_prefix.warning_limit(), 750);
+        assert_eq!(max_prefix.restart_time(), Some(Duration::from_secs(300)));
+        assert_eq!(config.neighbor("192.168.10.1".parse().unwrap()).add_path, Some(AddPathMode::Receive));
+        let policy = config.policy(&Some("from-core".to_string())).unwrap();
+        assert_eq!(policy.terms.len(), 1);
+        assert_eq!(policy.terms[0].actions.local_pref, Some(200));
+    }
+
+    #[test]
+    fn test_example_config() {
+        Config::load(Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/bgtrap.example.toml"))).unwrap();
+    }
+
+    #[test]
+    fn test_unknown_policy() {
+        let config: Config = toml::from_str(r#"
+            local-as = 65002
+            router-id = "192.0.2.1"
+
+            [[neighbor]]
+            address = "192.168.10.1"
+            export-policy = "missing"
+        "#).unwrap();
+        assert!(matches!(config.validate(), Err(ConfigError::UnknownPolicy { .. })));
+    }
+
+    #[test]
+    fn test_route_reflector_client() {
+        let config: Config = toml::from_str(r#"
+            local-as = 65002
+            router-id = "192.0.2.1"
+
+            [[neighbor]]
+            address = "192.168.10.1"
+            remote-as = 65001
+            route-reflector-client = true
+        "#).unwrap();
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))));
+        assert!(config.route_reflector());
+        assert_eq!(config.cluster_id(), Ipv4Addr::new(192, 0, 2, 1));
+    }
+
+    #[test]
+    fn test_confederation_member() {
+        let mut config: Config = toml::from_str(r#"
+            local-as = 65010
+            router-id = "192.0.2.1"
+
+            [[neighbor]]
+            address = "192.168.10.1"
+            remote-as = 65011
+            confederation-member = true
+        "#).unwrap();
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))));
+        config.confederation_id = Some(65000);
+        config.validate().unwrap();
+        assert!(config.neighbor("192.168.10.1".parse().unwrap()).confederation_member);
+    }
+}
@@ -0,0 +1,375 @@
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::broadcast;
+use tracing::{error, info, warn};
+
+use crate::bgp::utils::as_path::AsPathDisplay;
+use crate::bgp::utils::flowspec::FlowSpecRule;
+use crate::bgp::utils::prefix::Prefix;
+use crate::peer::Shared;
+use crate::reload;
+use crate::session::{SessionCommand, collect_tables};
+use crate::trap::{TrapDetails, TrapTable};
+
+/// Request to the control API, one JSON object per line, e.g.
+/// `{"command": "trap-add", "prefix": "192.0.2.1/32"}`
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum Request {
+    /// Trap a prefix, optionally with a reason and for `ttl` seconds
+    TrapAdd {
+        prefix: Prefix,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reason: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ttl: Option<u64>,
+    },
+    TrapDel { prefix: Prefix },
+    TrapList,
+    FlowspecAdd { rule: FlowSpecRule },
+    FlowspecDel { rule: FlowSpecRule },
+    FlowspecList,
+    /// Close the session and refuse connections from the neighbor until it is enabled again
+    NeighborDisable { address: IpAddr, #[serde(default)] message: String },
+    NeighborEnable { address: IpAddr },
+    /// Close the session, the neighbor may reconnect right away
+    NeighborReset { address: IpAddr, #[serde(default)] message: String },
+    NeighborSoftReset { address: IpAddr, direction: SoftResetDirection },
+    /// Read the configuration file again, see [`reload`](crate::reload::reload)
+    Reload,
+    NeighborList,
+    /// Routes received from all neighbors, only those within `prefix` if it is set
+    RouteList {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        prefix: Option<Prefix>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SoftResetDirection {
+    /// Ask the neighbor to send its routes again
+    In,
+    /// Send our routes again
+    Out,
+}
+
+/// State of a session as listed by `neighbor-list`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct NeighborStatus {
+    pub address: IpAddr,
+    pub state: String,
+    /// AS from the neighbor's last OPEN, 0 if it never sent one
+    pub remote_as: u16,
+    /// Seconds since the session was established, 0 if it is not
+    pub uptime: u64,
+    pub prefixes_received: u64,
+    pub prefixes_accepted: usize,
+    pub prefixes_advertised: usize,
+}
+
+/// Route in a neighbor's Adj-RIB-In as listed by `route-list`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct RouteEntry {
+    pub neighbor: IpAddr,
+    pub prefix: Prefix,
+    pub path_id: u32,
+    pub next_hop: Option<Ipv4Addr>,
+    pub as_path: String,
+    pub communities: Vec<String>,
+}
+
+/// Response to a request, sent as a single line of JSON
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Response {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub traps: Option<Vec<Prefix>>,
+    /// Reason and expiry of the traps that have them, with `trap-list`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub details: Option<Vec<TrapDetails>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub flowspec: Option<Vec<FlowSpecRule>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub neighbors: Option<Vec<NeighborStatus>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub routes: Option<Vec<RouteEntry>>,
+}
+
+impl Response {
+    fn ok() -> Response {
+        Response { ok: true, ..Default::default() }
+    }
+
+    fn error(error: impl ToString) -> Response {
+        Response { ok: false, error: Some(error.to_string()), ..Default::default() }
+    }
+}
+
+fn neighbor_list(shared: &Shared) -> Vec<NeighborStatus> {
+    shared.metrics.snapshot(&shared.config()).into_iter().map(|(address, metrics)| NeighborStatus {
+        address,
+        state: metrics.state.to_string(),
+        remote_as: metrics.remote_as,
+        uptime: metrics.established_at.map_or(0, |established_at| established_at.elapsed().as_secs()),
+        prefixes_received: metrics.prefixes_received,
+        prefixes_accepted: metrics.prefixes_accepted,
+        prefixes_advertised: metrics.prefixes_advertised,
+    }).collect()
+}
+
+async fn route_list(prefix: Option<Prefix>, shared: &Shared) -> Vec<RouteEntry> {
+    let mut routes: Vec<RouteEntry> = collect_tables(shared).await.into_iter()
+        .flat_map(|table| {
+            let neighbor = table.address;
+            table.routes.into_iter().map(move |route| RouteEntry {
+                neighbor,
+                prefix: route.prefix,
+                path_id: route.path_id,
+                next_hop: route.next_hop(),
+                as_path: AsPathDisplay(&route.as_path()).to_string(),
+                communities: route.communities().iter().map(|community| community.to_string()).collect(),
+            })
+        })
+        .filter(|route| prefix.is_none_or(|prefix| prefix.contains(&route.prefix)))
+        .collect();
+    routes.sort_by_key(|route| (u32::from_be_bytes(route.prefix.prefix), route.prefix.length, route.neighbor, route.path_id));
+    routes
+}
+
+/// Applies the request to the trap table or passes it on to the neighbor's session. Trap table
+/// changes are persisted and announced to all sessions.
+pub async fn handle_request(request: Request, shared: &Shared, commands: &broadcast::Sender<SessionCommand>) -> Response {
+    let command = match request {
+        Request::NeighborList => return Response { neighbors: Some(neighbor_list(shared)), ..Response::ok() },
+        Request::RouteList { prefix } => return Response { routes: Some(route_list(prefix, shared).await), ..Response::ok() },
+        Request::NeighborDisable { address, message } => {
+            shared.suspended.suspend(address, None);
+            SessionCommand::Disable(address, message)
+        },
+        Request::NeighborEnable { address } => {
+            if !shared.suspended.resume(address) {
+                return Response::error(format!("{} is not disabled", address));
+            }
+            return Response::ok();
+        },
+        Request::NeighborReset { address, message } => SessionCommand::Reset(address, message),
+        Request::NeighborSoftReset { address, direction: SoftResetDirection::In } => SessionCommand::SoftResetIn(address),
+        Request::NeighborSoftReset { address, direction: SoftResetDirection::Out } => SessionCommand::SoftResetOut(address),
+        Request::Reload => {
+            return match reload::reload(shared, commands) {
+                Ok(()) => Response::ok(),
+                Err(e) => Response::error(e),
+            };
+        },
+        request => return handle_trap_request(request, shared, commands),
+    };
+    let _ = commands.send(command);
+    Response::ok()
+}
+
+fn handle_trap_request(request: Request, shared: &Shared, commands: &broadcast::Sender<SessionCommand>) -> Response {
+    let mut traps = shared.traps.lock().unwrap();
+    let changed = match request {
+        Request::TrapList => {
+            return Response { traps: Some(traps.prefixes().to_vec()), details: Some(traps.details().to_vec()), ..Response::ok() };
+        },
+        Request::FlowspecList => return Response { flowspec: Some(traps.flowspec().to_vec()), ..Response::ok() },
+        Request::TrapAdd { prefix, reason, ttl } => {
+            let expires = ttl.map(|ttl| (SystemTime::now() + Duration::from_secs(ttl)).duration_since(UNIX_EPOCH).unwrap_or_default().as_secs());
+            let details = TrapDetails { prefix, reason, expires };
+            let details_changed = traps.details().iter().find(|existing| existing.prefix == prefix) != Some(&details);
+            traps.set_details(details);
+            // Only the route itself concerns the sessions
+            if traps.add_prefix(prefix) {
+                true
+            } else {
+                if details_changed {
+                    save(&traps);
+                }
+                false
+            }
+        },
+        Request::TrapDel { prefix } => {
+            if !traps.remove_prefix(&prefix) {
+                return Response::error(format!("{} is not trapped", prefix));
+            }
+            true
+        },
+        Request::FlowspecAdd { rule } => {
+            if let Err(e) = rule.validate() {
+                return Response::error(e);
+            }
+            traps.add_flowspec(rule);
+            true
+        },
+        Request::FlowspecDel { rule } => {
+            if !traps.remove_flowspec(&rule) {
+                return Response::error("No matching FlowSpec rule");
+            }
+            true
+        },
+        Request::NeighborDisable { .. } | Request::NeighborEnable { .. } | Request::NeighborReset { .. }
+            | Request::NeighborSoftReset { .. } | Request::Reload | Request::NeighborList | Request::RouteList { .. } => {
+            unreachable!("handled by handle_request")
+        },
+    };
+    if changed {
+        save(&traps);
+        let _ = commands.send(SessionCommand::TrapsChanged);
+    }
+    Response::ok()
+}
+
+fn save(traps: &TrapTable) {
+    if let Err(e) = traps.save() {
+        error!(error = %e, "Failed to save trap table");
+    }
+}
+
+/// Removes expired traps once a second
+pub async fn expire_traps(shared: Arc<Shared>, commands: broadcast::Sender<SessionCommand>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        let mut traps = shared.traps.lock().unwrap();
+        let expired = traps.expire(SystemTime::now());
+        if expired.is_empty() {
+            continue;
+        }
+        for prefix in &expired {
+            info!(%prefix, "Trap expired");
+        }
+        save(&traps);
+        let _ = commands.send(SessionCommand::TrapsChanged);
+    }
+}
+
+async fn handle_connection(stream: UnixStream, shared: Arc<Shared>, commands: broadcast::Sender<SessionCommand>) -> std::io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str(&line) {
+            Ok(request) => handle_request(request, &shared, &commands).await,
+            Err(e) => Response::error(format!("Invalid request: {}", e)),
+        };
+        let mut buf = serde_json::to_vec(&response)?;
+        buf.push(b'\n');
+        writer.write_all(&buf).await?;
+    }
+    Ok(())
+}
+
+/// Serves the control API on a Unix socket, replacing a socket left behind by a previous run
+pub async fn serve(path: &Path, shared: Arc<Shared>, commands: broadcast::Sender<SessionCommand>) -> std::io::Result<()> {
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let shared = shared.clone();
+        let commands = commands.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, shared, commands).await {
+                warn!(error = %e, "Control connection failed");
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::trap::TrapTable;
+
+    fn request(json: &str) -> Request {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_trap_requests() {
+        let config = Config::default();
+        let shared = Shared::new(Arc::new(Config::default()), TrapTable::new(&config.trap));
+        let (commands, mut receiver) = broadcast::channel(16);
+
+        let response = handle_request(request(r#"{"command": "trap-add", "prefix": "192.0.2.1/32"}"#), &shared, &commands).await;
+        assert!(response.ok);
+        assert!(matches!(receiver.try_recv(), Ok(SessionCommand::TrapsChanged)));
+
+        let response = handle_request(request(r#"{"command": "trap-list"}"#), &shared, &commands).await;
+        assert_eq!(response.traps.unwrap(), vec!["10.10.100.200/32".parse().unwrap(), "192.0.2.1/32".parse().unwrap()]);
+
+        let add = r#"{"command": "trap-add", "prefix": "192.0.2.1/32", "reason": "DDoS", "ttl": 3600}"#;
+        assert!(handle_request(request(add), &shared, &commands).await.ok);
+        // Only the details changed, the route stays as it is
+        assert!(receiver.try_recv().is_err());
+        let details = handle_request(request(r#"{"command": "trap-list"}"#), &shared, &commands).await.details.unwrap();
+        assert_eq!(details.len(), 1);
+        assert_eq!(details[0].reason.as_deref(), Some("DDoS"));
+        assert!(details[0].expires.is_some());
+
+        let response = handle_request(request(r#"{"command": "trap-del", "prefix": "198.51.100.1/32"}"#), &shared, &commands).await;
+        assert_eq!(response.error.unwrap(), "198.51.100.1/32 is not trapped");
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_flowspec_requests() {
+        let config = Config::default();
+        let shared = Shared::new(Arc::new(Config::default()), TrapTable::new(&config.trap));
+        let (commands, _receiver) = broadcast::channel(16);
+
+        let add = r#"{"command": "flowspec-add", "rule": {
+            "destination": "192.0.2.1/32", "protocol": ["17"], "source-port": ["123"], "actions": ["discard"]
+        }}"#;
+        assert!(handle_request(request(add), &shared, &commands).await.ok);
+        let response = handle_request(request(r#"{"command": "flowspec-list"}"#), &shared, &commands).await;
+        assert_eq!(response.flowspec.unwrap().len(), 1);
+
+        let response = handle_request(request(r#"{"command": "flowspec-add", "rule": {}}"#), &shared, &commands).await;
+        assert_eq!(response.error.unwrap(), "FlowSpec rule has no match components");
+
+        let delete = r#"{"command": "flowspec-del", "rule": {
+            "destination": "192.0.2.1/32", "protocol": ["17"], "source-port": ["123"]
+        }}"#;
+        assert!(handle_request(request(delete), &shared, &commands).await.ok);
+        assert!(shared.traps.lock().unwrap().flowspec().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_neighbor_requests() {
+        let config = Config::default();
+        let shared = Shared::new(Arc::new(Config::default()), TrapTable::new(&config.trap));
+        let (commands, mut receiver) = broadcast::channel(16);
+        let address: IpAddr = "192.0.2.10".parse().unwrap();
+
+        let disable = r#"{"command": "neighbor-disable", "address": "192.0.2.10", "message": "Maintenance"}"#;
+        assert!(handle_request(request(disable), &shared, &commands).await.ok);
+        assert!(matches!(receiver.try_recv(), Ok(SessionCommand::Disable(disabled, message)) if disabled == address && message == "Maintenance"));
+        assert!(shared.suspended.is_suspended(address));
+
+        let enable = r#"{"command": "neighbor-enable", "address": "192.0.2.10"}"#;
+        assert!(handle_request(request(enable), &shared, &commands).await.ok);
+        assert!(!shared.suspended.is_suspended(address));
+        assert_eq!(handle_request(request(enable), &shared, &commands).await.error.unwrap(), "192.0.2.10 is not disabled");
+
+        let soft_reset = r#"{"command": "neighbor-soft-reset", "address": "192.0.2.10", "direction": "out"}"#;
+        assert!(handle_request(request(soft_reset), &shared, &commands).await.ok);
+        assert!(matches!(receiver.try_recv(), Ok(SessionCommand::SoftResetOut(reset)) if reset == address));
+    }
+}
@@ -0,0 +1,25 @@
+//! BGtraP, a BGP speaker that injects blackhole and FlowSpec routes for trapped prefixes.
+//!
+//! [`bgp`] holds the message model and codec and can be used on its own. The other modules make
+//! up the daemon in `main.rs`.
+
+pub mod bgp;
+pub mod bmp;
+pub mod config;
+pub mod control;
+pub mod metrics;
+pub mod mrt;
+pub mod peer;
+pub mod policy;
+pub mod reload;
+pub mod replay;
+pub mod rib;
+pub mod route_reflector;
+pub mod route_server;
+pub mod session;
+pub mod socket;
+pub mod test_peer;
+pub mod trap;
+
+#[macro_use]
+extern crate num_derive;
@@ -1,119 +1,151 @@
-mod bgp;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
-#[macro_use]
-extern crate num_derive;
+use tokio::net::TcpListener;
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::{broadcast, mpsc};
+use tracing::{error, info};
+use tracing_subscriber::EnvFilter;
 
-use tokio::net::{TcpListener, TcpStream};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use bgtrap::{bmp, control, metrics, mrt, reload, replay, session, socket};
+use bgtrap::config::{Config, LogConfig, LogFormat};
+use bgtrap::peer::Shared;
+use bgtrap::session::SessionCommand;
+use bgtrap::trap::TrapTable;
 
-use bgp::{BGP_MAX_MSG_SIZE, BGPMessage, message_length};
-use bgp::open::BGPOpen;
-use bgp::keepalive::BGPKeepalive;
-use bgp::utils::prefix::Prefix;
-use bgp::errors::BgpError;
-use crate::bgp::update::BGPUpdate;
-use crate::bgp::utils::path_attribute::{PathAttribute, AttributeType, AttributeFlag};
+/// How long sessions get to withdraw routes and say goodbye on shutdown
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
 
-const LOG_MESSAGES: bool = true;
-
-macro_rules! log_message_content {
-    ($prefix:expr, $message:expr, [$($type:ident),+]) => {
-        match $message {
-            $(
-                BGPMessage::$type(content) => println!("{}: {:#?}", $prefix, &content),
-            )+
-        }
-    }
-}
-
-fn log_message(prefix: &str, message: &BGPMessage) {
-    if !LOG_MESSAGES {
-        return
+/// Logs to stdout, RUST_LOG takes precedence over the configured level
+fn init_logging(config: &LogConfig) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(&config.level));
+    let subscriber = tracing_subscriber::fmt().with_env_filter(filter);
+    match config.format {
+        LogFormat::Text => subscriber.init(),
+        LogFormat::Json => subscriber.json().init(),
     }
-    log_message_content!(prefix, message, [Open, Keepalive, Update, Notification]);
 }
 
-async fn send_message(message: BGPMessage, socket: &mut TcpStream) -> Result<(), BgpError>{
-    log_message("S", &message);
-    let buf: Vec<u8> = message.into();
-    socket.write_all(&buf[..]).await?;
-    Ok(())
-}
-
-static mut ADV_SENT: bool = false;
-
-async fn demo(socket: &mut TcpStream) -> Result<(), BgpError> {
-    unsafe {
-        if ADV_SENT {
-            return Ok(())
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // Usage: bgtrap [config] [--replay <MRT file>]
+    let mut config_path = None;
+    let mut replay_path = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--replay" => replay_path = Some(args.next().ok_or("--replay requires an MRT file")?),
+            _ => config_path = Some(arg),
         }
-        ADV_SENT = true;
     }
-
-    let advertisement = BGPUpdate {
-        withdrawn_routes: vec![],
-        network_layer_reachability_information: vec![Prefix { length: 32, prefix: [10, 10, 100, 200]}],
-        path_attributes: vec![
-            PathAttribute { type_code: AttributeType::Origin, value: vec![2], flags: vec![AttributeFlag::Transitive]},
-            PathAttribute { type_code: AttributeType::ASPath, value: vec![], flags: vec![AttributeFlag::ExtendedLength, AttributeFlag::Transitive]},
-            PathAttribute { type_code: AttributeType::NextHop, value: vec![192, 168, 10, 5], flags: vec![AttributeFlag::Transitive]},
-            PathAttribute { type_code: AttributeType::LocalPref, value: vec![0, 0, 0, 100], flags: vec![AttributeFlag::Transitive]}
-        ]
+    let config = match &config_path {
+        Some(path) => Config::load(Path::new(path))?,
+        None => Config::default(),
     };
-    send_message(BGPMessage::Update(advertisement), socket).await?;
-    Ok(())
-}
-
-async fn handle_message(message: &BGPMessage, socket: &mut TcpStream) -> Result<(), BgpError> {
-    log_message("R", &message);
-    match message {
-        BGPMessage::Open(_) => {
-            let open = BGPOpen {
-                version: 4,
-                sender_as: 65002,
-                hold_time: 30,
-                bgp_id: 1234567890,
-                opt_params_len: 0,
-                opt_params: (),
-            };
-            send_message(BGPMessage::Open(open), socket).await?;
-        },
-        BGPMessage::Keepalive(_) => {
-            let keepalive = BGPKeepalive {};
-            send_message(BGPMessage::Keepalive(keepalive), socket).await?;
-            demo(socket).await?;
-        },
-        _ => {}
+    init_logging(&config.log);
+    let config = Arc::new(config);
+    let traps = TrapTable::load(&config.trap)?;
+    if let Some(path) = replay_path {
+        replay::run(Path::new(&path), Arc::new(Shared::new(config, traps)))?;
+        return Ok(());
     }
-    Ok(())
-}
+    traps.save()?;
+    let mut shared = Shared::new(config.clone(), traps);
+    shared.config_path = config_path.map(PathBuf::from);
+    let listener = TcpListener::bind(config.listen).await?;
+    socket::configure_listener(&listener, &config)?;
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let listener = TcpListener::bind("0.0.0.0:179").await?;
+    let (commands, _) = broadcast::channel(16);
+    if let Some(bmp_config) = config.bmp.clone() {
+        let (sender, events) = bmp::queue();
+        shared.bmp = sender;
+        tokio::spawn(bmp::run(bmp_config, config.router_id.to_string(), events, commands.clone()));
+    }
+    let mut mrt_records = None;
+    if let Some(mrt_config) = config.mrt.clone() {
+        let (sender, records) = mrt::queue();
+        shared.mrt = sender;
+        mrt_records = Some((mrt_config, records));
+    }
+    let shared = Arc::new(shared);
+    // Table dumps need the shared state to reach the sessions
+    if let Some((mrt_config, records)) = mrt_records {
+        tokio::spawn(mrt::run(mrt_config, config.router_id, records, shared.clone()));
+    }
+    if let Some(path) = config.control_socket.clone() {
+        let shared = shared.clone();
+        let commands = commands.clone();
+        tokio::spawn(async move {
+            if let Err(e) = control::serve(&path, shared, commands).await {
+                error!(path = %path.display(), error = %e, "Control API failed");
+            }
+        });
+    }
+    tokio::spawn(control::expire_traps(shared.clone(), commands.clone()));
+    if let Some(metrics_config) = config.metrics.clone() {
+        let shared = shared.clone();
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve(metrics_config.listen, shared).await {
+                error!(listen = %metrics_config.listen, error = %e, "Metrics endpoint failed");
+            }
+        });
+    }
+    let mut refresh_signal = signal(SignalKind::user_defined1())?;
+    let mut terminate_signal = signal(SignalKind::terminate())?;
+    let mut interrupt_signal = signal(SignalKind::interrupt())?;
+    let mut reload_signal = signal(SignalKind::hangup())?;
+    // Reloads can also come from the control API, the listener's MD5 keys follow either way
+    let mut reloads = commands.subscribe();
+    // Every session holds a sender, so the receiver sees the channel closed once all have ended
+    let (running, mut finished) = mpsc::channel::<()>(1);
 
     loop {
-        let (mut socket, _) = listener.accept().await?;
-
-        tokio::spawn(async move {
-            let mut buf = [0; BGP_MAX_MSG_SIZE];
-            loop {
-                let n = match socket.read(&mut buf).await {
-                    Ok(n) if n == 0 => return,
-                    Ok(n) => n,
-                    Err(e) => { eprintln!("failed to read from socket, err = {:?}", e); return; }
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (socket, address) = accepted?;
+                let session = match session::accept(socket, address, shared.clone(), commands.subscribe()) {
+                    Some(session) => session,
+                    None => continue,
                 };
-                let mut bytes_left = n;
-                let mut i = 0;
-                while bytes_left > 0 {
-                    let bgp_message_buf = &buf[i..n];
-                    let bgp_message_length = message_length(&bgp_message_buf);
-                    let bgp_message: BGPMessage = bgp_message_buf.into();
-                    handle_message(&bgp_message, &mut socket).await.expect("Failed to handle message");
-                    i += bgp_message_length;
-                    bytes_left -= bgp_message_length;
+                let running = running.clone();
+                tokio::spawn(async move {
+                    session.await;
+                    drop(running);
+                });
+            },
+            _ = refresh_signal.recv() => {
+                info!("Requesting route refresh from all neighbors");
+                let _ = commands.send(SessionCommand::RequestRefresh);
+            },
+            _ = reload_signal.recv() => {
+                if let Err(e) = reload::reload(&shared, &commands) {
+                    error!(error = %e, "Failed to reload configuration");
                 }
-            }
-        });
+            },
+            command = reloads.recv() => {
+                let result = match command {
+                    Ok(SessionCommand::ConfigReloaded(previous)) => socket::reconfigure_listener(&listener, &previous, &shared.config()),
+                    Err(broadcast::error::RecvError::Lagged(_)) => socket::configure_listener(&listener, &shared.config()),
+                    _ => Ok(()),
+                };
+                if let Err(e) = result {
+                    error!(error = %e, "Failed to reconfigure the listening socket");
+                }
+            },
+            _ = terminate_signal.recv() => break,
+            _ = interrupt_signal.recv() => break,
+        }
+    }
+
+    info!("Shutting down");
+    let _ = commands.send(SessionCommand::Shutdown("BGtraP is shutting down".to_string()));
+    drop(running);
+    if tokio::time::timeout(SHUTDOWN_TIMEOUT, finished.recv()).await.is_err() {
+        info!("Sessions did not close in time");
     }
+    if let Some(path) = &config.control_socket {
+        let _ = std::fs::remove_file(path);
+    }
+    Ok(())
 }
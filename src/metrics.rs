@@ -0,0 +1,243 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::warn;
+
+use crate::bgp::BGPMessage;
+use crate::config::Config;
+use crate::peer::Shared;
+
+/// FSM states a session can be reported in, `Idle` once it has closed
+const STATES: [&str; 4] = ["Idle", "Active", "OpenConfirm", "Established"];
+
+/// Largest HTTP request head that is read before giving up on the request
+const MAX_REQUEST_SIZE: usize = 8192;
+
+/// Counters and gauges of a single neighbor, kept across sessions
+#[derive(Debug, Default, Clone)]
+pub struct PeerMetrics {
+    pub state: &'static str,
+    /// AS from the neighbor's last OPEN
+    pub remote_as: u16,
+    pub established_at: Option<Instant>,
+    /// Messages by type name
+    pub messages_sent: BTreeMap<&'static str, u64>,
+    pub messages_received: BTreeMap<&'static str, u64>,
+    /// NOTIFICATIONs by code and subcode
+    pub notifications_sent: BTreeMap<(u8, u8), u64>,
+    pub notifications_received: BTreeMap<(u8, u8), u64>,
+    /// Prefixes announced in received UPDATEs
+    pub prefixes_received: u64,
+    /// Routes in the Adj-RIB-In and Adj-RIB-Out of the current session
+    pub prefixes_accepted: usize,
+    pub prefixes_advertised: usize,
+    pub decode_errors: u64,
+}
+
+/// Metrics of all neighbors that have connected since the daemon started
+#[derive(Default)]
+pub struct Metrics(Mutex<BTreeMap<IpAddr, PeerMetrics>>);
+
+impl Metrics {
+    pub fn update(&self, address: IpAddr, f: impl FnOnce(&mut PeerMetrics)) {
+        f(self.0.lock().unwrap().entry(address).or_default());
+    }
+
+    pub fn message_sent(&self, address: IpAddr, message: &BGPMessage) {
+        self.update(address, |metrics| {
+            *metrics.messages_sent.entry(message.type_name()).or_default() += 1;
+            if let BGPMessage::Notification(notification) = message {
+                *metrics.notifications_sent.entry((notification.error_code, notification.error_subcode)).or_default() += 1;
+            }
+        });
+    }
+
+    pub fn message_received(&self, address: IpAddr, message: &BGPMessage) {
+        self.update(address, |metrics| {
+            *metrics.messages_received.entry(message.type_name()).or_default() += 1;
+            match message {
+                BGPMessage::Notification(notification) => {
+                    *metrics.notifications_received.entry((notification.error_code, notification.error_subcode)).or_default() += 1;
+                },
+                BGPMessage::Update(update) => metrics.prefixes_received += update.network_layer_reachability_information.len() as u64,
+                _ => {},
+            }
+        });
+    }
+
+    /// Records the session's current state, uptime is measured from the first report as Established
+    pub fn session_state(&self, address: IpAddr, state: &'static str, prefixes_accepted: usize, prefixes_advertised: usize) {
+        self.update(address, |metrics| {
+            if state != "Established" {
+                metrics.established_at = None;
+            } else if metrics.established_at.is_none() {
+                metrics.established_at = Some(Instant::now());
+            }
+            metrics.state = state;
+            metrics.prefixes_accepted = prefixes_accepted;
+            metrics.prefixes_advertised = prefixes_advertised;
+        });
+    }
+
+    pub fn session_closed(&self, address: IpAddr) {
+        self.session_state(address, "Idle", 0, 0);
+    }
+
+    /// Metrics of all configured neighbors and all neighbors that have connected, ordered by
+    /// address. Configured neighbors are reported as Idle before they first connect.
+    pub fn snapshot(&self, config: &Config) -> Vec<(IpAddr, PeerMetrics)> {
+        let idle = PeerMetrics { state: "Idle", ..Default::default() };
+        let mut peers: BTreeMap<IpAddr, PeerMetrics> = config.neighbors.iter()
+            .map(|neighbor| (neighbor.address, idle.clone()))
+            .collect();
+        peers.extend(self.0.lock().unwrap().iter().map(|(address, metrics)| (*address, metrics.clone())));
+        peers.into_iter().collect()
+    }
+}
+
+/// Writes a metric family's HELP and TYPE lines followed by its samples
+fn family(
+    out: &mut String,
+    name: &str,
+    kind: &str,
+    help: &str,
+    samples: impl IntoIterator<Item = (String, String)>,
+) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for (labels, value) in samples {
+        let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+    }
+}
+
+/// All metrics in the Prometheus text exposition format
+pub fn render(shared: &Shared) -> String {
+    let peers = shared.metrics.snapshot(&shared.config());
+
+    let mut out = String::new();
+    family(&mut out, "bgtrap_peer_state", "gauge", "Session FSM state, 1 for the current state",
+        peers.iter().flat_map(|(address, metrics)| STATES.iter().map(move |state| {
+            (format!("peer=\"{}\",state=\"{}\"", address, state), ((metrics.state == *state) as u8).to_string())
+        })));
+    family(&mut out, "bgtrap_peer_uptime_seconds", "gauge", "Seconds since the session was established, 0 if it is not",
+        peers.iter().map(|(address, metrics)| {
+            let uptime = metrics.established_at.map_or(0.0, |established_at| established_at.elapsed().as_secs_f64());
+            (format!("peer=\"{}\"", address), format!("{:.3}", uptime))
+        }));
+    family(&mut out, "bgtrap_messages_sent_total", "counter", "BGP messages sent by type",
+        peers.iter().flat_map(|(address, metrics)| metrics.messages_sent.iter().map(move |(kind, count)| {
+            (format!("peer=\"{}\",type=\"{}\"", address, kind), count.to_string())
+        })));
+    family(&mut out, "bgtrap_messages_received_total", "counter", "BGP messages received by type",
+        peers.iter().flat_map(|(address, metrics)| metrics.messages_received.iter().map(move |(kind, count)| {
+            (format!("peer=\"{}\",type=\"{}\"", address, kind), count.to_string())
+        })));
+    family(&mut out, "bgtrap_notifications_sent_total", "counter", "NOTIFICATIONs sent by error code and subcode",
+        peers.iter().flat_map(|(address, metrics)| metrics.notifications_sent.iter().map(move |((code, subcode), count)| {
+            (format!("peer=\"{}\",code=\"{}\",subcode=\"{}\"", address, code, subcode), count.to_string())
+        })));
+    family(&mut out, "bgtrap_notifications_received_total", "counter", "NOTIFICATIONs received by error code and subcode",
+        peers.iter().flat_map(|(address, metrics)| metrics.notifications_received.iter().map(move |((code, subcode), count)| {
+            (format!("peer=\"{}\",code=\"{}\",subcode=\"{}\"", address, code, subcode), count.to_string())
+        })));
+    family(&mut out, "bgtrap_prefixes_received_total", "counter", "Prefixes announced by the neighbor",
+        peers.iter().map(|(address, metrics)| (format!("peer=\"{}\"", address), metrics.prefixes_received.to_string())));
+    family(&mut out, "bgtrap_prefixes_accepted", "gauge", "Routes accepted by the import policy",
+        peers.iter().map(|(address, metrics)| (format!("peer=\"{}\"", address), metrics.prefixes_accepted.to_string())));
+    family(&mut out, "bgtrap_prefixes_advertised", "gauge", "Routes advertised to the neighbor",
+        peers.iter().map(|(address, metrics)| (format!("peer=\"{}\"", address), metrics.prefixes_advertised.to_string())));
+    family(&mut out, "bgtrap_decode_errors_total", "counter", "Received messages that could not be decoded",
+        peers.iter().map(|(address, metrics)| (format!("peer=\"{}\"", address), metrics.decode_errors.to_string())));
+
+    let traps = shared.traps.lock().unwrap();
+    let _ = writeln!(out, "# HELP bgtrap_trapped_prefixes Prefixes in the trap table");
+    let _ = writeln!(out, "# TYPE bgtrap_trapped_prefixes gauge");
+    let _ = writeln!(out, "bgtrap_trapped_prefixes {}", traps.prefixes().len());
+    let _ = writeln!(out, "# HELP bgtrap_flowspec_rules FlowSpec rules in the trap table");
+    let _ = writeln!(out, "# TYPE bgtrap_flowspec_rules gauge");
+    let _ = writeln!(out, "bgtrap_flowspec_rules {}", traps.flowspec().len());
+    out
+}
+
+async fn handle_connection(mut stream: TcpStream, shared: Arc<Shared>) -> std::io::Result<()> {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 || request.len() + n > MAX_REQUEST_SIZE {
+            return Ok(());
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    let response = if request.starts_with(b"GET /metrics ") {
+        let body = render(&shared);
+        format!("HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body)
+    } else {
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Serves `GET /metrics` over plain HTTP, one request per connection
+pub async fn serve(listen: SocketAddr, shared: Arc<Shared>) -> std::io::Result<()> {
+    let listener = TcpListener::bind(listen).await?;
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let shared = shared.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, shared).await {
+                warn!(error = %e, "Metrics request failed");
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bgp::notification::BGPNotification;
+    use crate::trap::TrapTable;
+
+    #[test]
+    fn test_render() {
+        let config: Config = toml::from_str(r#"
+            local-as = 65002
+            router-id = "192.0.2.1"
+
+            [[neighbor]]
+            address = "192.0.2.11"
+
+            [trap]
+            prefixes = ["10.0.0.1/32", "10.0.0.2/32"]
+        "#).unwrap();
+        let traps = TrapTable::new(&config.trap);
+        let shared = Shared::new(Arc::new(config), traps);
+        let address: IpAddr = "192.0.2.10".parse().unwrap();
+        shared.metrics.session_state(address, "Established", 3, 2);
+        shared.metrics.message_sent(address, &BGPMessage::Notification(BGPNotification::new(6, 2, vec![])));
+        shared.metrics.message_received(address, &BGPMessage::Keepalive(Default::default()));
+        shared.metrics.message_received(address, &BGPMessage::Keepalive(Default::default()));
+
+        let out = render(&shared);
+        assert!(out.contains("bgtrap_peer_state{peer=\"192.0.2.10\",state=\"Established\"} 1\n"));
+        assert!(out.contains("bgtrap_peer_state{peer=\"192.0.2.10\",state=\"Idle\"} 0\n"));
+        assert!(out.contains("bgtrap_peer_state{peer=\"192.0.2.11\",state=\"Idle\"} 1\n"));
+        assert!(out.contains("bgtrap_peer_uptime_seconds{peer=\"192.0.2.11\"} 0.000\n"));
+        assert!(out.contains("bgtrap_messages_sent_total{peer=\"192.0.2.10\",type=\"notification\"} 1\n"));
+        assert!(out.contains("bgtrap_messages_received_total{peer=\"192.0.2.10\",type=\"keepalive\"} 2\n"));
+        assert!(out.contains("bgtrap_notifications_sent_total{peer=\"192.0.2.10\",code=\"6\",subcode=\"2\"} 1\n"));
+        assert!(out.contains("bgtrap_prefixes_accepted{peer=\"192.0.2.10\"} 3\n"));
+        assert!(out.contains("bgtrap_prefixes_advertised{peer=\"192.0.2.10\"} 2\n"));
+        assert!(out.contains("# TYPE bgtrap_decode_errors_total counter\n"));
+        assert!(out.contains("bgtrap_trapped_prefixes 2\n"));
+
+        shared.metrics.session_closed(address);
+        assert!(render(&shared).contains("bgtrap_peer_state{peer=\"192.0.2.10\",state=\"Idle\"} 1\n"));
+    }
+}
@@ -0,0 +1,539 @@
+use std::convert::{TryFrom, TryInto};
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use tracing::{error, info, warn};
+
+use crate::bgp::utils::as_path::{compile_as4_path, extract_as_path};
+use crate::bgp::utils::path_attribute::{AttributeFlag, AttributeType, PathAttribute, compile_path_attributes};
+use crate::config::MrtConfig;
+use crate::rib::Route;
+use crate::peer::Shared;
+use crate::session::collect_tables;
+
+const MRT_HEADER_SIZE: usize = 12;
+
+/// Records waiting to be written, further ones are dropped
+const MRT_QUEUE_SIZE: usize = 10_000;
+
+const TYPE_TABLE_DUMP_V2: u16 = 13;
+const TYPE_BGP4MP: u16 = 16;
+/// BGP4MP with microsecond timestamps
+const TYPE_BGP4MP_ET: u16 = 17;
+
+const SUBTYPE_PEER_INDEX_TABLE: u16 = 1;
+const SUBTYPE_RIB_IPV4_UNICAST: u16 = 2;
+
+const SUBTYPE_BGP4MP_MESSAGE: u16 = 1;
+const SUBTYPE_BGP4MP_MESSAGE_AS4: u16 = 4;
+const SUBTYPE_BGP4MP_MESSAGE_LOCAL: u16 = 6;
+/// Same format as BGP4MP_MESSAGE_AS4, for messages sent by us
+const SUBTYPE_BGP4MP_MESSAGE_AS4_LOCAL: u16 = 7;
+/// Messages with ADD-PATH path identifiers (RFC 8050)
+const SUBTYPE_BGP4MP_MESSAGE_ADDPATH: u16 = 8;
+const SUBTYPE_BGP4MP_MESSAGE_AS4_ADDPATH: u16 = 9;
+const SUBTYPE_BGP4MP_MESSAGE_LOCAL_ADDPATH: u16 = 10;
+const SUBTYPE_BGP4MP_MESSAGE_AS4_LOCAL_ADDPATH: u16 = 11;
+
+const PEER_TYPE_IPV6: u8 = 0x01;
+const PEER_TYPE_AS4: u8 = 0x02;
+
+/// AS number used in place of 4-byte AS numbers that do not fit
+const AS_TRANS: u16 = 23456;
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum MrtError {
+    #[error("Truncated MRT record at offset {0}")]
+    Truncated(usize),
+    #[error("Invalid BGP4MP record at offset {0}")]
+    InvalidBgp4mp(usize),
+}
+
+fn make_mrt_record(timestamp: SystemTime, mrt_type: u16, subtype: u16, body: Vec<u8>) -> Vec<u8> {
+    let timestamp = timestamp.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let mut buf = Vec::with_capacity(MRT_HEADER_SIZE + body.len());
+    buf.extend_from_slice(&(timestamp.as_secs() as u32).to_be_bytes());
+    buf.extend_from_slice(&mrt_type.to_be_bytes());
+    buf.extend_from_slice(&subtype.to_be_bytes());
+    buf.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    buf.extend(body);
+    buf
+}
+
+fn address_octets(address: IpAddr) -> Vec<u8> {
+    match address {
+        IpAddr::V4(address) => address.octets().to_vec(),
+        IpAddr::V6(address) => address.octets().to_vec(),
+    }
+}
+
+/// Both ends of a BGP message recorded in the MRT file
+#[derive(Debug, Clone, Copy)]
+pub struct MessageEndpoints {
+    pub peer_as: u16,
+    pub peer_address: IpAddr,
+    pub local_as: u16,
+    pub local_address: IpAddr,
+}
+
+/// BGP4MP_ET record with a full BGP message, `sent` for messages from us to the neighbor and
+/// `add_path` if its IPv4 unicast prefixes carry path identifiers in that direction
+pub fn bgp4mp_message(timestamp: SystemTime, endpoints: &MessageEndpoints, sent: bool, add_path: bool, message: &[u8]) -> Vec<u8> {
+    let microseconds = timestamp.duration_since(UNIX_EPOCH).unwrap_or_default().subsec_micros();
+    let afi: u16 = if endpoints.peer_address.is_ipv6() { 2 } else { 1 };
+    let mut body = Vec::new();
+    body.extend_from_slice(&microseconds.to_be_bytes());
+    body.extend_from_slice(&(endpoints.peer_as as u32).to_be_bytes());
+    body.extend_from_slice(&(endpoints.local_as as u32).to_be_bytes());
+    body.extend_from_slice(&0u16.to_be_bytes()); // Interface index
+    body.extend_from_slice(&afi.to_be_bytes());
+    body.extend(address_octets(endpoints.peer_address));
+    body.extend(address_octets(endpoints.local_address));
+    body.extend_from_slice(message);
+    let subtype = match (sent, add_path) {
+        (false, false) => SUBTYPE_BGP4MP_MESSAGE_AS4,
+        (true, false) => SUBTYPE_BGP4MP_MESSAGE_AS4_LOCAL,
+        (false, true) => SUBTYPE_BGP4MP_MESSAGE_AS4_ADDPATH,
+        (true, true) => SUBTYPE_BGP4MP_MESSAGE_AS4_LOCAL_ADDPATH,
+    };
+    make_mrt_record(timestamp, TYPE_BGP4MP_ET, subtype, body)
+}
+
+/// BGP message read from a BGP4MP record
+#[derive(Debug)]
+pub struct RecordedMessage {
+    pub timestamp: SystemTime,
+    pub endpoints: MessageEndpoints,
+    /// Sent by the recording router rather than received from the peer
+    pub sent: bool,
+    /// IPv4 unicast prefixes carry ADD-PATH path identifiers
+    pub add_path: bool,
+    pub message: Vec<u8>,
+}
+
+fn read_address(buf: &[u8], afi: u16) -> Option<(IpAddr, &[u8])> {
+    match afi {
+        1 if buf.len() >= 4 => Some((IpAddr::from(<[u8; 4]>::try_from(&buf[..4]).unwrap()), &buf[4..])),
+        2 if buf.len() >= 16 => Some((IpAddr::from(<[u8; 16]>::try_from(&buf[..16]).unwrap()), &buf[16..])),
+        _ => None,
+    }
+}
+
+/// Parses the body of a BGP4MP or BGP4MP_ET message record, other records are skipped
+fn read_bgp4mp(mrt_type: u16, subtype: u16, seconds: u32, mut body: &[u8]) -> Option<Option<RecordedMessage>> {
+    let (as4, sent, add_path) = match subtype {
+        SUBTYPE_BGP4MP_MESSAGE => (false, false, false),
+        SUBTYPE_BGP4MP_MESSAGE_AS4 => (true, false, false),
+        SUBTYPE_BGP4MP_MESSAGE_LOCAL => (false, true, false),
+        SUBTYPE_BGP4MP_MESSAGE_AS4_LOCAL => (true, true, false),
+        SUBTYPE_BGP4MP_MESSAGE_ADDPATH => (false, false, true),
+        SUBTYPE_BGP4MP_MESSAGE_AS4_ADDPATH => (true, false, true),
+        SUBTYPE_BGP4MP_MESSAGE_LOCAL_ADDPATH => (false, true, true),
+        SUBTYPE_BGP4MP_MESSAGE_AS4_LOCAL_ADDPATH => (true, true, true),
+        // State changes
+        _ => return Some(None),
+    };
+    let mut timestamp = UNIX_EPOCH + Duration::from_secs(seconds as u64);
+    if mrt_type == TYPE_BGP4MP_ET {
+        let microseconds = u32::from_be_bytes(body.get(..4)?.try_into().unwrap());
+        timestamp += Duration::from_micros(microseconds as u64);
+        body = &body[4..];
+    }
+    let (peer_as, local_as) = if as4 {
+        let peer_as = u32::from_be_bytes(body.get(..4)?.try_into().unwrap());
+        let local_as = u32::from_be_bytes(body.get(4..8)?.try_into().unwrap());
+        body = &body[8..];
+        (u16::try_from(peer_as).unwrap_or(AS_TRANS), u16::try_from(local_as).unwrap_or(AS_TRANS))
+    } else {
+        let peer_as = u16::from_be_bytes(body.get(..2)?.try_into().unwrap());
+        let local_as = u16::from_be_bytes(body.get(2..4)?.try_into().unwrap());
+        body = &body[4..];
+        (peer_as, local_as)
+    };
+    // Interface index
+    let afi = u16::from_be_bytes(body.get(2..4)?.try_into().unwrap());
+    let (peer_address, body) = read_address(&body[4..], afi)?;
+    let (local_address, message) = read_address(body, afi)?;
+    Some(Some(RecordedMessage {
+        timestamp,
+        endpoints: MessageEndpoints { peer_as, peer_address, local_as, local_address },
+        sent,
+        add_path,
+        message: message.to_vec(),
+    }))
+}
+
+/// Reads the BGP messages of an MRT file, records of other types are skipped
+pub fn read_messages(buf: &[u8]) -> impl Iterator<Item = Result<RecordedMessage, MrtError>> + '_ {
+    let mut offset = 0;
+    std::iter::from_fn(move || {
+        while offset < buf.len() {
+            let header = match buf.get(offset..offset + MRT_HEADER_SIZE) {
+                Some(header) => header,
+                None => {
+                    let error = MrtError::Truncated(offset);
+                    offset = buf.len();
+                    return Some(Err(error));
+                },
+            };
+            let seconds = u32::from_be_bytes(header[..4].try_into().unwrap());
+            let mrt_type = u16::from_be_bytes([header[4], header[5]]);
+            let subtype = u16::from_be_bytes([header[6], header[7]]);
+            let length = u32::from_be_bytes(header[8..].try_into().unwrap()) as usize;
+            let record_offset = offset;
+            let body = match buf.get(offset + MRT_HEADER_SIZE..offset + MRT_HEADER_SIZE + length) {
+                Some(body) => body,
+                None => {
+                    offset = buf.len();
+                    return Some(Err(MrtError::Truncated(record_offset)));
+                },
+            };
+            offset += MRT_HEADER_SIZE + length;
+            if mrt_type != TYPE_BGP4MP && mrt_type != TYPE_BGP4MP_ET {
+                continue;
+            }
+            match read_bgp4mp(mrt_type, subtype, seconds, body) {
+                Some(Some(message)) => return Some(Ok(message)),
+                Some(None) => continue,
+                None => return Some(Err(MrtError::InvalidBgp4mp(record_offset))),
+            }
+        }
+        None
+    })
+}
+
+/// Adj-RIB-In of a single established session
+#[derive(Debug)]
+pub struct PeerTable {
+    pub address: IpAddr,
+    pub asn: u16,
+    pub bgp_id: u32,
+    pub routes: Vec<Route>,
+}
+
+fn peer_index_table(timestamp: SystemTime, collector_id: Ipv4Addr, peers: &[PeerTable]) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&collector_id.octets());
+    body.extend_from_slice(&0u16.to_be_bytes()); // View name length
+    body.extend_from_slice(&(peers.len() as u16).to_be_bytes());
+    for peer in peers {
+        let mut peer_type = PEER_TYPE_AS4;
+        if peer.address.is_ipv6() {
+            peer_type |= PEER_TYPE_IPV6;
+        }
+        body.push(peer_type);
+        body.extend_from_slice(&peer.bgp_id.to_be_bytes());
+        body.extend(address_octets(peer.address));
+        body.extend_from_slice(&(peer.asn as u32).to_be_bytes());
+    }
+    make_mrt_record(timestamp, TYPE_TABLE_DUMP_V2, SUBTYPE_PEER_INDEX_TABLE, body)
+}
+
+/// Path attributes of a RIB entry, AS_PATH always uses 4-byte AS numbers in TABLE_DUMP_V2
+fn compile_rib_attributes(attributes: &[PathAttribute]) -> Vec<u8> {
+    let attributes = attributes.iter().map(|attribute| {
+        if attribute.type_code != AttributeType::ASPath {
+            return attribute.clone();
+        }
+        let value = compile_as4_path(&extract_as_path(&attribute.value));
+        let mut flags: Vec<AttributeFlag> = attribute.flags.iter().copied().filter(|flag| *flag != AttributeFlag::ExtendedLength).collect();
+        if value.len() > u8::MAX as usize {
+            flags.push(AttributeFlag::ExtendedLength);
+        }
+        PathAttribute { type_code: AttributeType::ASPath, value, flags }
+    }).collect();
+    compile_path_attributes(attributes)
+}
+
+/// TABLE_DUMP_V2 snapshot: the peer index table followed by one RIB_IPV4_UNICAST record per
+/// prefix. Receive times are not tracked, entries carry the time of the dump.
+pub fn table_dump(timestamp: SystemTime, collector_id: Ipv4Addr, peers: &[PeerTable]) -> Vec<u8> {
+    let mut buf = peer_index_table(timestamp, collector_id, peers);
+    let mut entries: Vec<(usize, &Route)> = peers.iter().enumerate()
+        .flat_map(|(index, peer)| peer.routes.iter().map(move |route| (index, route)))
+        .collect();
+    entries.sort_by_key(|(index, route)| (u32::from_be_bytes(route.prefix.prefix), route.prefix.length, *index, route.path_id));
+
+    let originated = timestamp.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as u32;
+    for (sequence, prefix_entries) in entries.chunk_by(|(_, a), (_, b)| a.prefix == b.prefix).enumerate() {
+        let prefix = prefix_entries[0].1.prefix;
+        let mut body = Vec::new();
+        body.extend_from_slice(&(sequence as u32).to_be_bytes());
+        body.push(prefix.length);
+        body.extend_from_slice(&prefix.prefix[..(prefix.length as usize).div_ceil(8)]);
+        body.extend_from_slice(&(prefix_entries.len() as u16).to_be_bytes());
+        for (index, route) in prefix_entries {
+            let attributes = compile_rib_attributes(&route.path_attributes);
+            body.extend_from_slice(&(*index as u16).to_be_bytes());
+            body.extend_from_slice(&originated.to_be_bytes());
+            body.extend_from_slice(&(attributes.len() as u16).to_be_bytes());
+            body.extend(attributes);
+        }
+        buf.extend(make_mrt_record(timestamp, TYPE_TABLE_DUMP_V2, SUBTYPE_RIB_IPV4_UNICAST, body));
+    }
+    buf
+}
+
+/// Handle for sessions to pass records to the writer, does nothing if MRT is not configured.
+/// The queue is bounded so that a slow disk cannot make us buffer without limit, records that
+/// do not fit are dropped and counted.
+#[derive(Default)]
+pub struct MrtSender(Option<(mpsc::Sender<Vec<u8>>, Arc<AtomicU64>)>);
+
+/// The writer's end of the queue
+pub struct MrtRecords {
+    records: mpsc::Receiver<Vec<u8>>,
+    dropped: Arc<AtomicU64>,
+}
+
+/// Queue from the sessions to the writer
+pub fn queue() -> (MrtSender, MrtRecords) {
+    queue_with_capacity(MRT_QUEUE_SIZE)
+}
+
+fn queue_with_capacity(capacity: usize) -> (MrtSender, MrtRecords) {
+    let (sender, records) = mpsc::channel(capacity);
+    let dropped = Arc::new(AtomicU64::new(0));
+    (MrtSender(Some((sender, dropped.clone()))), MrtRecords { records, dropped })
+}
+
+impl MrtSender {
+    pub fn enabled(&self) -> bool {
+        self.0.is_some()
+    }
+
+    pub fn send(&self, record: Vec<u8>) {
+        if let Some((sender, dropped)) = &self.0 {
+            if let Err(mpsc::error::TrySendError::Full(_)) = sender.try_send(record) {
+                dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// Updates file, replaced by a new one once it exceeds the configured size or age
+struct UpdatesFile {
+    file: File,
+    opened: Instant,
+    size: u64,
+}
+
+fn file_name(directory: &std::path::Path, kind: &str) -> PathBuf {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    directory.join(format!("{}.{}.mrt", kind, timestamp))
+}
+
+async fn open_updates_file(config: &MrtConfig) -> std::io::Result<UpdatesFile> {
+    let path = file_name(&config.directory, "updates");
+    let file = OpenOptions::new().create(true).append(true).open(&path).await?;
+    info!(path = %path.display(), "Writing MRT updates");
+    Ok(UpdatesFile { file, opened: Instant::now(), size: 0 })
+}
+
+async fn write_record(config: &MrtConfig, updates: &mut Option<UpdatesFile>, record: &[u8]) -> std::io::Result<()> {
+    let rotate = updates.as_ref().is_some_and(|updates| {
+        config.rotate_size.is_some_and(|size| updates.size >= size)
+            || config.rotate_interval.is_some_and(|interval| updates.opened.elapsed() >= Duration::from_secs(interval))
+    });
+    if rotate || updates.is_none() {
+        *updates = Some(open_updates_file(config).await?);
+    }
+    let updates = updates.as_mut().unwrap();
+    updates.file.write_all(record).await?;
+    updates.file.flush().await?;
+    updates.size += record.len() as u64;
+    Ok(())
+}
+
+/// Collects the Adj-RIB-Ins of all sessions and writes them to a new RIB file
+async fn dump_table(config: &MrtConfig, collector_id: Ipv4Addr, shared: &Shared) -> std::io::Result<()> {
+    let peers = collect_tables(shared).await;
+    let path = file_name(&config.directory, "rib");
+    tokio::fs::write(&path, table_dump(SystemTime::now(), collector_id, &peers)).await?;
+    info!(neighbors = peers.len(), path = %path.display(), "Wrote MRT table dump");
+    Ok(())
+}
+
+/// Writes the sessions' records to the updates file and periodically dumps the table. Records
+/// dropped because the queue was full are reported with the next one written.
+pub async fn run(
+    config: MrtConfig,
+    collector_id: Ipv4Addr,
+    mut records: MrtRecords,
+    shared: Arc<Shared>,
+) {
+    let mut updates: Option<UpdatesFile> = None;
+    let mut table_dump = config.table_dump_interval.map(|interval| {
+        let interval = Duration::from_secs(interval);
+        tokio::time::interval_at(tokio::time::Instant::now() + interval, interval)
+    });
+    loop {
+        tokio::select! {
+            record = records.records.recv() => match record {
+                Some(record) => {
+                    let dropped = records.dropped.swap(0, Ordering::Relaxed);
+                    if dropped > 0 {
+                        warn!(dropped, "MRT records were dropped, the updates file is missing messages");
+                    }
+                    if let Err(e) = write_record(&config, &mut updates, &record).await {
+                        error!(error = %e, "Failed to write MRT record");
+                        updates = None;
+                    }
+                },
+                None => return,
+            },
+            _ = async { table_dump.as_mut().unwrap().tick().await }, if table_dump.is_some() => {
+                if let Err(e) = dump_table(&config, collector_id, &shared).await {
+                    error!(error = %e, "Failed to write MRT table dump");
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bgp::utils::path_attribute::extract_path_attributes;
+    use crate::config::Config;
+    use crate::trap::TrapTable;
+
+    fn timestamp() -> SystemTime {
+        UNIX_EPOCH + Duration::new(1_000_000, 5_000)
+    }
+
+    #[test]
+    fn test_bgp4mp_message() {
+        let endpoints = MessageEndpoints {
+            peer_as: 65001,
+            peer_address: "192.0.2.10".parse().unwrap(),
+            local_as: 65002,
+            local_address: "192.0.2.1".parse().unwrap(),
+        };
+        let keepalive = [0xFF; 19];
+        let record = bgp4mp_message(timestamp(), &endpoints, false, false, &keepalive);
+        assert_eq!(&record[..MRT_HEADER_SIZE], &[0, 0x0F, 0x42, 0x40, 0, 17, 0, 4, 0, 0, 0, 4 + 20 + 19]);
+        assert_eq!(&record[MRT_HEADER_SIZE..MRT_HEADER_SIZE + 24], &[
+            0, 0, 0, 5,
+            0, 0, 0xFD, 0xE9,
+            0, 0, 0xFD, 0xEA,
+            0, 0,
+            0, 1,
+            192, 0, 2, 10,
+            192, 0, 2, 1,
+        ]);
+        assert_eq!(&record[MRT_HEADER_SIZE + 24..], &keepalive[..]);
+        assert_eq!(bgp4mp_message(timestamp(), &endpoints, true, false, &keepalive)[7], 7);
+        assert_eq!(bgp4mp_message(timestamp(), &endpoints, false, true, &keepalive)[7], 9);
+        assert_eq!(bgp4mp_message(timestamp(), &endpoints, true, true, &keepalive)[7], 11);
+    }
+
+    #[test]
+    fn test_read_messages() {
+        let endpoints = MessageEndpoints {
+            peer_as: 65001,
+            peer_address: "2001:db8::10".parse().unwrap(),
+            local_as: 65002,
+            local_address: "2001:db8::1".parse().unwrap(),
+        };
+        let keepalive = [0xFF; 19];
+        let mut buf = bgp4mp_message(timestamp(), &endpoints, false, false, &keepalive);
+        buf.extend(bgp4mp_message(timestamp(), &endpoints, true, true, &keepalive));
+        // BGP4MP_MESSAGE with 2-byte AS numbers, no microseconds and an IPv4 peer
+        buf.extend_from_slice(&[0, 0x0F, 0x42, 0x40, 0, 16, 0, 1, 0, 0, 0, 16 + 19]);
+        buf.extend_from_slice(&[0x5B, 0xA0, 0xFD, 0xEA, 0, 0, 0, 1, 192, 0, 2, 10, 192, 0, 2, 1]);
+        buf.extend_from_slice(&keepalive);
+        // STATE_CHANGE records are skipped, records cut short are reported
+        buf.extend_from_slice(&[0, 0x0F, 0x42, 0x40, 0, 16, 0, 0, 0, 0, 0, 0]);
+        buf.extend_from_slice(&[0, 0x0F, 0x42, 0x40, 0, 16, 0, 4, 0, 0, 0, 8]);
+        buf.extend_from_slice(&[0, 0, 0xFD, 0xE9, 0, 0, 0xFD, 0xEA]);
+
+        let messages: Vec<_> = read_messages(&buf).collect();
+        assert_eq!(messages.len(), 4);
+        let received = messages[0].as_ref().unwrap();
+        assert_eq!(received.timestamp, timestamp());
+        assert_eq!(received.endpoints.peer_address, endpoints.peer_address);
+        assert_eq!((received.endpoints.peer_as, received.endpoints.local_as), (65001, 65002));
+        assert!(!received.sent && !received.add_path);
+        assert_eq!(received.message, keepalive);
+        assert!(messages[1].as_ref().unwrap().sent && messages[1].as_ref().unwrap().add_path);
+        let as2 = messages[2].as_ref().unwrap();
+        assert_eq!(as2.timestamp, UNIX_EPOCH + Duration::from_secs(1_000_000));
+        assert_eq!(as2.endpoints.peer_as, 23456);
+        assert_eq!(as2.endpoints.peer_address, "192.0.2.10".parse::<IpAddr>().unwrap());
+        assert_eq!(messages[3].as_ref().unwrap_err(), &MrtError::InvalidBgp4mp(buf.len() - 20));
+
+        assert_eq!(read_messages(&buf[..10]).collect::<Vec<_>>().pop().unwrap().unwrap_err(), MrtError::Truncated(0));
+    }
+
+    #[test]
+    fn test_table_dump() {
+        let route = |prefix: &str| Route {
+            prefix: prefix.parse().unwrap(),
+            path_id: 0,
+            path_attributes: vec![
+                PathAttribute { type_code: AttributeType::ASPath, value: vec![2, 1, 0xFD, 0xE9], flags: vec![AttributeFlag::Transitive] },
+            ],
+        };
+        let peers = vec![
+            PeerTable { address: "192.0.2.10".parse().unwrap(), asn: 65001, bgp_id: 1, routes: vec![route("10.0.0.2/32"), route("10.0.0.1/32")] },
+            PeerTable { address: "2001:db8::1".parse().unwrap(), asn: 65003, bgp_id: 2, routes: vec![route("10.0.0.1/32")] },
+        ];
+        let dump = table_dump(timestamp(), "192.0.2.1".parse().unwrap(), &peers);
+
+        let index_length = u32::from_be_bytes([dump[8], dump[9], dump[10], dump[11]]) as usize;
+        assert_eq!(&dump[4..8], &[0, 13, 0, 1]);
+        assert_eq!(index_length, 4 + 2 + 2 + (1 + 4 + 4 + 4) + (1 + 4 + 16 + 4));
+        assert_eq!(dump[MRT_HEADER_SIZE + 8], PEER_TYPE_AS4);
+        assert_eq!(dump[MRT_HEADER_SIZE + 21], PEER_TYPE_AS4 | PEER_TYPE_IPV6);
+
+        // 10.0.0.1/32 comes first, learned from both neighbors
+        let rib = &dump[MRT_HEADER_SIZE + index_length..];
+        assert_eq!(&rib[4..8], &[0, 13, 0, 2]);
+        assert_eq!(&rib[MRT_HEADER_SIZE..MRT_HEADER_SIZE + 11], &[0, 0, 0, 0, 32, 10, 0, 0, 1, 0, 2]);
+        let entry = &rib[MRT_HEADER_SIZE + 11..];
+        assert_eq!(&entry[..2], &[0, 0]);
+        let attributes_length = u16::from_be_bytes([entry[6], entry[7]]) as usize;
+        let attributes = extract_path_attributes(&entry[8..8 + attributes_length]).unwrap();
+        assert_eq!(attributes[0].value, vec![2, 1, 0, 0, 0xFD, 0xE9]);
+    }
+
+    #[tokio::test]
+    async fn test_run_without_table_dumps() {
+        let directory = std::env::temp_dir().join(format!("bgtrap-mrt-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let config = MrtConfig { directory: directory.clone(), rotate_size: None, rotate_interval: None, table_dump_interval: None };
+        let traps = TrapTable::new(&Config::default().trap);
+        let shared = Arc::new(Shared::new(Arc::new(Config::default()), traps));
+        let (sender, records) = queue();
+        let writer = tokio::spawn(run(config, "192.0.2.1".parse().unwrap(), records, shared));
+
+        sender.send(vec![1, 2, 3]);
+        drop(sender);
+        writer.await.unwrap();
+        let files: Vec<_> = std::fs::read_dir(&directory).unwrap()
+            .map(|entry| std::fs::read(entry.unwrap().path()).unwrap())
+            .collect();
+        std::fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(files, vec![vec![1, 2, 3]]);
+    }
+
+    #[tokio::test]
+    async fn test_queue_overflow() {
+        let (sender, mut records) = queue_with_capacity(1);
+        sender.send(vec![1]);
+        sender.send(vec![2]);
+        sender.send(vec![3]);
+        assert_eq!(records.dropped.load(Ordering::Relaxed), 2);
+
+        drop(sender);
+        assert_eq!(records.records.recv().await, Some(vec![1]));
+        assert_eq!(records.records.recv().await, None);
+    }
+}
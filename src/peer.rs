@@ -0,0 +1,837 @@
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+use tracing::{debug, info, warn};
+
+use crate::bgp::{AFI_IPV4, AFI_IPV6, SAFI_FLOWSPEC, SAFI_UNICAST};
+use crate::bgp::CodecOptions;
+use crate::bgp::capability::{AddPathFamily, Capability, GracefulRestartFamily};
+use crate::bgp::errors::BgpError;
+use crate::bgp::update::BGPUpdate;
+use crate::bmp::{BmpSender, PeerHeader};
+use crate::metrics::Metrics;
+use crate::mrt::{MessageEndpoints, MrtSender, PeerTable};
+use crate::bgp::utils::flowspec::FlowSpecRule;
+use crate::bgp::utils::path_attribute::AttributeType;
+use crate::bgp::utils::prefix::Prefix;
+use crate::config::{Config, NeighborConfig};
+use crate::policy::Decision;
+use crate::rib::{AdjRibIn, AdjRibOut, Route, updates_from_routes};
+use crate::route_reflector::{ReflectedRoutes, RouteReflector, is_reflection_loop};
+use crate::route_server::RouteServer;
+use crate::session::SessionRegistry;
+use crate::socket;
+use crate::trap::{TrapTable, flowspec_update, flowspec_withdrawal};
+
+/// Neighbors that are not allowed to reconnect, e.g. after exceeding their prefix limit or being
+/// removed from the configuration. Suspensions without an expiry last until they are lifted or
+/// the daemon is restarted.
+#[derive(Default)]
+pub struct SuspendedPeers(Mutex<HashMap<IpAddr, Option<Instant>>>);
+
+impl SuspendedPeers {
+    pub fn suspend(&self, address: IpAddr, duration: Option<Duration>) {
+        self.0.lock().unwrap().insert(address, duration.map(|duration| Instant::now() + duration));
+    }
+
+    /// Lifts a suspension, returns whether the neighbor was suspended
+    pub fn resume(&self, address: IpAddr) -> bool {
+        self.0.lock().unwrap().remove(&address).is_some()
+    }
+
+    pub fn is_suspended(&self, address: IpAddr) -> bool {
+        let mut suspended = self.0.lock().unwrap();
+        match suspended.get(&address) {
+            Some(Some(until)) if *until <= Instant::now() => {
+                suspended.remove(&address);
+                false
+            },
+            Some(_) => true,
+            None => false,
+        }
+    }
+}
+
+/// Routes of neighbors that restarted gracefully, kept as stale until the neighbor re-establishes
+/// the session or its restart time runs out (RFC 4724)
+#[derive(Default)]
+pub struct RetainedRibs(Mutex<HashMap<IpAddr, (AdjRibIn, Instant)>>);
+
+impl RetainedRibs {
+    pub fn retain(&self, address: IpAddr, mut rib: AdjRibIn, restart_time: Duration) {
+        rib.mark_stale();
+        self.0.lock().unwrap().insert(address, (rib, Instant::now() + restart_time));
+    }
+
+    /// Takes the retained routes of the neighbor, unless its restart time has already run out
+    pub fn take(&self, address: IpAddr) -> Option<AdjRibIn> {
+        match self.0.lock().unwrap().remove(&address) {
+            Some((rib, until)) if until > Instant::now() => Some(rib),
+            _ => None,
+        }
+    }
+}
+
+/// State shared by all sessions
+pub struct Shared {
+    /// Replaced when the configuration is reloaded
+    config: RwLock<Arc<Config>>,
+    /// File the configuration was loaded from, for reloading it
+    pub config_path: Option<PathBuf>,
+    pub traps: Mutex<TrapTable>,
+    pub suspended: SuspendedPeers,
+    pub retained: RetainedRibs,
+    pub route_server: RouteServer,
+    pub route_reflector: RouteReflector,
+    pub started: Instant,
+    pub bmp: BmpSender,
+    pub mrt: MrtSender,
+    pub metrics: Metrics,
+    pub sessions: SessionRegistry,
+}
+
+impl Shared {
+    pub fn new(config: Arc<Config>, traps: TrapTable) -> Shared {
+        Shared {
+            config: RwLock::new(config),
+            config_path: None,
+            traps: Mutex::new(traps),
+            suspended: SuspendedPeers::default(),
+            retained: RetainedRibs::default(),
+            route_server: RouteServer::default(),
+            route_reflector: RouteReflector::default(),
+            started: Instant::now(),
+            bmp: BmpSender::default(),
+            mrt: MrtSender::default(),
+            metrics: Metrics::default(),
+            sessions: SessionRegistry::default(),
+        }
+    }
+
+    pub fn config(&self) -> Arc<Config> {
+        self.config.read().unwrap().clone()
+    }
+
+    /// Replaces the configuration and returns the previous one
+    pub fn set_config(&self, config: Arc<Config>) -> Arc<Config> {
+        std::mem::replace(&mut *self.config.write().unwrap(), config)
+    }
+}
+
+/// Endpoints of the TCP connection to a neighbor
+#[derive(Debug, Clone, Copy)]
+pub struct Connection {
+    pub local: SocketAddr,
+    pub remote: SocketAddr,
+}
+
+/// State of a session with a single neighbor
+pub struct Peer {
+    pub shared: Arc<Shared>,
+    pub neighbor: NeighborConfig,
+    pub adj_rib_in: AdjRibIn,
+    pub adj_rib_out: AdjRibOut,
+    /// FlowSpec rules advertised to the neighbor
+    pub flowspec_out: Vec<FlowSpecRule>,
+    /// Capabilities from the neighbor's OPEN, empty until it has been received
+    pub remote_capabilities: Vec<Capability>,
+    /// Wire format negotiated with the neighbor's OPEN
+    pub codec_options: CodecOptions,
+    /// Set once KEEPALIVEs have been exchanged and the initial advertisement was sent
+    pub established: bool,
+    /// Address families (AFI, SAFI) the neighbor has sent its End-of-RIB marker for
+    pub end_of_rib_received: HashSet<(u16, u8)>,
+    pub connection: Option<Connection>,
+    /// AS and BGP identifier from the neighbor's OPEN
+    pub remote_as: u16,
+    pub remote_id: u32,
+    /// OPEN messages as exchanged on the wire, for BMP Peer Up
+    pub sent_open: Vec<u8>,
+    pub received_open: Vec<u8>,
+    /// NOTIFICATION that ended the session as received on the wire, for BMP Peer Down
+    pub received_notification: Option<Vec<u8>>,
+    /// Prefixes rejected by the import policy
+    pub rejected_prefixes: u32,
+    prefix_warning_logged: bool,
+}
+
+impl Peer {
+    pub fn new(address: IpAddr, shared: Arc<Shared>) -> Peer {
+        let adj_rib_in = shared.retained.take(address).unwrap_or_default();
+        Peer {
+            neighbor: shared.config().neighbor(address),
+            shared,
+            adj_rib_in,
+            adj_rib_out: AdjRibOut::default(),
+            flowspec_out: vec![],
+            remote_capabilities: vec![],
+            codec_options: CodecOptions::default(),
+            established: false,
+            end_of_rib_received: HashSet::new(),
+            connection: None,
+            remote_as: 0,
+            remote_id: 0,
+            sent_open: vec![],
+            received_open: vec![],
+            received_notification: None,
+            rejected_prefixes: 0,
+            prefix_warning_logged: false,
+        }
+    }
+
+    /// Capabilities advertised in our OPEN
+    pub fn local_capabilities(&self) -> Vec<Capability> {
+        let mut capabilities = vec![
+            Capability::Multiprotocol { afi: AFI_IPV4, safi: SAFI_UNICAST },
+            Capability::RouteRefresh,
+            Capability::EnhancedRouteRefresh,
+        ];
+        if self.neighbor.flowspec {
+            capabilities.push(Capability::Multiprotocol { afi: AFI_IPV4, safi: SAFI_FLOWSPEC });
+            capabilities.push(Capability::Multiprotocol { afi: AFI_IPV6, safi: SAFI_FLOWSPEC });
+        }
+        if let Some(graceful_restart) = &self.shared.config().graceful_restart {
+            // Restart State tells the neighbor to wait for our End-of-RIB, only while we are
+            // coming back up from a restart
+            let restart_time = graceful_restart.restart_time;
+            let restarting = self.shared.traps.lock().unwrap().restored()
+                && self.shared.started.elapsed() < Duration::from_secs(restart_time as u64);
+            capabilities.push(Capability::GracefulRestart {
+                restart_state: restarting,
+                restart_time,
+                families: vec![GracefulRestartFamily { afi: AFI_IPV4, safi: SAFI_UNICAST, forwarding_preserved: true }],
+            });
+        }
+        if let Some(add_path) = self.neighbor.add_path {
+            capabilities.push(Capability::AddPath(vec![AddPathFamily {
+                afi: AFI_IPV4,
+                safi: SAFI_UNICAST,
+                receive: add_path.receive(),
+                send: add_path.send(),
+            }]));
+        }
+        capabilities
+    }
+
+    fn add_path_family(capabilities: &[Capability]) -> Option<&AddPathFamily> {
+        capabilities.iter().find_map(|capability| match capability {
+            Capability::AddPath(families) => families.iter().find(|family| family.afi == AFI_IPV4 && family.safi == SAFI_UNICAST),
+            _ => None,
+        })
+    }
+
+    /// Path identifiers are received if we advertised receive and the neighbor advertised send
+    /// for IPv4 unicast, and sent in the opposite case
+    fn negotiate_codec_options(&self) -> CodecOptions {
+        let local_capabilities = self.local_capabilities();
+        match (Peer::add_path_family(&local_capabilities), Peer::add_path_family(&self.remote_capabilities)) {
+            (Some(local), Some(remote)) => CodecOptions {
+                add_path_receive: local.receive && remote.send,
+                add_path_send: local.send && remote.receive,
+            },
+            _ => CodecOptions::default(),
+        }
+    }
+
+    /// Address families we advertise routes for, each of them gets an End-of-RIB marker after the
+    /// initial advertisement
+    pub fn families(&self) -> Vec<(u16, u8)> {
+        self.local_capabilities().iter().filter_map(|capability| match capability {
+            Capability::Multiprotocol { afi, safi } => Some((*afi, *safi)),
+            _ => None,
+        }).collect()
+    }
+
+    /// Whether the neighbor has finished sending its initial table for the address family
+    pub fn initial_table_received(&self, afi: u16, safi: u8) -> bool {
+        self.end_of_rib_received.contains(&(afi, safi))
+    }
+
+    /// Whether both sides advertised the capability
+    pub fn negotiated(&self, capability: &Capability) -> bool {
+        self.remote_capabilities.contains(capability) && self.local_capabilities().contains(capability)
+    }
+
+    pub fn graceful_restart_negotiated(&self) -> bool {
+        self.shared.config().graceful_restart.is_some()
+            && self.remote_capabilities.iter().any(|capability| matches!(capability, Capability::GracefulRestart { .. }))
+    }
+
+    /// The neighbor's graceful restart time, if graceful restart was negotiated for IPv4 unicast
+    /// with the neighbor preserving its forwarding state
+    pub fn graceful_restart_time(&self) -> Option<Duration> {
+        if !self.graceful_restart_negotiated() {
+            return None;
+        }
+        self.remote_capabilities.iter().find_map(|capability| match capability {
+            Capability::GracefulRestart { restart_time, families, .. } if families.iter().any(|family| {
+                family.afi == AFI_IPV4 && family.safi == SAFI_UNICAST && family.forwarding_preserved
+            }) => Some(Duration::from_secs(*restart_time as u64)),
+            _ => None,
+        })
+    }
+
+    /// Called with the neighbor's OPEN. Routes retained from a previous session are only kept
+    /// if the neighbor still does graceful restart, otherwise they are dropped right away.
+    pub fn open_received(&mut self, capabilities: &[Capability]) {
+        self.remote_capabilities = capabilities.to_vec();
+        self.codec_options = self.negotiate_codec_options();
+        if self.graceful_restart_time().is_none() {
+            self.adj_rib_in.purge_stale();
+        }
+    }
+
+    /// Called when the session went down without a NOTIFICATION. The routes are retained as stale
+    /// if graceful restart was negotiated, returns when the neighbor's restart time runs out.
+    pub fn session_lost(self) -> Option<Instant> {
+        let restart_time = self.graceful_restart_time()?;
+        info!(routes = self.adj_rib_in.len(), ?restart_time, "Retaining routes for graceful restart");
+        self.shared.retained.retain(self.neighbor.address, self.adj_rib_in, restart_time);
+        Some(Instant::now() + restart_time)
+    }
+
+    /// Runs received routes through the neighbor's import policy into the Adj-RIB-In.
+    /// A rejected route also removes the previously accepted route for the same prefix.
+    pub fn import(&mut self, update: &BGPUpdate) -> Result<(), BgpError> {
+        for nlri in &update.withdrawn_routes {
+            self.adj_rib_in.remove(nlri);
+        }
+
+        let config = self.shared.config();
+        let policy = config.policy(&self.neighbor.import_policy);
+        let internal = self.internal();
+        for nlri in &update.network_layer_reachability_information {
+            let mut route = Route { prefix: nlri.prefix, path_id: nlri.path_id, path_attributes: update.path_attributes.clone() };
+            if let Some(next_hop) = route.next_hop() {
+                self.check_next_hop(next_hop)?;
+            }
+            if internal && is_reflection_loop(&route, config.router_id, config.cluster_id()) {
+                debug!(prefix = %nlri.prefix, "Ignoring route reflected back to us");
+                self.adj_rib_in.remove(nlri);
+                continue;
+            }
+            if self.neighbor.confederation_member && route.as_path().iter()
+                .any(|segment| segment.segment_type.is_confed() && segment.asns.contains(&config.local_as)) {
+                debug!(prefix = %nlri.prefix, "Ignoring route that already passed through our member AS");
+                self.adj_rib_in.remove(nlri);
+                continue;
+            }
+            let decision = policy.map_or(Decision::Accept, |policy| policy.apply(&mut route));
+            match decision {
+                Decision::Accept => self.adj_rib_in.insert(route),
+                Decision::Reject => {
+                    self.rejected_prefixes += 1;
+                    self.adj_rib_in.remove(nlri);
+                }
+            }
+        }
+
+        self.check_max_prefix()
+    }
+
+    /// Whether the neighbor is in our AS, known once its OPEN has been received
+    pub fn internal(&self) -> bool {
+        self.remote_as != 0 && self.remote_as == self.shared.config().local_as
+    }
+
+    /// Whether the neighbor is outside our AS and confederation
+    pub fn external(&self) -> bool {
+        !self.internal() && !self.neighbor.confederation_member
+    }
+
+    /// Our AS as the neighbor sees it, the confederation identifier for neighbors outside of it
+    pub fn local_as(&self) -> u16 {
+        let config = self.shared.config();
+        match config.confederation_id {
+            Some(confederation_id) if self.external() => confederation_id,
+            _ => config.local_as,
+        }
+    }
+
+    /// Our IPv4 address on the session, also when the neighbor connected to a dual-stack socket
+    fn local_ipv4(&self) -> Option<Ipv4Addr> {
+        match self.connection?.local.ip() {
+            IpAddr::V4(address) => Some(address),
+            IpAddr::V6(address) => address.to_ipv4_mapped(),
+        }
+    }
+
+    /// A received NEXT_HOP must not be our own address, unspecified or multicast
+    fn check_next_hop(&self, next_hop: Ipv4Addr) -> Result<(), BgpError> {
+        if next_hop.is_unspecified() || next_hop.is_multicast() || Some(next_hop) == self.local_ipv4() {
+            return Err(BgpError::InvalidNextHop { next_hop });
+        }
+        Ok(())
+    }
+
+    /// Subnet shared with a directly connected neighbor, where it can be given the address of
+    /// another router on the subnet as a third-party next hop
+    fn shared_subnet(&self, local: Ipv4Addr) -> Option<Prefix> {
+        if self.neighbor.ebgp_multihop.is_some() {
+            return None;
+        }
+        let subnet = socket::interface_subnet(local)?;
+        match self.neighbor.address {
+            IpAddr::V4(address) if subnet.contains(&host_prefix(address)) => Some(subnet),
+            _ => None,
+        }
+    }
+
+    /// Whether a route is advertised with our address as the next hop (RFC 4271 section 5.1.3).
+    /// iBGP neighbors, confederation members and route server clients get the next hop
+    /// unchanged, other eBGP neighbors only if it is on their subnet.
+    fn rewrites_next_hop(&self, route: &Route, subnet: Option<Prefix>) -> bool {
+        if self.neighbor.next_hop_self {
+            return true;
+        }
+        if !self.external() || self.neighbor.route_server_client {
+            return false;
+        }
+        !route.next_hop().zip(subnet).is_some_and(|(next_hop, subnet)| subnet.contains(&host_prefix(next_hop)))
+    }
+
+    /// Hands the Adj-RIB-In to the route server or route reflector to pass on to other neighbors
+    pub fn publish_routes(&self) {
+        if self.neighbor.route_server_client {
+            self.shared.route_server.update(self.neighbor.address, self.adj_rib_in.routes().cloned().collect());
+        }
+        if self.internal() && self.shared.config().route_reflector() {
+            self.shared.route_reflector.update(self.neighbor.address, ReflectedRoutes {
+                client: self.neighbor.route_reflector_client,
+                bgp_id: Ipv4Addr::from(self.remote_id),
+                routes: self.adj_rib_in.routes().cloned().collect(),
+            });
+        }
+    }
+
+    /// Routes to advertise to the neighbor before its export policy: the trap table, for route
+    /// server clients the best routes of the other clients and for iBGP neighbors the reflected
+    /// routes. Trapped prefixes take precedence. eBGP neighbors get our address as the next hop
+    /// unless the configured discard next hop is on their subnet.
+    pub fn routes(&self, traps: &TrapTable) -> Vec<Route> {
+        let config = self.shared.config();
+        let mut redistributed = Vec::new();
+        if self.neighbor.route_server_client {
+            redistributed = self.shared.route_server.routes_for(self.neighbor.address, self.remote_as, self.local_as());
+        } else if self.internal() && config.route_reflector() {
+            let bgp_id = Ipv4Addr::from(self.remote_id);
+            redistributed = self.shared.route_reflector.routes_for(self.neighbor.address, bgp_id, self.neighbor.route_reflector_client, config.cluster_id());
+        }
+        let mut routes = traps.routes();
+        let trapped = traps.prefixes();
+        routes.extend(redistributed.into_iter().filter(|route| !trapped.contains(&route.prefix)));
+        if let Some(local) = self.local_ipv4() {
+            let subnet = if self.external() && !self.neighbor.route_server_client { self.shared_subnet(local) } else { None };
+            for route in routes.iter_mut().filter(|route| self.rewrites_next_hop(route, subnet)) {
+                route.set_next_hop(local);
+            }
+        }
+        routes
+    }
+
+    fn check_max_prefix(&mut self) -> Result<(), BgpError> {
+        let max_prefix = match &self.neighbor.max_prefix {
+            Some(max_prefix) => max_prefix,
+            None => return Ok(()),
+        };
+        let count = self.adj_rib_in.len() as u32;
+        if count > max_prefix.limit {
+            self.shared.suspended.suspend(self.neighbor.address, max_prefix.restart_time());
+            return Err(BgpError::MaxPrefixExceeded { limit: max_prefix.limit });
+        }
+        if count >= max_prefix.warning_limit() {
+            if !self.prefix_warning_logged {
+                warn!(prefixes = count, limit = max_prefix.limit, "Approaching the prefix limit");
+                self.prefix_warning_logged = true;
+            }
+        } else {
+            self.prefix_warning_logged = false;
+        }
+        Ok(())
+    }
+
+    /// Adjusts a route for the kind of neighbor it is advertised to, before the export policy.
+    /// Confederation members get our member AS in an AS_CONFED_SEQUENCE. Other eBGP neighbors
+    /// never see the confederation segments and get our AS as they see it prepended, the
+    /// confederation identifier if there is one (RFC 5065 section 4.1), without LOCAL_PREF
+    /// (RFC 4271 section 5.1.5). Route server clients get the path unchanged (RFC 7947).
+    fn prepare_export(&self, route: &mut Route, config: &Config) {
+        if self.neighbor.confederation_member {
+            route.prepend_confed_sequence(config.local_as);
+        } else if self.external() && !self.neighbor.route_server_client {
+            route.remove_confed_segments();
+            route.prepend_as_path(&[self.local_as()]);
+            route.remove_attribute(AttributeType::LocalPref);
+        }
+    }
+
+    /// Runs routes through the neighbor's export policy into the Adj-RIB-Out and builds the
+    /// UPDATEs for the differences to what was advertised before
+    pub fn export(&mut self, routes: Vec<Route>) -> Vec<BGPUpdate> {
+        let config = self.shared.config();
+        let policy = config.policy(&self.neighbor.export_policy);
+        let exported: Vec<Route> = routes.into_iter().filter_map(|mut route| {
+            self.prepare_export(&mut route, &config);
+            match policy.map_or(Decision::Accept, |policy| policy.apply(&mut route)) {
+                Decision::Accept => Some(route),
+                Decision::Reject => None,
+            }
+        }).collect();
+        let (changed, withdrawn) = self.adj_rib_out.replace(exported);
+        let mut updates = Vec::new();
+        if !withdrawn.is_empty() {
+            updates.extend(BGPUpdate::withdraw(withdrawn).split(self.codec_options.add_path_send));
+        }
+        updates.extend(updates_from_routes(changed, self.codec_options.add_path_send));
+        updates
+    }
+
+    /// Per-peer header for BMP messages about this neighbor
+    pub fn bmp_header(&self, adj_rib_out: bool) -> PeerHeader {
+        PeerHeader {
+            address: self.neighbor.address,
+            asn: self.remote_as,
+            bgp_id: self.remote_id,
+            adj_rib_out,
+            timestamp: SystemTime::now(),
+        }
+    }
+
+    /// BGP FSM state of the session for logging. Connections are accepted passively, so sessions
+    /// start out in Active waiting for the neighbor's OPEN.
+    pub fn state(&self) -> &'static str {
+        if self.established {
+            "Established"
+        } else if self.received_open.is_empty() {
+            "Active"
+        } else {
+            "OpenConfirm"
+        }
+    }
+
+    /// Updates the neighbor's state and route counts in the metrics
+    pub fn report_metrics(&self) {
+        let metrics = &self.shared.metrics;
+        metrics.session_state(self.neighbor.address, self.state(), self.adj_rib_in.len(), self.adj_rib_out.len());
+        if self.remote_as != 0 {
+            metrics.update(self.neighbor.address, |metrics| metrics.remote_as = self.remote_as);
+        }
+    }
+
+    /// Both ends of the session for MRT records
+    pub fn mrt_endpoints(&self) -> MessageEndpoints {
+        let local_address = match (self.connection, self.neighbor.address) {
+            (Some(connection), _) => connection.local.ip(),
+            (None, IpAddr::V4(_)) => IpAddr::from([0; 4]),
+            (None, IpAddr::V6(_)) => IpAddr::from([0; 16]),
+        };
+        MessageEndpoints {
+            peer_as: self.remote_as,
+            peer_address: self.neighbor.address,
+            local_as: self.local_as(),
+            local_address,
+        }
+    }
+
+    /// The Adj-RIB-In for MRT table dumps
+    pub fn table(&self) -> PeerTable {
+        PeerTable {
+            address: self.neighbor.address,
+            asn: self.remote_as,
+            bgp_id: self.remote_id,
+            routes: self.adj_rib_in.routes().cloned().collect(),
+        }
+    }
+
+    /// Whether FlowSpec rules of the address family are exchanged with the neighbor
+    pub fn flowspec_negotiated(&self, afi: u16) -> bool {
+        self.negotiated(&Capability::Multiprotocol { afi, safi: SAFI_FLOWSPEC })
+    }
+
+    /// Builds the UPDATEs for the differences between `rules` and the FlowSpec rules advertised
+    /// before, leaving out address families not negotiated with the neighbor
+    pub fn export_flowspec(&mut self, rules: &[FlowSpecRule]) -> Vec<BGPUpdate> {
+        let exported: Vec<FlowSpecRule> = rules.iter().filter(|rule| self.flowspec_negotiated(rule.afi())).cloned().collect();
+        let previous = std::mem::replace(&mut self.flowspec_out, exported);
+        let mut updates = Vec::new();
+        for afi in [AFI_IPV4, AFI_IPV6] {
+            let withdrawn: Vec<FlowSpecRule> = previous.iter()
+                .filter(|rule| rule.afi() == afi && !self.flowspec_out.iter().any(|current| current.same_match(rule)))
+                .cloned()
+                .collect();
+            if !withdrawn.is_empty() {
+                updates.push(flowspec_withdrawal(afi, &withdrawn));
+            }
+        }
+        for rule in self.flowspec_out.iter().filter(|rule| !previous.contains(rule)) {
+            updates.push(flowspec_update(rule));
+        }
+        updates
+    }
+}
+
+fn host_prefix(address: Ipv4Addr) -> Prefix {
+    Prefix { length: 32, prefix: address.octets() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bgp::utils::as_path::AsPathDisplay;
+    use crate::bgp::utils::path_attribute::PathAttribute;
+    use crate::config::{AddPathMode, GracefulRestartConfig, MaxPrefixConfig};
+
+    fn update(prefixes: &[&str]) -> BGPUpdate {
+        BGPUpdate {
+            withdrawn_routes: vec![],
+            path_attributes: vec![],
+            network_layer_reachability_information: prefixes.iter().map(|prefix| prefix.parse::<Prefix>().unwrap().into()).collect(),
+        }
+    }
+
+    fn flowspec_rule(destination: &str) -> FlowSpecRule {
+        FlowSpecRule { destination: Some(destination.parse().unwrap()), ..Default::default() }
+    }
+
+    fn shared(config: Config) -> Arc<Shared> {
+        let traps = TrapTable::new(&config.trap);
+        Arc::new(Shared::new(Arc::new(config), traps))
+    }
+
+    #[test]
+    fn test_max_prefix() {
+        let shared = shared(Config::default());
+        let suspended = &shared.suspended;
+        let address: IpAddr = "192.0.2.10".parse().unwrap();
+        let mut peer = Peer::new(address, shared.clone());
+        peer.neighbor.max_prefix = Some(MaxPrefixConfig { limit: 2, warning_threshold: 50, restart_time: Some(3600) });
+
+        peer.import(&update(&["10.0.0.1/32", "10.0.0.2/32"])).unwrap();
+        assert!(peer.prefix_warning_logged);
+        // Re-announcing known prefixes does not count against the limit
+        peer.import(&update(&["10.0.0.1/32"])).unwrap();
+        assert!(!suspended.is_suspended(address));
+
+        let error = peer.import(&update(&["10.0.0.3/32"])).unwrap_err();
+        assert!(matches!(error, BgpError::MaxPrefixExceeded { limit: 2 }));
+        assert_eq!(error.notification().unwrap().data, vec![0, 1, 1, 0, 0, 0, 2]);
+        assert!(suspended.is_suspended(address));
+    }
+
+    #[test]
+    fn test_graceful_restart_retention() {
+        let shared = shared(Config {
+            graceful_restart: Some(GracefulRestartConfig { restart_time: 120 }),
+            ..Default::default()
+        });
+        let address: IpAddr = "192.0.2.10".parse().unwrap();
+        let graceful_restart = Capability::GracefulRestart {
+            restart_state: false,
+            restart_time: 60,
+            families: vec![GracefulRestartFamily { afi: AFI_IPV4, safi: SAFI_UNICAST, forwarding_preserved: true }],
+        };
+
+        let mut peer = Peer::new(address, shared.clone());
+        peer.open_received(std::slice::from_ref(&graceful_restart));
+        peer.import(&update(&["10.0.0.1/32", "10.0.0.2/32"])).unwrap();
+        peer.session_lost();
+
+        // Routes survive the restart until the neighbor's End-of-RIB purges those not re-advertised
+        let mut peer = Peer::new(address, shared.clone());
+        peer.open_received(&[graceful_restart]);
+        assert_eq!(peer.adj_rib_in.len(), 2);
+        peer.import(&update(&["10.0.0.2/32"])).unwrap();
+        assert_eq!(peer.adj_rib_in.purge_stale(), 1);
+        assert_eq!(peer.adj_rib_in.len(), 1);
+        peer.session_lost();
+
+        // Without graceful restart in the new OPEN the retained routes are dropped
+        let mut peer = Peer::new(address, shared);
+        assert_eq!(peer.adj_rib_in.len(), 1);
+        peer.open_received(&[]);
+        assert_eq!(peer.adj_rib_in.len(), 0);
+    }
+
+    #[test]
+    fn test_add_path_negotiation() {
+        let mut peer = Peer::new("192.0.2.10".parse().unwrap(), shared(Config::default()));
+        let remote = |receive, send| vec![Capability::AddPath(vec![AddPathFamily { afi: AFI_IPV4, safi: SAFI_UNICAST, receive, send }])];
+
+        peer.open_received(&remote(true, true));
+        assert!(!peer.codec_options.add_path_receive && !peer.codec_options.add_path_send);
+
+        peer.neighbor.add_path = Some(AddPathMode::Receive);
+        peer.open_received(&remote(true, true));
+        assert!(peer.codec_options.add_path_receive && !peer.codec_options.add_path_send);
+
+        peer.neighbor.add_path = Some(AddPathMode::Both);
+        peer.open_received(&remote(true, false));
+        assert!(!peer.codec_options.add_path_receive && peer.codec_options.add_path_send);
+    }
+
+    #[test]
+    fn test_suspension_expiry() {
+        let suspended = SuspendedPeers::default();
+        let address: IpAddr = "192.0.2.10".parse().unwrap();
+        suspended.suspend(address, Some(Duration::from_secs(0)));
+        assert!(!suspended.is_suspended(address));
+        suspended.suspend(address, None);
+        assert!(suspended.is_suspended(address));
+    }
+
+    #[test]
+    fn test_export_flowspec() {
+        let mut peer = Peer::new("192.0.2.10".parse().unwrap(), shared(Config::default()));
+        peer.neighbor.flowspec = true;
+        peer.open_received(&[Capability::Multiprotocol { afi: AFI_IPV4, safi: SAFI_FLOWSPEC }]);
+
+        let rules = vec![flowspec_rule("10.0.0.1/32"), flowspec_rule("2001:db8::1/128")];
+        assert_eq!(peer.export_flowspec(&rules).len(), 1);
+        assert_eq!(peer.flowspec_out, vec![flowspec_rule("10.0.0.1/32")]);
+        assert!(peer.export_flowspec(&rules).is_empty());
+
+        let updates = peer.export_flowspec(&[flowspec_rule("10.0.0.2/32")]);
+        assert_eq!(updates.len(), 2);
+        assert_eq!(updates[0].path_attributes[0].type_code, AttributeType::MpUnreachNlri);
+    }
+
+    #[test]
+    fn test_confederation_export() {
+        let shared = shared(Config { local_as: 65010, confederation_id: Some(65000), ..Default::default() });
+        let mut route = Route { prefix: "10.0.0.0/24".parse().unwrap(), path_id: 0, path_attributes: vec![] };
+        route.prepend_as_path(&[65001]);
+        route.prepend_confed_sequence(65011);
+
+        let mut member = Peer::new("192.0.2.10".parse().unwrap(), shared.clone());
+        member.neighbor.confederation_member = true;
+        member.remote_as = 65012;
+        assert_eq!(member.local_as(), 65010);
+        member.export(vec![route.clone()]);
+        assert_eq!(AsPathDisplay(&member.adj_rib_out.routes().next().unwrap().as_path()).to_string(), "(65010 65011) 65001");
+
+        let mut external = Peer::new("192.0.2.11".parse().unwrap(), shared.clone());
+        external.remote_as = 65001;
+        assert_eq!(external.local_as(), 65000);
+        external.export(vec![route.clone()]);
+        assert_eq!(AsPathDisplay(&external.adj_rib_out.routes().next().unwrap().as_path()).to_string(), "65000 65001");
+
+        // Route server clients get the path as the other client advertised it
+        let mut client = Peer::new("192.0.2.12".parse().unwrap(), shared);
+        client.neighbor.route_server_client = true;
+        client.remote_as = 65003;
+        client.export(vec![route]);
+        assert_eq!(AsPathDisplay(&client.adj_rib_out.routes().next().unwrap().as_path()).to_string(), "(65011) 65001");
+    }
+
+    #[test]
+    fn test_trap_export() {
+        let shared = shared(Config::default());
+        let traps = shared.traps.lock().unwrap().routes();
+
+        let mut internal = Peer::new("192.0.2.10".parse().unwrap(), shared.clone());
+        internal.remote_as = 65002;
+        internal.export(traps.clone());
+        let route = internal.adj_rib_out.routes().next().unwrap();
+        assert!(route.as_path().is_empty());
+        assert_eq!(route.local_pref(), Some(100));
+
+        let mut external = Peer::new("192.0.2.11".parse().unwrap(), shared);
+        external.remote_as = 65001;
+        external.export(traps);
+        let route = external.adj_rib_out.routes().next().unwrap();
+        assert_eq!(AsPathDisplay(&route.as_path()).to_string(), "65002");
+        assert_eq!(route.local_pref(), None);
+    }
+
+    #[test]
+    fn test_invalid_next_hop() {
+        let mut peer = Peer::new("192.0.2.10".parse().unwrap(), shared(Config::default()));
+        peer.connection = Some(Connection { local: "192.0.2.1:179".parse().unwrap(), remote: "192.0.2.10:50000".parse().unwrap() });
+        let with_next_hop = |next_hop: [u8; 4]| {
+            let mut update = update(&["10.0.0.1/32"]);
+            update.path_attributes = vec![PathAttribute::new(AttributeType::NextHop, next_hop.to_vec())];
+            update
+        };
+
+        peer.import(&with_next_hop([192, 0, 2, 20])).unwrap();
+        for next_hop in [[0, 0, 0, 0], [224, 0, 0, 5], [192, 0, 2, 1]] {
+            let error = peer.import(&with_next_hop(next_hop)).unwrap_err();
+            assert!(matches!(error, BgpError::InvalidNextHop { .. }));
+            let notification = error.notification().unwrap();
+            assert_eq!((notification.error_code, notification.error_subcode), (3, 8));
+            assert_eq!(notification.data[1..], [3, 4, next_hop[0], next_hop[1], next_hop[2], next_hop[3]]);
+        }
+        // Withdrawals carry no next hop
+        peer.import(&BGPUpdate::withdraw(vec!["10.0.0.1/32".parse::<Prefix>().unwrap().into()])).unwrap();
+    }
+
+    #[test]
+    fn test_next_hop_self() {
+        let mut client = NeighborConfig::new("192.0.2.11".parse().unwrap());
+        client.route_reflector_client = true;
+        let shared = shared(Config { local_as: 65002, neighbors: vec![client], ..Default::default() });
+        let mut route = Route { prefix: "10.0.0.0/24".parse().unwrap(), path_id: 0, path_attributes: vec![] };
+        route.set_next_hop(Ipv4Addr::new(192, 0, 2, 20));
+        shared.route_reflector.update("192.0.2.11".parse().unwrap(), ReflectedRoutes {
+            client: true,
+            bgp_id: Ipv4Addr::new(10, 0, 0, 11),
+            routes: vec![route],
+        });
+
+        let mut peer = Peer::new("192.0.2.10".parse().unwrap(), shared.clone());
+        peer.remote_as = 65002;
+        peer.connection = Some(Connection { local: "192.0.2.1:179".parse().unwrap(), remote: "192.0.2.10:50000".parse().unwrap() });
+        let traps = shared.traps.lock().unwrap();
+        let trap_next_hop = traps.routes()[0].next_hop();
+        let next_hops = |peer: &Peer| peer.routes(&traps).iter().map(|route| route.next_hop()).collect::<Vec<_>>();
+
+        // Traps and reflected routes keep their next hop
+        assert_eq!(next_hops(&peer), vec![trap_next_hop, Some(Ipv4Addr::new(192, 0, 2, 20))]);
+        peer.neighbor.next_hop_self = true;
+        assert_eq!(next_hops(&peer), vec![Some(Ipv4Addr::new(192, 0, 2, 1)); 2]);
+    }
+
+    #[test]
+    fn test_next_hop_rules() {
+        let shared = shared(Config { local_as: 65002, ..Default::default() });
+        let subnet = Some("192.0.2.0/24".parse().unwrap());
+        let mut route = Route { prefix: "10.0.0.0/24".parse().unwrap(), path_id: 0, path_attributes: vec![] };
+        route.set_next_hop(Ipv4Addr::new(192, 0, 2, 20));
+
+        let mut peer = Peer::new("192.0.2.10".parse().unwrap(), shared);
+        peer.remote_as = 65002;
+        assert!(!peer.rewrites_next_hop(&route, None));
+        peer.neighbor.next_hop_self = true;
+        assert!(peer.rewrites_next_hop(&route, None));
+
+        // eBGP neighbors keep a third-party next hop only on the shared subnet
+        peer.neighbor.next_hop_self = false;
+        peer.remote_as = 65001;
+        assert!(!peer.rewrites_next_hop(&route, subnet));
+        assert!(peer.rewrites_next_hop(&route, None));
+        route.set_next_hop(Ipv4Addr::new(198, 51, 100, 1));
+        assert!(peer.rewrites_next_hop(&route, subnet));
+        peer.neighbor.route_server_client = true;
+        assert!(!peer.rewrites_next_hop(&route, subnet));
+    }
+
+    #[test]
+    fn test_external_trap_next_hop() {
+        let mut config = Config::default();
+        config.trap.next_hop = Ipv4Addr::new(127, 0, 0, 5);
+        let shared = shared(config);
+        let traps = shared.traps.lock().unwrap();
+        let mut peer = Peer::new("127.0.0.2".parse().unwrap(), shared.clone());
+        peer.remote_as = 65001;
+        let next_hop = |peer: &Peer| peer.routes(&traps)[0].next_hop();
+
+        // The discard next hop is on the loopback subnet shared with the neighbor
+        peer.connection = Some(Connection { local: "127.0.0.1:179".parse().unwrap(), remote: "127.0.0.2:50000".parse().unwrap() });
+        assert_eq!(next_hop(&peer), Some(Ipv4Addr::new(127, 0, 0, 5)));
+        peer.neighbor.ebgp_multihop = Some(2);
+        assert_eq!(next_hop(&peer), Some(Ipv4Addr::LOCALHOST));
+    }
+}
@@ -0,0 +1,130 @@
+use std::convert::TryFrom;
+use std::net::Ipv4Addr;
+
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::bgp::utils::as_path::AsPathDisplay;
+use crate::bgp::utils::community::Community;
+use crate::bgp::utils::prefix::Prefix;
+use crate::policy::PolicyParseError;
+use crate::rib::Route;
+
+/// Prefix list entry in the form `10.0.0.0/8`, `10.0.0.0/8 le 24`, `10.0.0.0/8 ge 24 le 32`.
+/// Without `le`/`ge` only the exact prefix matches.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct PrefixMatch {
+    pub prefix: Prefix,
+    pub ge: Option<u8>,
+    pub le: Option<u8>,
+}
+
+impl PrefixMatch {
+    pub fn matches(&self, prefix: &Prefix) -> bool {
+        let min = self.ge.unwrap_or(self.prefix.length);
+        let max = match (self.ge, self.le) {
+            (_, Some(le)) => le,
+            (Some(_), None) => 32,
+            (None, None) => self.prefix.length,
+        };
+        self.prefix.contains(prefix) && prefix.length >= min && prefix.length <= max
+    }
+}
+
+impl TryFrom<String> for PrefixMatch {
+    type Error = PolicyParseError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        let error = || PolicyParseError::PrefixMatch(s.clone());
+        let mut words = s.split_whitespace();
+        let prefix: Prefix = words.next().ok_or_else(error)?.parse().map_err(|_| error())?;
+        let (mut ge, mut le) = (None, None);
+        while let Some(keyword) = words.next() {
+            let length: u8 = words.next().and_then(|length| length.parse().ok()).ok_or_else(error)?;
+            if length < prefix.length || length > 32 {
+                return Err(error());
+            }
+            match keyword {
+                "ge" => ge = Some(length),
+                "le" => le = Some(length),
+                _ => return Err(error()),
+            }
+        }
+        if let (Some(ge), Some(le)) = (ge, le) {
+            if ge > le {
+                return Err(error());
+            }
+        }
+        Ok(PrefixMatch { prefix, ge, le })
+    }
+}
+
+/// Regex matched against the AS path rendered as `65001 65002 {65003 65004}`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct AsPathRegex(pub Regex);
+
+impl TryFrom<String> for AsPathRegex {
+    type Error = PolicyParseError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        Regex::new(&s).map(AsPathRegex).map_err(|e| PolicyParseError::AsPathRegex(s, e))
+    }
+}
+
+impl PartialEq for AsPathRegex {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Origin {
+    Igp = 0,
+    Egp = 1,
+    Incomplete = 2,
+}
+
+/// All given conditions have to match. Where a condition takes a list, any entry matching is enough.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Conditions {
+    #[serde(default)]
+    pub prefix: Vec<PrefixMatch>,
+    pub as_path: Option<AsPathRegex>,
+    #[serde(default)]
+    pub community: Vec<Community>,
+    #[serde(default)]
+    pub next_hop: Vec<Ipv4Addr>,
+    pub origin: Option<Origin>,
+}
+
+impl Conditions {
+    pub fn matches(&self, route: &Route) -> bool {
+        if !self.prefix.is_empty() && !self.prefix.iter().any(|entry| entry.matches(&route.prefix)) {
+            return false;
+        }
+        if let Some(AsPathRegex(regex)) = &self.as_path {
+            if !regex.is_match(&AsPathDisplay(&route.as_path()).to_string()) {
+                return false;
+            }
+        }
+        if !self.community.is_empty() {
+            let communities = route.communities();
+            if !self.community.iter().any(|community| communities.contains(community)) {
+                return false;
+            }
+        }
+        if !self.next_hop.is_empty() && !route.next_hop().is_some_and(|next_hop| self.next_hop.contains(&next_hop)) {
+            return false;
+        }
+        if let Some(origin) = self.origin {
+            if route.origin() != Some(origin as u8) {
+                return false;
+            }
+        }
+        true
+    }
+}
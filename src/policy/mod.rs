@@ -0,0 +1,224 @@
+pub mod conditions;
+
+use std::net::Ipv4Addr;
+
+use serde::Deserialize;
+
+use crate::bgp::utils::community::Community;
+use crate::rib::Route;
+use conditions::Conditions;
+
+#[derive(thiserror::Error, Debug)]
+pub enum PolicyParseError {
+    #[error("Invalid prefix match '{0}', expected e.g. '10.0.0.0/8 ge 24 le 32'")]
+    PrefixMatch(String),
+    #[error("Invalid AS path regex '{0}': {1}")]
+    AsPathRegex(String, regex::Error),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Decision {
+    Accept,
+    #[default]
+    Reject,
+}
+
+/// Modifications applied to a route when a term matches. `action` ends evaluation of the policy.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Actions {
+    pub local_pref: Option<u32>,
+    pub med: Option<u32>,
+    #[serde(default)]
+    pub add_community: Vec<Community>,
+    #[serde(default)]
+    pub remove_community: Vec<Community>,
+    #[serde(default)]
+    pub prepend_as_path: Vec<u16>,
+    pub next_hop: Option<Ipv4Addr>,
+    pub action: Option<Decision>,
+}
+
+impl Actions {
+    fn apply(&self, route: &mut Route) {
+        if let Some(local_pref) = self.local_pref {
+            route.set_local_pref(local_pref);
+        }
+        if let Some(med) = self.med {
+            route.set_med(med);
+        }
+        if !self.add_community.is_empty() || !self.remove_community.is_empty() {
+            let mut communities = route.communities();
+            communities.retain(|community| !self.remove_community.contains(community));
+            for community in &self.add_community {
+                if !communities.contains(community) {
+                    communities.push(*community);
+                }
+            }
+            route.set_communities(&communities);
+        }
+        if !self.prepend_as_path.is_empty() {
+            route.prepend_as_path(&self.prepend_as_path);
+        }
+        if let Some(next_hop) = self.next_hop {
+            route.set_next_hop(next_hop);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Term {
+    #[serde(default)]
+    pub name: String,
+    #[serde(default, rename = "match")]
+    pub conditions: Conditions,
+    #[serde(default, rename = "then")]
+    pub actions: Actions,
+}
+
+/// Ordered list of terms. The first matching term with an `action` decides the fate of the route,
+/// terms without one only modify it. Routes falling through all terms get `default-action`.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Policy {
+    #[serde(default)]
+    pub default_action: Decision,
+    #[serde(default, rename = "term")]
+    pub terms: Vec<Term>,
+}
+
+impl Policy {
+    /// Evaluates the policy, modifying the route in place. Modifications made before a reject
+    /// are left in the route, callers are expected to drop it.
+    pub fn apply(&self, route: &mut Route) -> Decision {
+        for term in &self.terms {
+            if !term.conditions.matches(route) {
+                continue;
+            }
+            term.actions.apply(route);
+            if let Some(decision) = term.actions.action {
+                return decision;
+            }
+        }
+        self.default_action
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+    use crate::bgp::utils::as_path::{AsPathDisplay, SegmentType};
+    use crate::bgp::utils::path_attribute::{AttributeFlag, AttributeType, PathAttribute};
+    use crate::policy::conditions::{AsPathRegex, Origin, PrefixMatch};
+
+    fn as_path_value(asns: &[u16]) -> Vec<u8> {
+        let mut value = vec![SegmentType::Sequence as u8, asns.len() as u8];
+        asns.iter().for_each(|asn| value.extend_from_slice(&asn.to_be_bytes()));
+        value
+    }
+
+    fn route(prefix: &str, as_path: Vec<u16>, communities: &[Community]) -> Route {
+        let mut route = Route {
+            prefix: prefix.parse().unwrap(),
+            path_id: 0,
+            path_attributes: vec![
+                PathAttribute { type_code: AttributeType::Origin, value: vec![0], flags: vec![AttributeFlag::Transitive]},
+                PathAttribute { type_code: AttributeType::ASPath, value: as_path_value(&as_path), flags: vec![AttributeFlag::Transitive]},
+                PathAttribute { type_code: AttributeType::NextHop, value: vec![192, 168, 10, 1], flags: vec![AttributeFlag::Transitive]},
+            ],
+        };
+        route.set_communities(communities);
+        route
+    }
+
+    fn prefix_match(s: &str) -> PrefixMatch {
+        PrefixMatch::try_from(s.to_string()).unwrap()
+    }
+
+    #[test]
+    fn test_prefix_match() {
+        let exact = prefix_match("10.0.0.0/8");
+        assert!(exact.matches(&"10.0.0.0/8".parse().unwrap()));
+        assert!(!exact.matches(&"10.1.0.0/16".parse().unwrap()));
+
+        let le = prefix_match("10.0.0.0/8 le 24");
+        assert!(le.matches(&"10.0.0.0/8".parse().unwrap()));
+        assert!(le.matches(&"10.1.2.0/24".parse().unwrap()));
+        assert!(!le.matches(&"10.1.2.3/32".parse().unwrap()));
+
+        let ge = prefix_match("10.0.0.0/8 ge 24");
+        assert!(!ge.matches(&"10.1.0.0/16".parse().unwrap()));
+        assert!(ge.matches(&"10.1.2.3/32".parse().unwrap()));
+        assert!(!ge.matches(&"11.1.2.3/32".parse().unwrap()));
+
+        let range = prefix_match("10.0.0.0/8 ge 16 le 24");
+        assert!(range.matches(&"10.1.0.0/16".parse().unwrap()));
+        assert!(!range.matches(&"10.1.2.3/32".parse().unwrap()));
+
+        assert!(PrefixMatch::try_from("10.0.0.0/8 le 4".to_string()).is_err());
+        assert!(PrefixMatch::try_from("10.0.0.0/8 ge 24 le 16".to_string()).is_err());
+        assert!(PrefixMatch::try_from("10.0.0.0/8 eq 24".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_conditions() {
+        let rtbh = route("10.1.2.3/32", vec![65001, 65010], &[Community::new(65001, 666)]);
+
+        let conditions = Conditions {
+            prefix: vec![prefix_match("10.0.0.0/8 ge 32")],
+            as_path: Some(AsPathRegex::try_from("^65001( |$)".to_string()).unwrap()),
+            community: vec![Community::new(65001, 666), Community::new(65001, 667)],
+            next_hop: vec!["192.168.10.1".parse().unwrap()],
+            origin: Some(Origin::Igp),
+        };
+        assert!(conditions.matches(&rtbh));
+        assert!(!conditions.matches(&route("10.1.2.3/32", vec![65002, 65001], &[Community::new(65001, 666)])));
+        assert!(!conditions.matches(&route("10.1.2.3/32", vec![65001], &[])));
+        assert!(!conditions.matches(&route("10.1.2.0/24", vec![65001], &[Community::new(65001, 666)])));
+
+        let origin = Conditions { origin: Some(Origin::Incomplete), ..Default::default() };
+        assert!(!origin.matches(&rtbh));
+        assert!(Conditions::default().matches(&rtbh));
+    }
+
+    #[test]
+    fn test_policy_apply() {
+        let policy = Policy {
+            default_action: Decision::Reject,
+            terms: vec![
+                Term {
+                    name: "tag".to_string(),
+                    conditions: Conditions::default(),
+                    actions: Actions { remove_community: vec![Community::new(65001, 1)], ..Default::default() },
+                },
+                Term {
+                    name: "rtbh".to_string(),
+                    conditions: Conditions { community: vec![Community::new(65001, 666)], ..Default::default() },
+                    actions: Actions {
+                        local_pref: Some(200),
+                        med: Some(10),
+                        add_community: vec!["blackhole".parse().unwrap()],
+                        prepend_as_path: vec![65002, 65002],
+                        next_hop: Some("192.0.2.1".parse().unwrap()),
+                        action: Some(Decision::Accept),
+                        ..Default::default()
+                    },
+                },
+            ],
+        };
+
+        let mut accepted = route("10.1.2.3/32", vec![65001], &[Community::new(65001, 1), Community::new(65001, 666)]);
+        assert_eq!(policy.apply(&mut accepted), Decision::Accept);
+        assert_eq!(accepted.attribute(AttributeType::LocalPref).unwrap().value, vec![0, 0, 0, 200]);
+        assert_eq!(accepted.attribute(AttributeType::MultiExitDisc).unwrap().value, vec![0, 0, 0, 10]);
+        assert_eq!(accepted.communities(), vec![Community::new(65001, 666), Community::new(65535, 666)]);
+        assert_eq!(AsPathDisplay(&accepted.as_path()).to_string(), "65002 65002 65001");
+        assert_eq!(accepted.next_hop(), Some("192.0.2.1".parse().unwrap()));
+
+        let mut rejected = route("10.1.2.3/32", vec![65001], &[]);
+        assert_eq!(policy.apply(&mut rejected), Decision::Reject);
+    }
+}
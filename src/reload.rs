@@ -0,0 +1,190 @@
+//! Reloading the configuration file while the daemon is running. Neighbors and policies take
+//! effect right away, sessions are only reset when settings negotiated in the OPEN or applied to
+//! the socket changed.
+
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use tokio::sync::broadcast;
+use tracing::{info, warn};
+
+use crate::config::{Config, ConfigError, NeighborConfig};
+use crate::peer::Shared;
+use crate::session::SessionCommand;
+
+/// What a reload means for the session with a neighbor
+#[derive(Debug, PartialEq)]
+pub enum NeighborChange {
+    Unchanged,
+    /// The `[[neighbor]]` section was removed
+    Removed,
+    /// Settings that only apply to a new session changed
+    Reset,
+    /// Settings were changed that are applied to the running session, routes have to go
+    /// through the import or export policy again if it changed
+    Update { import: bool, export: bool },
+}
+
+fn session_settings_changed(previous: &NeighborConfig, current: &NeighborConfig) -> bool {
+    previous.remote_as != current.remote_as
+        || previous.add_path != current.add_path
+        || previous.flowspec != current.flowspec
+        || previous.password != current.password
+        || previous.ttl_security != current.ttl_security
+        || previous.ebgp_multihop != current.ebgp_multihop
+        || previous.route_server_client != current.route_server_client
+        || previous.route_reflector_client != current.route_reflector_client
+        || previous.confederation_member != current.confederation_member
+}
+
+fn configured(config: &Config, address: IpAddr) -> bool {
+    config.neighbors.iter().any(|neighbor| neighbor.address == address)
+}
+
+pub fn neighbor_change(address: IpAddr, previous: &Config, current: &Config) -> NeighborChange {
+    if configured(previous, address) && !configured(current, address) {
+        return NeighborChange::Removed;
+    }
+    let (before, after) = (previous.neighbor(address), current.neighbor(address));
+    if session_settings_changed(&before, &after) {
+        return NeighborChange::Reset;
+    }
+    let import = before.import_policy != after.import_policy
+        || previous.policy(&before.import_policy) != current.policy(&after.import_policy);
+    let export = before.export_policy != after.export_policy
+        || before.next_hop_self != after.next_hop_self
+        || previous.policy(&before.export_policy) != current.policy(&after.export_policy);
+    if before == after && !import && !export {
+        NeighborChange::Unchanged
+    } else {
+        NeighborChange::Update { import, export }
+    }
+}
+
+/// Sections that are only read at startup and differ between the configurations
+fn restart_required(previous: &Config, current: &Config) -> Vec<&'static str> {
+    let mut sections = Vec::new();
+    if previous.listen != current.listen {
+        sections.push("listen");
+    }
+    if (previous.local_as, previous.router_id, previous.hold_time) != (current.local_as, current.router_id, current.hold_time) {
+        sections.push("local-as, router-id and hold-time");
+    }
+    if (previous.cluster_id, previous.confederation_id) != (current.cluster_id, current.confederation_id) {
+        sections.push("cluster-id and confederation-id");
+    }
+    if previous.trap != current.trap {
+        sections.push("trap");
+    }
+    if previous.graceful_restart != current.graceful_restart {
+        sections.push("graceful-restart");
+    }
+    if previous.control_socket != current.control_socket {
+        sections.push("control-socket");
+    }
+    if previous.bmp != current.bmp {
+        sections.push("bmp");
+    }
+    if previous.mrt != current.mrt {
+        sections.push("mrt");
+    }
+    if previous.metrics != current.metrics {
+        sections.push("metrics");
+    }
+    if previous.log != current.log {
+        sections.push("log");
+    }
+    sections
+}
+
+/// Keeps removed neighbors from reconnecting, as any other address would get a session with the
+/// default settings. Neighbors that are configured again are accepted once more.
+fn suspend_removed_neighbors(shared: &Shared, previous: &Config) {
+    let current = shared.config();
+    for neighbor in &previous.neighbors {
+        if !configured(&current, neighbor.address) {
+            shared.suspended.suspend(neighbor.address, None);
+            info!(peer = %neighbor.address, "Neighbor removed, refusing its connections");
+        }
+    }
+    for neighbor in &current.neighbors {
+        if !configured(previous, neighbor.address) && shared.suspended.resume(neighbor.address) {
+            info!(peer = %neighbor.address, "Neighbor added, accepting its connections again");
+        }
+    }
+}
+
+/// Reads the configuration file again and applies its neighbors and policies. Every session
+/// works out what changed for its neighbor from the previous configuration it is sent.
+pub fn reload(shared: &Shared, commands: &broadcast::Sender<SessionCommand>) -> Result<(), ConfigError> {
+    let path = shared.config_path.as_ref()
+        .ok_or_else(|| ConfigError::Invalid("running without a configuration file".to_string()))?;
+    let loaded = Config::load(path)?;
+    let running = shared.config();
+    for section in restart_required(&running, &loaded) {
+        warn!(section, "Changes take effect after a restart");
+    }
+    let config = Config { neighbors: loaded.neighbors, policies: loaded.policies, ..Config::clone(&running) };
+    let previous = shared.set_config(Arc::new(config));
+    suspend_removed_neighbors(shared, &previous);
+    info!(path = %path.display(), "Configuration reloaded");
+    let _ = commands.send(SessionCommand::ConfigReloaded(previous));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(toml: &str) -> Config {
+        toml::from_str(toml).unwrap()
+    }
+
+    #[test]
+    fn test_neighbor_change() {
+        let previous = config(r#"
+            local-as = 65002
+            router-id = "192.0.2.1"
+
+            [[neighbor]]
+            address = "192.0.2.10"
+            export-policy = "host-routes"
+
+            [[neighbor]]
+            address = "192.0.2.11"
+            remote-as = 65001
+
+            [[neighbor]]
+            address = "192.0.2.12"
+
+            [policy.host-routes]
+            default-action = "reject"
+        "#);
+        let current = config(r#"
+            local-as = 65002
+            router-id = "192.0.2.1"
+
+            [[neighbor]]
+            address = "192.0.2.10"
+            export-policy = "host-routes"
+
+            [[neighbor]]
+            address = "192.0.2.11"
+            remote-as = 65003
+
+            [[neighbor]]
+            address = "192.0.2.13"
+            max-prefix = { limit = 10 }
+
+            [policy.host-routes]
+            default-action = "accept"
+        "#);
+        let change = |address: &str| neighbor_change(address.parse().unwrap(), &previous, &current);
+        assert_eq!(change("192.0.2.10"), NeighborChange::Update { import: false, export: true });
+        assert_eq!(change("192.0.2.11"), NeighborChange::Reset);
+        assert_eq!(change("192.0.2.12"), NeighborChange::Removed);
+        assert_eq!(change("192.0.2.13"), NeighborChange::Update { import: false, export: false });
+        assert_eq!(change("192.0.2.14"), NeighborChange::Unchanged);
+        assert_eq!(restart_required(&previous, &current), Vec::<&str>::new());
+    }
+}
@@ -0,0 +1,198 @@
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+
+use tracing::{error, info, info_span, warn};
+
+use crate::bgp::{BGPMessage, CodecOptions};
+use crate::mrt::{RecordedMessage, read_messages};
+use crate::peer::{Peer, Shared};
+use crate::session::handle_update;
+
+/// Counters of a neighbor whose messages were replayed
+#[derive(Debug, Default, PartialEq)]
+pub struct ReplayStatistics {
+    pub messages: usize,
+    pub updates: usize,
+    /// Messages the decoder could not handle
+    pub malformed: usize,
+    /// Sessions ended by a NOTIFICATION or an error from the import pipeline
+    pub resets: usize,
+}
+
+struct ReplayedNeighbor {
+    peer: Peer,
+    statistics: ReplayStatistics,
+}
+
+/// Feeds recorded messages through decoding and the import policies of the configured
+/// neighbors, as if they had been received on a session
+pub struct Replay {
+    shared: Arc<Shared>,
+    neighbors: BTreeMap<IpAddr, ReplayedNeighbor>,
+}
+
+impl Replay {
+    pub fn new(shared: Arc<Shared>) -> Replay {
+        Replay { shared, neighbors: BTreeMap::new() }
+    }
+
+    /// Handles a single recorded message, messages sent by the recording router are ignored
+    pub fn message(&mut self, recorded: &RecordedMessage) {
+        if recorded.sent {
+            return;
+        }
+        let address = recorded.endpoints.peer_address;
+        let shared = &self.shared;
+        let neighbor = self.neighbors.entry(address).or_insert_with(|| ReplayedNeighbor {
+            peer: Peer::new(address, shared.clone()),
+            statistics: ReplayStatistics::default(),
+        });
+        let _span = info_span!("replay", peer = %address).entered();
+        neighbor.statistics.messages += 1;
+        neighbor.peer.remote_as = recorded.endpoints.peer_as;
+
+        let options = CodecOptions { add_path_receive: recorded.add_path, ..CodecOptions::default() };
+        let message = match BGPMessage::decode(&recorded.message, options) {
+            Ok(message) => message,
+            Err(e) => {
+                let recorded_at = recorded.timestamp.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+                warn!(index = neighbor.statistics.messages, recorded_at, error = %e, "Could not decode message");
+                neighbor.statistics.malformed += 1;
+                return;
+            },
+        };
+        let result = match message {
+            BGPMessage::Open(open) => {
+                neighbor.peer.remote_id = open.bgp_id;
+                neighbor.peer.open_received(&open.capabilities);
+                Ok(())
+            },
+            BGPMessage::Update(update) => {
+                neighbor.statistics.updates += 1;
+                handle_update(&update, &mut neighbor.peer)
+            },
+            BGPMessage::Notification(notification) => {
+                info!(code = notification.error_code, subcode = notification.error_subcode, "NOTIFICATION received");
+                neighbor.peer = Peer::new(address, shared.clone());
+                neighbor.statistics.resets += 1;
+                return;
+            },
+            BGPMessage::Keepalive(_) | BGPMessage::RouteRefresh(_) => Ok(()),
+        };
+        if let Err(e) = result {
+            warn!(error = %e, "Session would be closed");
+            neighbor.peer = Peer::new(address, shared.clone());
+            neighbor.statistics.resets += 1;
+        }
+    }
+
+    /// State of every neighbor seen in the recording, ordered by address
+    pub fn neighbors(&self) -> impl Iterator<Item = (&Peer, &ReplayStatistics)> {
+        self.neighbors.values().map(|neighbor| (&neighbor.peer, &neighbor.statistics))
+    }
+}
+
+/// Replays an MRT file and prints a summary per neighbor. Unreadable records are reported and
+/// skipped.
+pub fn run(path: &Path, shared: Arc<Shared>) -> std::io::Result<()> {
+    let buf = std::fs::read(path)?;
+    let mut replay = Replay::new(shared);
+    for recorded in read_messages(&buf) {
+        match recorded {
+            Ok(recorded) => replay.message(&recorded),
+            Err(e) => error!(path = %path.display(), error = %e, "Failed to read MRT record"),
+        }
+    }
+    for (peer, statistics) in replay.neighbors() {
+        info!(
+            peer = %peer.neighbor.address,
+            messages = statistics.messages,
+            updates = statistics.updates,
+            malformed = statistics.malformed,
+            resets = statistics.resets,
+            routes_accepted = peer.adj_rib_in.len(),
+            prefixes_rejected = peer.rejected_prefixes,
+            "Replay finished",
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::SystemTime;
+    use crate::bgp::update::BGPUpdate;
+    use crate::bgp::utils::prefix::Prefix;
+    use crate::config::Config;
+    use crate::mrt::{MessageEndpoints, bgp4mp_message};
+    use crate::trap::TrapTable;
+
+    fn update(prefixes: &[&str]) -> Vec<u8> {
+        update_with(prefixes, CodecOptions::default())
+    }
+
+    fn update_with(prefixes: &[&str], options: CodecOptions) -> Vec<u8> {
+        BGPMessage::Update(BGPUpdate {
+            withdrawn_routes: vec![],
+            path_attributes: vec![],
+            network_layer_reachability_information: prefixes.iter().map(|prefix| prefix.parse::<Prefix>().unwrap().into()).collect(),
+        }).encode(options)
+    }
+
+    #[test]
+    fn test_replay() {
+        let config: Config = toml::from_str(r#"
+            local-as = 65002
+            router-id = "192.0.2.1"
+
+            [[neighbor]]
+            address = "192.0.2.10"
+            import-policy = "host-routes"
+
+            [policy.host-routes]
+            default-action = "reject"
+
+            [[policy.host-routes.term]]
+            match = { prefix = ["0.0.0.0/0 ge 32"] }
+            then = { action = "accept" }
+        "#).unwrap();
+        let traps = TrapTable::new(&config.trap);
+        let shared = Arc::new(Shared::new(Arc::new(config), traps));
+        let endpoints = |peer_address: &str| MessageEndpoints {
+            peer_as: 65001,
+            peer_address: peer_address.parse().unwrap(),
+            local_as: 65002,
+            local_address: "192.0.2.1".parse().unwrap(),
+        };
+
+        let mut buf = bgp4mp_message(SystemTime::now(), &endpoints("192.0.2.10"), false, false, &update(&["10.0.0.1/32", "10.1.0.0/16"]));
+        buf.extend(bgp4mp_message(SystemTime::now(), &endpoints("192.0.2.10"), true, false, &update(&["10.0.0.2/32"])));
+        buf.extend(bgp4mp_message(SystemTime::now(), &endpoints("192.0.2.11"), false, false, &update(&["10.1.0.0/16"])));
+        let mut truncated = update(&["10.0.0.3/32"]);
+        truncated.pop();
+        buf.extend(bgp4mp_message(SystemTime::now(), &endpoints("192.0.2.11"), false, false, &truncated));
+        // Path identifiers are expected as the record's subtype says
+        let add_path = CodecOptions { add_path_send: true, ..CodecOptions::default() };
+        buf.extend(bgp4mp_message(SystemTime::now(), &endpoints("192.0.2.12"), false, true, &update_with(&["10.2.0.0/16"], add_path)));
+
+        let mut replay = Replay::new(shared);
+        for recorded in read_messages(&buf) {
+            replay.message(&recorded.unwrap());
+        }
+        let neighbors: Vec<_> = replay.neighbors().collect();
+        assert_eq!(neighbors.len(), 3);
+        let (filtered, statistics) = neighbors[0];
+        assert_eq!((filtered.adj_rib_in.len(), filtered.rejected_prefixes), (1, 1));
+        assert_eq!(statistics, &ReplayStatistics { messages: 1, updates: 1, malformed: 0, resets: 0 });
+        let (unfiltered, statistics) = neighbors[1];
+        assert_eq!(unfiltered.adj_rib_in.len(), 1);
+        assert_eq!(statistics, &ReplayStatistics { messages: 2, updates: 1, malformed: 1, resets: 0 });
+        let (add_path, statistics) = neighbors[2];
+        assert_eq!(add_path.adj_rib_in.len(), 1);
+        assert_eq!(statistics.malformed, 0);
+    }
+}
@@ -0,0 +1,424 @@
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::net::Ipv4Addr;
+
+use crate::bgp::update::BGPUpdate;
+use crate::bgp::utils::as_path::{AsPathSegment, SegmentType, compile_as_path, extract_as_path};
+use crate::bgp::utils::community::{Community, compile_communities, extract_communities};
+use crate::bgp::utils::path_attribute::{AttributeFlag, AttributeType, PathAttribute};
+use crate::bgp::utils::prefix::{Nlri, Prefix};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Route {
+    pub prefix: Prefix,
+    /// ADD-PATH path identifier, 0 unless ADD-PATH is used with the neighbor
+    pub path_id: u32,
+    pub path_attributes: Vec<PathAttribute>,
+}
+
+impl Route {
+    /// Routes are identified by their prefix and path identifier
+    pub fn nlri(&self) -> Nlri {
+        Nlri { path_id: self.path_id, prefix: self.prefix }
+    }
+
+    pub fn attribute(&self, type_code: AttributeType) -> Option<&PathAttribute> {
+        self.path_attributes.iter().find(|attribute| attribute.type_code == type_code)
+    }
+
+    /// Replaces an existing attribute of the same type or appends a new one
+    pub fn set_attribute(&mut self, attribute: PathAttribute) {
+        match self.path_attributes.iter_mut().find(|existing| existing.type_code == attribute.type_code) {
+            Some(existing) => *existing = attribute,
+            None => self.path_attributes.push(attribute),
+        }
+    }
+
+    pub fn remove_attribute(&mut self, type_code: AttributeType) {
+        self.path_attributes.retain(|attribute| attribute.type_code != type_code);
+    }
+
+    pub fn origin(&self) -> Option<u8> {
+        self.attribute(AttributeType::Origin).and_then(|attribute| attribute.value.first().copied())
+    }
+
+    pub fn as_path(&self) -> Vec<AsPathSegment> {
+        self.attribute(AttributeType::ASPath).map(|attribute| extract_as_path(&attribute.value)).unwrap_or_default()
+    }
+
+    pub fn set_as_path(&mut self, segments: &[AsPathSegment]) {
+        let value = compile_as_path(segments);
+        let mut flags = vec![AttributeFlag::Transitive];
+        if value.len() > u8::MAX as usize {
+            flags.push(AttributeFlag::ExtendedLength);
+        }
+        self.set_attribute(PathAttribute { type_code: AttributeType::ASPath, value, flags });
+    }
+
+    /// Prepends to the leading AS_SEQUENCE, or adds a new one if the path starts with another
+    /// type of segment
+    pub fn prepend_as_path(&mut self, asns: &[u16]) {
+        self.prepend_segment(SegmentType::Sequence, asns);
+    }
+
+    /// Prepends our member AS when advertising to another member AS of the confederation
+    pub fn prepend_confed_sequence(&mut self, asn: u16) {
+        self.prepend_segment(SegmentType::ConfedSequence, &[asn]);
+    }
+
+    fn prepend_segment(&mut self, segment_type: SegmentType, asns: &[u16]) {
+        let mut segments = self.as_path();
+        match segments.first_mut() {
+            Some(segment) if segment.segment_type == segment_type && segment.asns.len() + asns.len() <= u8::MAX as usize => {
+                segment.asns.splice(0..0, asns.iter().copied());
+            },
+            _ => segments.insert(0, AsPathSegment { segment_type, asns: asns.to_vec() }),
+        }
+        self.set_as_path(&segments);
+    }
+
+    /// Removes the confederation segments when advertising outside the confederation
+    pub fn remove_confed_segments(&mut self) {
+        let mut segments = self.as_path();
+        if segments.iter().any(|segment| segment.segment_type.is_confed()) {
+            segments.retain(|segment| !segment.segment_type.is_confed());
+            self.set_as_path(&segments);
+        }
+    }
+
+    pub fn next_hop(&self) -> Option<Ipv4Addr> {
+        self.attribute(AttributeType::NextHop)
+            .filter(|attribute| attribute.value.len() == 4)
+            .map(|attribute| Ipv4Addr::new(attribute.value[0], attribute.value[1], attribute.value[2], attribute.value[3]))
+    }
+
+    pub fn set_next_hop(&mut self, next_hop: Ipv4Addr) {
+        self.set_attribute(PathAttribute {
+            type_code: AttributeType::NextHop,
+            value: next_hop.octets().to_vec(),
+            flags: vec![AttributeFlag::Transitive],
+        });
+    }
+
+    /// BGP identifier of the router that originated the route into the AS (RFC 4456)
+    pub fn originator_id(&self) -> Option<Ipv4Addr> {
+        self.u32_attribute(AttributeType::OriginatorId).map(Ipv4Addr::from)
+    }
+
+    pub fn set_originator_id(&mut self, originator_id: Ipv4Addr) {
+        self.set_attribute(PathAttribute::new(AttributeType::OriginatorId, originator_id.octets().to_vec()));
+    }
+
+    /// Cluster IDs of the route reflectors the route passed through, the most recent first
+    pub fn cluster_list(&self) -> Vec<Ipv4Addr> {
+        self.attribute(AttributeType::ClusterList)
+            .map(|attribute| attribute.value.chunks_exact(4).map(|id| Ipv4Addr::new(id[0], id[1], id[2], id[3])).collect())
+            .unwrap_or_default()
+    }
+
+    pub fn prepend_cluster_list(&mut self, cluster_id: Ipv4Addr) {
+        let mut value = cluster_id.octets().to_vec();
+        value.extend(self.cluster_list().iter().flat_map(|id| id.octets()));
+        self.set_attribute(PathAttribute::new(AttributeType::ClusterList, value));
+    }
+
+    pub fn communities(&self) -> Vec<Community> {
+        self.attribute(AttributeType::Communities).map(|attribute| extract_communities(&attribute.value)).unwrap_or_default()
+    }
+
+    pub fn set_communities(&mut self, communities: &[Community]) {
+        if communities.is_empty() {
+            self.remove_attribute(AttributeType::Communities);
+            return;
+        }
+        self.set_attribute(PathAttribute {
+            type_code: AttributeType::Communities,
+            value: compile_communities(communities),
+            flags: vec![AttributeFlag::Optional, AttributeFlag::Transitive],
+        });
+    }
+
+    /// Length of the AS path in the decision process, an AS_SET counts as one AS and
+    /// confederation segments do not count (RFC 5065 section 5.3)
+    pub fn as_path_length(&self) -> usize {
+        self.as_path().iter().map(|segment| match segment.segment_type {
+            SegmentType::Sequence => segment.asns.len(),
+            SegmentType::Set => 1,
+            SegmentType::ConfedSequence | SegmentType::ConfedSet => 0,
+        }).sum()
+    }
+
+    fn u32_attribute(&self, type_code: AttributeType) -> Option<u32> {
+        self.attribute(type_code)
+            .filter(|attribute| attribute.value.len() == 4)
+            .map(|attribute| u32::from_be_bytes([attribute.value[0], attribute.value[1], attribute.value[2], attribute.value[3]]))
+    }
+
+    pub fn local_pref(&self) -> Option<u32> {
+        self.u32_attribute(AttributeType::LocalPref)
+    }
+
+    pub fn med(&self) -> Option<u32> {
+        self.u32_attribute(AttributeType::MultiExitDisc)
+    }
+
+    pub fn set_local_pref(&mut self, local_pref: u32) {
+        self.set_attribute(PathAttribute {
+            type_code: AttributeType::LocalPref,
+            value: local_pref.to_be_bytes().to_vec(),
+            flags: vec![AttributeFlag::Transitive],
+        });
+    }
+
+    pub fn set_med(&mut self, med: u32) {
+        self.set_attribute(PathAttribute {
+            type_code: AttributeType::MultiExitDisc,
+            value: med.to_be_bytes().to_vec(),
+            flags: vec![AttributeFlag::Optional],
+        });
+    }
+}
+
+/// Orders routes for the same prefix by the decision process (RFC 4271 9.1.2.2), the preferred
+/// route first: highest LOCAL_PREF, shortest AS path, lowest ORIGIN, then lowest MED. MEDs are
+/// compared regardless of the neighboring AS. Remaining ties are left to the caller.
+pub fn compare_routes(a: &Route, b: &Route) -> Ordering {
+    b.local_pref().unwrap_or(100).cmp(&a.local_pref().unwrap_or(100))
+        .then_with(|| a.as_path_length().cmp(&b.as_path_length()))
+        .then_with(|| a.origin().unwrap_or(2).cmp(&b.origin().unwrap_or(2)))
+        .then_with(|| a.med().unwrap_or(0).cmp(&b.med().unwrap_or(0)))
+}
+
+/// Builds UPDATE messages for the given routes, one per distinct set of path attributes unless
+/// the prefixes do not fit into a single message
+pub fn updates_from_routes(routes: Vec<Route>, add_path: bool) -> Vec<BGPUpdate> {
+    let mut updates: Vec<BGPUpdate> = Vec::new();
+    for route in routes {
+        match updates.iter_mut().find(|update| update.path_attributes == route.path_attributes) {
+            Some(update) => update.network_layer_reachability_information.push(route.nlri()),
+            None => updates.push(BGPUpdate {
+                withdrawn_routes: vec![],
+                network_layer_reachability_information: vec![route.nlri()],
+                path_attributes: route.path_attributes,
+            }),
+        }
+    }
+    updates.into_iter().flat_map(|update| update.split(add_path)).collect()
+}
+
+/// Routes received from a single neighbor that were accepted by its import policy
+#[derive(Debug, Default)]
+pub struct AdjRibIn {
+    routes: HashMap<Nlri, Route>,
+    /// Routes the neighbor is expected to re-advertise, e.g. during an enhanced route refresh
+    stale: HashSet<Nlri>,
+}
+
+impl AdjRibIn {
+    pub fn insert(&mut self, route: Route) {
+        self.stale.remove(&route.nlri());
+        self.routes.insert(route.nlri(), route);
+    }
+
+    pub fn remove(&mut self, nlri: &Nlri) -> Option<Route> {
+        self.stale.remove(nlri);
+        self.routes.remove(nlri)
+    }
+
+    pub fn mark_stale(&mut self) {
+        self.stale = self.routes.keys().copied().collect();
+    }
+
+    /// Removes the routes that were not re-advertised since `mark_stale`
+    pub fn purge_stale(&mut self) -> usize {
+        let stale = std::mem::take(&mut self.stale);
+        for nlri in &stale {
+            self.routes.remove(nlri);
+        }
+        stale.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.routes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+
+    pub fn routes(&self) -> impl Iterator<Item = &Route> {
+        self.routes.values()
+    }
+}
+
+/// Routes advertised to a single neighbor after its export policy
+#[derive(Debug, Default)]
+pub struct AdjRibOut {
+    routes: HashMap<Nlri, Route>,
+}
+
+impl AdjRibOut {
+    pub fn insert(&mut self, route: Route) {
+        self.routes.insert(route.nlri(), route);
+    }
+
+    /// Replaces the contents with `routes`. Returns the routes that are new or changed and the
+    /// previously advertised routes that have to be withdrawn.
+    pub fn replace(&mut self, routes: Vec<Route>) -> (Vec<Route>, Vec<Nlri>) {
+        let previous = std::mem::take(&mut self.routes);
+        let changed = routes.iter().filter(|route| previous.get(&route.nlri()) != Some(route)).cloned().collect();
+        for route in routes {
+            self.insert(route);
+        }
+        let withdrawn = previous.into_keys().filter(|nlri| !self.routes.contains_key(nlri)).collect();
+        (changed, withdrawn)
+    }
+
+    pub fn routes(&self) -> impl Iterator<Item = &Route> {
+        self.routes.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.routes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(prefix: &str, next_hop: [u8; 4]) -> Route {
+        Route {
+            prefix: prefix.parse().unwrap(),
+            path_id: 0,
+            path_attributes: vec![
+                PathAttribute { type_code: AttributeType::Origin, value: vec![2], flags: vec![AttributeFlag::Transitive]},
+                PathAttribute { type_code: AttributeType::NextHop, value: next_hop.to_vec(), flags: vec![AttributeFlag::Transitive]},
+            ],
+        }
+    }
+
+    #[test]
+    fn test_prepend_as_path() {
+        let mut route = route("10.0.0.1/32", [192, 0, 2, 1]);
+        route.prepend_as_path(&[65002]);
+        assert_eq!(route.as_path(), vec![AsPathSegment { segment_type: SegmentType::Sequence, asns: vec![65002] }]);
+        route.prepend_as_path(&[65003, 65003]);
+        assert_eq!(route.as_path(), vec![AsPathSegment { segment_type: SegmentType::Sequence, asns: vec![65003, 65003, 65002] }]);
+
+        route.set_as_path(&[AsPathSegment { segment_type: SegmentType::Set, asns: vec![1, 2] }]);
+        route.prepend_as_path(&[65002]);
+        assert_eq!(route.as_path(), vec![
+            AsPathSegment { segment_type: SegmentType::Sequence, asns: vec![65002] },
+            AsPathSegment { segment_type: SegmentType::Set, asns: vec![1, 2] },
+        ]);
+    }
+
+    #[test]
+    fn test_confed_segments() {
+        let mut route = route("10.0.0.1/32", [192, 0, 2, 1]);
+        route.prepend_as_path(&[65001]);
+        route.prepend_confed_sequence(65010);
+        route.prepend_confed_sequence(65011);
+        assert_eq!(route.as_path(), vec![
+            AsPathSegment { segment_type: SegmentType::ConfedSequence, asns: vec![65011, 65010] },
+            AsPathSegment { segment_type: SegmentType::Sequence, asns: vec![65001] },
+        ]);
+        assert_eq!(route.as_path_length(), 1);
+
+        route.remove_confed_segments();
+        assert_eq!(route.as_path(), vec![AsPathSegment { segment_type: SegmentType::Sequence, asns: vec![65001] }]);
+    }
+
+    #[test]
+    fn test_compare_routes() {
+        let short = {
+            let mut route = route("10.0.0.1/32", [192, 0, 2, 1]);
+            route.prepend_as_path(&[65001]);
+            route
+        };
+        let mut long = short.clone();
+        long.prepend_as_path(&[65003]);
+        assert_eq!(compare_routes(&short, &long), Ordering::Less);
+
+        long.set_local_pref(200);
+        assert_eq!(compare_routes(&short, &long), Ordering::Greater);
+
+        let mut higher_med = short.clone();
+        higher_med.set_med(10);
+        assert_eq!(compare_routes(&short, &higher_med), Ordering::Less);
+        assert_eq!(compare_routes(&short, &short), Ordering::Equal);
+    }
+
+    #[test]
+    fn test_cluster_list() {
+        let mut route = route("10.0.0.1/32", [192, 0, 2, 1]);
+        assert!(route.cluster_list().is_empty());
+        route.prepend_cluster_list(Ipv4Addr::new(192, 0, 2, 2));
+        route.prepend_cluster_list(Ipv4Addr::new(192, 0, 2, 3));
+        assert_eq!(route.cluster_list(), vec![Ipv4Addr::new(192, 0, 2, 3), Ipv4Addr::new(192, 0, 2, 2)]);
+        assert_eq!(route.attribute(AttributeType::ClusterList).unwrap().flags, vec![AttributeFlag::Optional]);
+    }
+
+    #[test]
+    fn test_stale_routes() {
+        let mut rib = AdjRibIn::default();
+        rib.insert(route("10.0.0.1/32", [192, 0, 2, 1]));
+        rib.insert(route("10.0.0.2/32", [192, 0, 2, 1]));
+        rib.mark_stale();
+        rib.insert(route("10.0.0.2/32", [192, 0, 2, 2]));
+        rib.insert(route("10.0.0.3/32", [192, 0, 2, 1]));
+        assert_eq!(rib.purge_stale(), 1);
+        assert_eq!(rib.len(), 2);
+        assert_eq!(rib.purge_stale(), 0);
+    }
+
+    #[test]
+    fn test_paths_keyed_by_path_id() {
+        let mut rib = AdjRibIn::default();
+        let mut second_path = route("10.0.0.1/32", [192, 0, 2, 2]);
+        second_path.path_id = 2;
+        rib.insert(route("10.0.0.1/32", [192, 0, 2, 1]));
+        rib.insert(second_path.clone());
+        assert_eq!(rib.len(), 2);
+        assert_eq!(rib.remove(&second_path.nlri()), Some(second_path));
+        assert_eq!(rib.len(), 1);
+    }
+
+    #[test]
+    fn test_replace_adj_rib_out() {
+        let mut rib = AdjRibOut::default();
+        rib.replace(vec![route("10.0.0.1/32", [192, 0, 2, 1]), route("10.0.0.2/32", [192, 0, 2, 1])]);
+        let (changed, withdrawn) = rib.replace(vec![
+            route("10.0.0.2/32", [192, 0, 2, 2]),
+            route("10.0.0.3/32", [192, 0, 2, 1]),
+        ]);
+        assert_eq!(changed, vec![route("10.0.0.2/32", [192, 0, 2, 2]), route("10.0.0.3/32", [192, 0, 2, 1])]);
+        assert_eq!(withdrawn, vec!["10.0.0.1/32".parse::<Prefix>().unwrap().into()]);
+        assert_eq!(rib.routes().count(), 2);
+    }
+
+    #[test]
+    fn test_updates_from_routes() {
+        let updates = updates_from_routes(vec![
+            route("10.0.0.1/32", [192, 0, 2, 1]),
+            route("10.0.0.2/32", [192, 0, 2, 2]),
+            route("10.0.0.3/32", [192, 0, 2, 1]),
+        ], false);
+        assert_eq!(updates.len(), 2);
+        let nlri = |prefix: &str| prefix.parse::<Prefix>().unwrap().into();
+        assert_eq!(updates[0].network_layer_reachability_information, vec![nlri("10.0.0.1/32"), nlri("10.0.0.3/32")]);
+        assert_eq!(updates[1].network_layer_reachability_information, vec![nlri("10.0.0.2/32")]);
+
+        // A large table with the same attributes is spread over several UPDATEs
+        let routes = (0..2000u32).map(|i| Route { prefix: Prefix { length: 32, prefix: (0x0A00_0000 + i).to_be_bytes() }, ..route("0.0.0.0/0", [192, 0, 2, 1]) });
+        let updates = updates_from_routes(routes.collect(), false);
+        assert!(updates.len() > 1);
+        assert_eq!(updates.iter().map(|update| update.network_layer_reachability_information.len()).sum::<usize>(), 2000);
+        assert!(updates.into_iter().all(|update| update.encode(false).len() <= crate::bgp::BGP_MAX_MSG_SIZE));
+    }
+}
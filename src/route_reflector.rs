@@ -0,0 +1,225 @@
+//! Route reflection (RFC 4456). Routes received from iBGP neighbors are reflected to the other
+//! iBGP neighbors: routes from clients to clients and non-clients, routes from non-clients only
+//! to clients. The best route per prefix is reflected with ORIGINATOR_ID set to the router it
+//! came from and our cluster ID prepended to the CLUSTER_LIST, everything else is kept as is.
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use tokio::sync::watch;
+
+use crate::bgp::utils::prefix::Prefix;
+use crate::rib::{Route, compare_routes};
+
+/// Routes accepted from an iBGP neighbor
+#[derive(Debug, Clone)]
+pub struct ReflectedRoutes {
+    pub client: bool,
+    /// BGP identifier of the neighbor, the originator of routes without an ORIGINATOR_ID
+    pub bgp_id: Ipv4Addr,
+    pub routes: Vec<Route>,
+}
+
+impl ReflectedRoutes {
+    fn originator_id(&self, route: &Route) -> Ipv4Addr {
+        route.originator_id().unwrap_or(self.bgp_id)
+    }
+}
+
+/// Routes accepted from all iBGP neighbors
+pub struct RouteReflector {
+    neighbors: Mutex<HashMap<IpAddr, ReflectedRoutes>>,
+    /// Neighbors restarting gracefully, with the time their routes are withdrawn unless the
+    /// session is back by then
+    stale: Mutex<HashMap<IpAddr, Instant>>,
+    changed: watch::Sender<()>,
+    /// Kept so that sending never fails and new sessions can subscribe
+    receiver: watch::Receiver<()>,
+}
+
+impl Default for RouteReflector {
+    fn default() -> Self {
+        let (changed, receiver) = watch::channel(());
+        RouteReflector { neighbors: Mutex::new(HashMap::new()), stale: Mutex::new(HashMap::new()), changed, receiver }
+    }
+}
+
+/// Tie-breaking beyond the decision process: lowest originator, shortest CLUSTER_LIST, then
+/// lowest neighbor address (RFC 4456 section 9)
+fn compare_reflected(a: (IpAddr, &ReflectedRoutes, &Route), b: (IpAddr, &ReflectedRoutes, &Route)) -> Ordering {
+    let ((a_address, a_neighbor, a_route), (b_address, b_neighbor, b_route)) = (a, b);
+    compare_routes(a_route, b_route)
+        .then_with(|| a_neighbor.originator_id(a_route).cmp(&b_neighbor.originator_id(b_route)))
+        .then_with(|| a_route.cluster_list().len().cmp(&b_route.cluster_list().len()))
+        .then_with(|| a_address.cmp(&b_address))
+}
+
+impl RouteReflector {
+    /// Replaces the routes of the neighbor and lets the other iBGP sessions know
+    pub fn update(&self, neighbor: IpAddr, routes: ReflectedRoutes) {
+        self.neighbors.lock().unwrap().insert(neighbor, routes);
+        self.stale.lock().unwrap().remove(&neighbor);
+        let _ = self.changed.send(());
+    }
+
+    /// Withdraws the routes of a neighbor whose session ended
+    pub fn remove(&self, neighbor: IpAddr) {
+        self.stale.lock().unwrap().remove(&neighbor);
+        if self.neighbors.lock().unwrap().remove(&neighbor).is_some() {
+            let _ = self.changed.send(());
+        }
+    }
+
+    /// Keeps reflecting the routes of a neighbor restarting gracefully until `until` (RFC 4724)
+    pub fn mark_stale(&self, neighbor: IpAddr, until: Instant) {
+        if self.neighbors.lock().unwrap().contains_key(&neighbor) {
+            self.stale.lock().unwrap().insert(neighbor, until);
+        }
+    }
+
+    /// Withdraws the routes of a neighbor if they are stale and their time has run out
+    pub fn remove_expired(&self, neighbor: IpAddr) {
+        let expired = self.stale.lock().unwrap().get(&neighbor).is_some_and(|until| *until <= Instant::now());
+        if expired {
+            self.remove(neighbor);
+        }
+    }
+
+    /// Notified whenever the routes of any iBGP neighbor changed
+    pub fn subscribe(&self) -> watch::Receiver<()> {
+        self.receiver.clone()
+    }
+
+    /// Best routes to reflect to the iBGP neighbor at `address` with the BGP identifier `bgp_id`.
+    /// Routes are never reflected back to the neighbor they came from or to their originator.
+    pub fn routes_for(&self, address: IpAddr, bgp_id: Ipv4Addr, client: bool, cluster_id: Ipv4Addr) -> Vec<Route> {
+        let neighbors = self.neighbors.lock().unwrap();
+        let mut best: HashMap<Prefix, (IpAddr, &ReflectedRoutes, &Route)> = HashMap::new();
+        for (source, reflected) in neighbors.iter() {
+            for route in &reflected.routes {
+                let candidate = (*source, reflected, route);
+                if best.get(&route.prefix).is_none_or(|current| compare_reflected(candidate, *current).is_lt()) {
+                    best.insert(route.prefix, candidate);
+                }
+            }
+        }
+        best.into_values()
+            .filter(|(source, reflected, route)| {
+                *source != address && (reflected.client || client) && reflected.originator_id(route) != bgp_id
+            })
+            .map(|(_, reflected, route)| {
+                let mut route = route.clone();
+                route.path_id = 0;
+                route.set_originator_id(reflected.originator_id(&route));
+                route.prepend_cluster_list(cluster_id);
+                route
+            })
+            .collect()
+    }
+}
+
+/// Whether a route received from an iBGP neighbor has already passed through us
+pub fn is_reflection_loop(route: &Route, router_id: Ipv4Addr, cluster_id: Ipv4Addr) -> bool {
+    route.originator_id() == Some(router_id) || route.cluster_list().contains(&cluster_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    use crate::bgp::utils::path_attribute::{AttributeFlag, AttributeType, PathAttribute};
+
+    const CLUSTER_ID: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+
+    fn route(prefix: &str, local_pref: u32) -> Route {
+        let mut route = Route {
+            prefix: prefix.parse().unwrap(),
+            path_id: 0,
+            path_attributes: vec![
+                PathAttribute { type_code: AttributeType::Origin, value: vec![0], flags: vec![AttributeFlag::Transitive] },
+                PathAttribute { type_code: AttributeType::NextHop, value: vec![192, 0, 2, 10], flags: vec![AttributeFlag::Transitive] },
+            ],
+        };
+        route.set_local_pref(local_pref);
+        route
+    }
+
+    fn reflected(client: bool, bgp_id: [u8; 4], routes: Vec<Route>) -> ReflectedRoutes {
+        ReflectedRoutes { client, bgp_id: Ipv4Addr::from(bgp_id), routes }
+    }
+
+    #[test]
+    fn test_reflection_rules() {
+        let reflector = RouteReflector::default();
+        let (client, other_client, non_client) = ("192.0.2.10".parse().unwrap(), "192.0.2.11".parse().unwrap(), "192.0.2.12".parse().unwrap());
+        reflector.update(client, reflected(true, [10, 0, 0, 10], vec![route("10.0.0.0/24", 100)]));
+        reflector.update(non_client, reflected(false, [10, 0, 0, 12], vec![route("10.0.1.0/24", 100)]));
+        reflector.update(other_client, reflected(true, [10, 0, 0, 11], vec![]));
+
+        let mut routes = reflector.routes_for(other_client, Ipv4Addr::new(10, 0, 0, 11), true, CLUSTER_ID);
+        routes.sort_by_key(|route| route.prefix.prefix);
+        assert_eq!(routes.len(), 2);
+        assert_eq!(routes[0].originator_id(), Some(Ipv4Addr::new(10, 0, 0, 10)));
+        assert_eq!(routes[0].cluster_list(), vec![CLUSTER_ID]);
+        assert_eq!(routes[1].originator_id(), Some(Ipv4Addr::new(10, 0, 0, 12)));
+
+        // Non-client routes only go to clients, and nothing goes back where it came from
+        let routes = reflector.routes_for(non_client, Ipv4Addr::new(10, 0, 0, 12), false, CLUSTER_ID);
+        assert_eq!(routes.iter().map(|route| route.prefix).collect::<Vec<_>>(), vec!["10.0.0.0/24".parse().unwrap()]);
+        let routes = reflector.routes_for(client, Ipv4Addr::new(10, 0, 0, 10), true, CLUSTER_ID);
+        assert_eq!(routes.iter().map(|route| route.prefix).collect::<Vec<_>>(), vec!["10.0.1.0/24".parse().unwrap()]);
+    }
+
+    #[test]
+    fn test_best_route() {
+        let reflector = RouteReflector::default();
+        let (first, second, third) = ("192.0.2.10".parse().unwrap(), "192.0.2.11".parse().unwrap(), "192.0.2.12".parse().unwrap());
+        reflector.update(first, reflected(true, [10, 0, 0, 10], vec![route("10.0.0.0/24", 100)]));
+        reflector.update(second, reflected(true, [10, 0, 0, 11], vec![route("10.0.0.0/24", 200)]));
+
+        let routes = reflector.routes_for(third, Ipv4Addr::new(10, 0, 0, 12), true, CLUSTER_ID);
+        assert_eq!(routes.len(), 1);
+        assert_eq!(routes[0].originator_id(), Some(Ipv4Addr::new(10, 0, 0, 11)));
+        // The best route is the neighbor's own, so it gets nothing
+        assert!(reflector.routes_for(second, Ipv4Addr::new(10, 0, 0, 11), true, CLUSTER_ID).is_empty());
+    }
+
+    #[test]
+    fn test_stale_routes() {
+        let reflector = RouteReflector::default();
+        let (first, second) = ("192.0.2.10".parse().unwrap(), "192.0.2.11".parse().unwrap());
+        reflector.update(first, reflected(true, [10, 0, 0, 10], vec![route("10.0.0.0/24", 100)]));
+
+        reflector.mark_stale(first, Instant::now() + Duration::from_secs(60));
+        reflector.remove_expired(first);
+        assert_eq!(reflector.routes_for(second, Ipv4Addr::new(10, 0, 0, 11), true, CLUSTER_ID).len(), 1);
+
+        // No longer stale once the neighbor's new session published its routes
+        reflector.mark_stale(first, Instant::now());
+        reflector.update(first, reflected(true, [10, 0, 0, 10], vec![route("10.0.0.0/24", 100)]));
+        reflector.remove_expired(first);
+        assert_eq!(reflector.routes_for(second, Ipv4Addr::new(10, 0, 0, 11), true, CLUSTER_ID).len(), 1);
+
+        reflector.mark_stale(first, Instant::now());
+        reflector.remove_expired(first);
+        assert!(reflector.routes_for(second, Ipv4Addr::new(10, 0, 0, 11), true, CLUSTER_ID).is_empty());
+    }
+
+    #[test]
+    fn test_reflection_loop() {
+        let router_id = Ipv4Addr::new(192, 0, 2, 254);
+        let mut route = route("10.0.0.0/24", 100);
+        assert!(!is_reflection_loop(&route, router_id, CLUSTER_ID));
+        route.prepend_cluster_list(Ipv4Addr::new(192, 0, 2, 2));
+        assert!(!is_reflection_loop(&route, router_id, CLUSTER_ID));
+        route.prepend_cluster_list(CLUSTER_ID);
+        assert!(is_reflection_loop(&route, router_id, CLUSTER_ID));
+        route.remove_attribute(AttributeType::ClusterList);
+        route.set_originator_id(router_id);
+        assert!(is_reflection_loop(&route, router_id, CLUSTER_ID));
+    }
+}
@@ -0,0 +1,204 @@
+//! Route server mode (RFC 7947). Routes accepted from route server clients are redistributed to
+//! the other clients without prepending our AS or changing the next hop. Each client gets the
+//! best route per prefix among those it may receive, before its own export policy.
+//!
+//! Clients steer where their routes go with action communities, `rs-as` being our local AS:
+//!
+//! - `0:peer-as` is not advertised to clients in AS `peer-as`
+//! - `0:rs-as` is not advertised to any client, except those allowed with `rs-as:peer-as`
+//! - `rs-as:peer-as` is advertised to clients in AS `peer-as`
+//!
+//! The action communities are removed before advertising. Routes with NO_EXPORT or NO_ADVERTISE
+//! are not redistributed, BLACKHOLE routes are passed on unchanged for the clients to act on.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use tokio::sync::watch;
+
+use crate::bgp::utils::community::Community;
+use crate::bgp::utils::prefix::Prefix;
+use crate::rib::{Route, compare_routes};
+
+const NO_EXPORT: Community = Community(0xFFFF_FF01);
+const NO_ADVERTISE: Community = Community(0xFFFF_FF02);
+
+/// Routes accepted from all route server clients
+pub struct RouteServer {
+    clients: Mutex<HashMap<IpAddr, Vec<Route>>>,
+    /// Clients restarting gracefully, with the time their routes are withdrawn unless the
+    /// session is back by then
+    stale: Mutex<HashMap<IpAddr, Instant>>,
+    changed: watch::Sender<()>,
+    /// Kept so that sending never fails and new sessions can subscribe
+    receiver: watch::Receiver<()>,
+}
+
+impl Default for RouteServer {
+    fn default() -> Self {
+        let (changed, receiver) = watch::channel(());
+        RouteServer { clients: Mutex::new(HashMap::new()), stale: Mutex::new(HashMap::new()), changed, receiver }
+    }
+}
+
+impl RouteServer {
+    /// Replaces the routes of the client and lets the other clients' sessions know
+    pub fn update(&self, client: IpAddr, routes: Vec<Route>) {
+        self.clients.lock().unwrap().insert(client, routes);
+        self.stale.lock().unwrap().remove(&client);
+        let _ = self.changed.send(());
+    }
+
+    /// Withdraws the routes of a client whose session ended
+    pub fn remove(&self, client: IpAddr) {
+        self.stale.lock().unwrap().remove(&client);
+        if self.clients.lock().unwrap().remove(&client).is_some() {
+            let _ = self.changed.send(());
+        }
+    }
+
+    /// Keeps passing on the routes of a client restarting gracefully until `until` (RFC 4724)
+    pub fn mark_stale(&self, client: IpAddr, until: Instant) {
+        if self.clients.lock().unwrap().contains_key(&client) {
+            self.stale.lock().unwrap().insert(client, until);
+        }
+    }
+
+    /// Withdraws the routes of a client if they are stale and their time has run out
+    pub fn remove_expired(&self, client: IpAddr) {
+        let expired = self.stale.lock().unwrap().get(&client).is_some_and(|until| *until <= Instant::now());
+        if expired {
+            self.remove(client);
+        }
+    }
+
+    /// Notified whenever the routes of any client changed
+    pub fn subscribe(&self) -> watch::Receiver<()> {
+        self.receiver.clone()
+    }
+
+    /// Best route per prefix from the other clients for the client at `address` in AS `asn`,
+    /// ready to be run through its export policy
+    pub fn routes_for(&self, address: IpAddr, asn: u16, rs_as: u16) -> Vec<Route> {
+        let clients = self.clients.lock().unwrap();
+        let mut best: HashMap<Prefix, (IpAddr, &Route)> = HashMap::new();
+        for (client, routes) in clients.iter().filter(|(client, _)| **client != address) {
+            for route in routes.iter().filter(|route| advertised_to(route, asn, rs_as)) {
+                // Ties are broken by the lowest client address to keep the choice stable
+                let preferred = best.get(&route.prefix).is_none_or(|(best_client, best_route)| {
+                    compare_routes(route, best_route).then_with(|| client.cmp(best_client)).is_lt()
+                });
+                if preferred {
+                    best.insert(route.prefix, (*client, route));
+                }
+            }
+        }
+        best.into_values().map(|(_, route)| {
+            let mut route = route.clone();
+            route.path_id = 0;
+            let mut communities = route.communities();
+            communities.retain(|community| !is_action_community(*community, rs_as));
+            route.set_communities(&communities);
+            route
+        }).collect()
+    }
+}
+
+fn is_action_community(community: Community, rs_as: u16) -> bool {
+    let asn = (community.0 >> 16) as u16;
+    asn == 0 || asn == rs_as
+}
+
+/// Whether the route's communities allow advertising it to a client in AS `asn`
+fn advertised_to(route: &Route, asn: u16, rs_as: u16) -> bool {
+    let communities = route.communities();
+    if communities.contains(&NO_EXPORT) || communities.contains(&NO_ADVERTISE) || communities.contains(&Community::new(0, asn)) {
+        return false;
+    }
+    !communities.contains(&Community::new(0, rs_as)) || communities.contains(&Community::new(rs_as, asn))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    use crate::bgp::utils::path_attribute::{AttributeFlag, AttributeType, PathAttribute};
+
+    const RS_AS: u16 = 65000;
+
+    fn route(prefix: &str, as_path: &[u16], communities: &[&str]) -> Route {
+        let mut route = Route {
+            prefix: prefix.parse().unwrap(),
+            path_id: 0,
+            path_attributes: vec![
+                PathAttribute { type_code: AttributeType::Origin, value: vec![0], flags: vec![AttributeFlag::Transitive] },
+                PathAttribute { type_code: AttributeType::NextHop, value: vec![192, 0, 2, 10], flags: vec![AttributeFlag::Transitive] },
+            ],
+        };
+        route.prepend_as_path(as_path);
+        route.set_communities(&communities.iter().map(|community| community.parse().unwrap()).collect::<Vec<_>>());
+        route
+    }
+
+    #[test]
+    fn test_action_communities() {
+        assert!(advertised_to(&route("10.0.0.0/24", &[65001], &[]), 65002, RS_AS));
+        assert!(!advertised_to(&route("10.0.0.0/24", &[65001], &["0:65002"]), 65002, RS_AS));
+        assert!(advertised_to(&route("10.0.0.0/24", &[65001], &["0:65002"]), 65003, RS_AS));
+        assert!(!advertised_to(&route("10.0.0.0/24", &[65001], &["0:65000"]), 65002, RS_AS));
+        assert!(advertised_to(&route("10.0.0.0/24", &[65001], &["0:65000", "65000:65002"]), 65002, RS_AS));
+        assert!(!advertised_to(&route("10.0.0.0/24", &[65001], &["no-export"]), 65002, RS_AS));
+        assert!(advertised_to(&route("10.0.0.1/32", &[65001], &["blackhole"]), 65002, RS_AS));
+    }
+
+    #[test]
+    fn test_routes_for() {
+        let route_server = RouteServer::default();
+        let (first, second, third) = ("192.0.2.10".parse().unwrap(), "192.0.2.11".parse().unwrap(), "192.0.2.12".parse().unwrap());
+        route_server.update(first, vec![
+            route("10.0.0.0/24", &[65001], &["0:65003", "65001:100"]),
+            route("10.0.1.0/24", &[65001, 65004], &[]),
+        ]);
+        route_server.update(second, vec![route("10.0.1.0/24", &[65002], &[])]);
+
+        let mut routes = route_server.routes_for(third, 65003, RS_AS);
+        routes.sort_by_key(|route| route.prefix.prefix);
+        // The second client's path is shorter, and the first client keeps 10.0.0.0/24 from AS 65003
+        assert_eq!(routes, vec![route("10.0.1.0/24", &[65002], &[])]);
+
+        let mut routes = route_server.routes_for(second, 65002, RS_AS);
+        routes.sort_by_key(|route| route.prefix.prefix);
+        assert_eq!(routes, vec![
+            route("10.0.0.0/24", &[65001], &["65001:100"]),
+            route("10.0.1.0/24", &[65001, 65004], &[]),
+        ]);
+
+        route_server.remove(second);
+        assert_eq!(route_server.routes_for(third, 65003, RS_AS), vec![route("10.0.1.0/24", &[65001, 65004], &[])]);
+    }
+
+    #[test]
+    fn test_stale_routes() {
+        let route_server = RouteServer::default();
+        let (first, second) = ("192.0.2.10".parse().unwrap(), "192.0.2.11".parse().unwrap());
+        route_server.update(first, vec![route("10.0.0.0/24", &[65001], &[])]);
+
+        // Kept until the restart time runs out
+        route_server.mark_stale(first, Instant::now() + Duration::from_secs(60));
+        route_server.remove_expired(first);
+        assert_eq!(route_server.routes_for(second, 65003, RS_AS).len(), 1);
+
+        // No longer stale once the client's new session published its routes
+        route_server.mark_stale(first, Instant::now());
+        route_server.update(first, vec![route("10.0.0.0/24", &[65001], &[])]);
+        route_server.remove_expired(first);
+        assert_eq!(route_server.routes_for(second, 65003, RS_AS).len(), 1);
+
+        route_server.mark_stale(first, Instant::now());
+        route_server.remove_expired(first);
+        assert!(route_server.routes_for(second, 65003, RS_AS).is_empty());
+    }
+}
@@ -0,0 +1,511 @@
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tracing::{Instrument, Span, debug, error, field, info, info_span, warn};
+
+use crate::bgp::{AFI_IPV4, AFI_IPV6, BGP_HEADER_SIZE, BGP_MAX_MSG_SIZE, BGPMessage, SAFI_FLOWSPEC, SAFI_UNICAST, message_length};
+use crate::bgp::capability::Capability;
+use crate::bgp::errors::BgpError;
+use crate::bgp::keepalive::BGPKeepalive;
+use crate::bgp::open::BGPOpen;
+use crate::bgp::update::BGPUpdate;
+use crate::bgp::route_refresh::{BGPRouteRefresh, ROUTE_REFRESH_BORR, ROUTE_REFRESH_EORR, ROUTE_REFRESH_REQUEST};
+use crate::bmp::{self, BmpEvent, PeerDownReason};
+use crate::mrt::{self, PeerTable};
+use crate::config::Config;
+use crate::peer::{Connection, Peer, Shared};
+use crate::reload::{NeighborChange, neighbor_change};
+use crate::rib::updates_from_routes;
+use crate::socket;
+use crate::trap::flowspec_update;
+
+/// Seconds to wait for the sessions' tables before giving up on the missing ones
+const TABLE_DUMP_TIMEOUT: u64 = 10;
+
+/// Commands broadcast to every running session, those with an address only apply to the
+/// session with that neighbor
+#[derive(Debug, Clone)]
+pub enum SessionCommand {
+    /// Ask the neighbor to re-advertise its routes so they go through the import policy again
+    RequestRefresh,
+    /// Trapped prefixes or FlowSpec rules were added or removed
+    TrapsChanged,
+    /// Send a BMP Statistics Report
+    ReportStatistics,
+    /// The daemon is stopping. Neighbors that negotiated graceful restart keep our routes, the
+    /// others get our withdrawals and an Administrative Shutdown with the message.
+    Shutdown(String),
+    /// Close the session with an Administrative Shutdown
+    Disable(IpAddr, String),
+    /// Close the session with an Administrative Reset, the neighbor may reconnect right away
+    Reset(IpAddr, String),
+    /// Request a route refresh from the neighbor
+    SoftResetIn(IpAddr),
+    /// Run the export policy again and re-send everything advertised to the neighbor
+    SoftResetOut(IpAddr),
+    /// The configuration was reloaded, carries the previous one to compare against
+    ConfigReloaded(Arc<Config>),
+}
+
+/// Asks a session for its Adj-RIB-In, answered with `None` if it is not established
+type TableRequest = oneshot::Sender<Option<PeerTable>>;
+
+/// Running sessions, so that table dumps know exactly whose tables to wait for
+#[derive(Default)]
+pub struct SessionRegistry(Mutex<Vec<mpsc::UnboundedSender<TableRequest>>>);
+
+impl SessionRegistry {
+    /// Registers a session, which stays registered as long as it holds the receiver
+    fn register(&self) -> mpsc::UnboundedReceiver<TableRequest> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.0.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// Sends a table request to every running session, sessions that have ended are dropped
+    fn request_tables(&self) -> Vec<oneshot::Receiver<Option<PeerTable>>> {
+        let mut answers = Vec::new();
+        self.0.lock().unwrap().retain(|session| {
+            let (sender, answer) = oneshot::channel();
+            let running = session.send(sender).is_ok();
+            if running {
+                answers.push(answer);
+            }
+            running
+        });
+        answers
+    }
+}
+
+async fn send_message<S: AsyncWrite + Unpin>(message: BGPMessage, socket: &mut S, peer: &Peer) -> Result<(), BgpError> {
+    debug!(content = ?message, "Sending");
+    peer.shared.metrics.message_sent(peer.neighbor.address, &message);
+    let is_update = matches!(message, BGPMessage::Update(_));
+    let buf = message.encode(peer.codec_options);
+    if peer.shared.mrt.enabled() {
+        peer.shared.mrt.send(mrt::bgp4mp_message(SystemTime::now(), &peer.mrt_endpoints(), true, peer.codec_options.add_path_send, &buf));
+    }
+    if is_update && peer.shared.bmp.enabled() {
+        peer.shared.bmp.send(BmpEvent::Message(bmp::route_monitoring(&peer.bmp_header(true), &buf)));
+    }
+    socket.write_all(&buf[..]).await?;
+    Ok(())
+}
+
+fn report_peer_up(peer: &Peer) {
+    let (local, remote_port) = match peer.connection {
+        Some(connection) => (connection.local, connection.remote.port()),
+        None => (SocketAddr::from(([0, 0, 0, 0], 0)), 0),
+    };
+    let message = bmp::peer_up(&peer.bmp_header(false), local, remote_port, &peer.sent_open, &peer.received_open);
+    peer.shared.bmp.send(BmpEvent::PeerUp(peer.neighbor.address, message));
+}
+
+/// Brings the neighbor up to date with the trap table and, for route server clients, the other
+/// clients' routes, sending only what changed since the previous advertisement
+async fn advertise<S: AsyncWrite + Unpin>(socket: &mut S, peer: &mut Peer) -> Result<(), BgpError> {
+    let (routes, rules) = {
+        let traps = peer.shared.traps.lock().unwrap();
+        (peer.routes(&traps), traps.flowspec().to_vec())
+    };
+    let mut updates = peer.export(routes);
+    updates.extend(peer.export_flowspec(&rules));
+    for update in updates {
+        send_message(BGPMessage::Update(update), socket, peer).await?;
+    }
+    Ok(())
+}
+
+/// Advertises the trap table once the session is established, followed by an End-of-RIB marker
+/// for each address family. With graceful restart the neighbor waits for our End-of-RIB before
+/// dropping stale routes, so every neighbor has to receive the full table before it.
+async fn initial_advertisement<S: AsyncWrite + Unpin>(socket: &mut S, peer: &mut Peer) -> Result<(), BgpError> {
+    advertise(socket, peer).await?;
+    for (afi, safi) in peer.families() {
+        send_message(BGPMessage::Update(BGPUpdate::end_of_rib(afi, safi)), socket, peer).await?;
+    }
+    Ok(())
+}
+
+/// Asks the neighbor to send its routes again
+async fn soft_reset_in<S: AsyncWrite + Unpin>(socket: &mut S, peer: &Peer) -> Result<(), BgpError> {
+    if peer.negotiated(&Capability::RouteRefresh) {
+        let request = BGPRouteRefresh::new(AFI_IPV4, SAFI_UNICAST, ROUTE_REFRESH_REQUEST);
+        send_message(BGPMessage::RouteRefresh(request), socket, peer).await?;
+    } else {
+        warn!("Neighbor does not support route refresh");
+    }
+    Ok(())
+}
+
+/// Withdraws everything advertised to the neighbor
+async fn withdraw_all<S: AsyncWrite + Unpin>(socket: &mut S, peer: &mut Peer) -> Result<(), BgpError> {
+    let mut updates = peer.export(vec![]);
+    updates.extend(peer.export_flowspec(&[]));
+    for update in updates {
+        send_message(BGPMessage::Update(update), socket, peer).await?;
+    }
+    Ok(())
+}
+
+/// Re-sends the Adj-RIB-Out of the address family, wrapped in BoRR/EoRR markers if enhanced route
+/// refresh was negotiated
+async fn send_refresh<S: AsyncWrite + Unpin>(socket: &mut S, peer: &Peer, afi: u16, safi: u8) -> Result<(), BgpError> {
+    let enhanced = peer.negotiated(&Capability::EnhancedRouteRefresh);
+    if enhanced {
+        let borr = BGPRouteRefresh::new(afi, safi, ROUTE_REFRESH_BORR);
+        send_message(BGPMessage::RouteRefresh(borr), socket, peer).await?;
+    }
+    let updates = if safi == SAFI_FLOWSPEC {
+        peer.flowspec_out.iter().filter(|rule| rule.afi() == afi).map(flowspec_update).collect()
+    } else {
+        updates_from_routes(peer.adj_rib_out.routes().cloned().collect(), peer.codec_options.add_path_send)
+    };
+    for update in updates {
+        send_message(BGPMessage::Update(update), socket, peer).await?;
+    }
+    if enhanced {
+        let eorr = BGPRouteRefresh::new(afi, safi, ROUTE_REFRESH_EORR);
+        send_message(BGPMessage::RouteRefresh(eorr), socket, peer).await?;
+    }
+    Ok(())
+}
+
+/// Handles a received UPDATE, also used when replaying recorded messages
+pub fn handle_update(update: &BGPUpdate, peer: &mut Peer) -> Result<(), BgpError> {
+    match update.end_of_rib_family() {
+        Some((afi, safi)) => {
+            let first = !peer.initial_table_received(afi, safi);
+            peer.end_of_rib_received.insert((afi, safi));
+            let purged = if (afi, safi) == (AFI_IPV4, SAFI_UNICAST) { peer.adj_rib_in.purge_stale() } else { 0 };
+            if first {
+                info!(afi, safi, stale_routes_removed = purged, "Initial table received");
+            }
+        },
+        None => {
+            peer.import(update)?;
+            debug!(routes = peer.adj_rib_in.len(), "UPDATE imported");
+        },
+    }
+    peer.publish_routes();
+    Ok(())
+}
+
+/// Handles a message from the neighbor, `raw` is the message as received on the wire
+async fn handle_message<S: AsyncWrite + Unpin>(message: &BGPMessage, raw: &[u8], socket: &mut S, peer: &mut Peer) -> Result<(), BgpError> {
+    debug!(content = ?message, "Received");
+    peer.shared.metrics.message_received(peer.neighbor.address, message);
+    if let BGPMessage::Open(received) = message {
+        // Known before anything else so that the OPEN itself is recorded with the neighbor's AS
+        peer.remote_as = received.sender_as;
+        peer.remote_id = received.bgp_id;
+    }
+    if peer.shared.mrt.enabled() {
+        peer.shared.mrt.send(mrt::bgp4mp_message(SystemTime::now(), &peer.mrt_endpoints(), false, peer.codec_options.add_path_receive, raw));
+    }
+    match message {
+        BGPMessage::Open(received) => {
+            peer.received_open = raw.to_vec();
+            if let Some(remote_as) = peer.neighbor.remote_as {
+                if received.sender_as != remote_as {
+                    return Err(BgpError::PeerAsMismatch { expected: remote_as, received: received.sender_as });
+                }
+            }
+            peer.open_received(&received.capabilities);
+            let config = peer.shared.config();
+            let open = BGPOpen::new(peer.local_as(), config.hold_time, config.router_id.into(), peer.local_capabilities());
+            peer.sent_open = BGPMessage::Open(open.clone()).encode(peer.codec_options);
+            send_message(BGPMessage::Open(open), socket, peer).await?;
+        },
+        BGPMessage::Keepalive(_) => {
+            let keepalive = BGPKeepalive::new();
+            send_message(BGPMessage::Keepalive(keepalive), socket, peer).await?;
+            if !peer.established {
+                peer.established = true;
+                info!("Session established");
+                report_peer_up(peer);
+                // Routes retained over a graceful restart are redistributed right away
+                peer.publish_routes();
+                initial_advertisement(socket, peer).await?;
+            }
+        },
+        BGPMessage::Update(update) => {
+            if peer.shared.bmp.enabled() {
+                peer.shared.bmp.send(BmpEvent::Message(bmp::route_monitoring(&peer.bmp_header(false), raw)));
+            }
+            handle_update(update, peer)?;
+        },
+        BGPMessage::Notification(notification) => {
+            peer.received_notification = Some(raw.to_vec());
+            if let Some(communication) = notification.shutdown_communication() {
+                info!(%communication, "Shutdown communication received");
+            }
+            return Err(BgpError::NotificationReceived { code: notification.error_code, subcode: notification.error_subcode });
+        },
+        BGPMessage::RouteRefresh(route_refresh) => {
+            let (afi, safi) = (route_refresh.afi, route_refresh.safi);
+            // We only send FlowSpec rules, received ones are ignored
+            if safi == SAFI_FLOWSPEC && peer.flowspec_negotiated(afi) && route_refresh.subtype == ROUTE_REFRESH_REQUEST {
+                return send_refresh(socket, peer, afi, safi).await;
+            }
+            // Refreshes for other address families are ignored as we never negotiate them
+            if afi != AFI_IPV4 || safi != SAFI_UNICAST {
+                return Ok(());
+            }
+            match route_refresh.subtype {
+                ROUTE_REFRESH_REQUEST => send_refresh(socket, peer, afi, safi).await?,
+                ROUTE_REFRESH_BORR => peer.adj_rib_in.mark_stale(),
+                ROUTE_REFRESH_EORR => {
+                    let purged = peer.adj_rib_in.purge_stale();
+                    peer.publish_routes();
+                    info!(stale_routes_removed = purged, "Route refresh complete");
+                },
+                _ => {},
+            }
+        },
+    }
+    Ok(())
+}
+
+async fn handle_command<S: AsyncWrite + Unpin>(command: SessionCommand, socket: &mut S, peer: &mut Peer) -> Result<(), BgpError> {
+    match command {
+        SessionCommand::RequestRefresh => {
+            if peer.negotiated(&Capability::RouteRefresh) {
+                let request = BGPRouteRefresh::new(AFI_IPV4, SAFI_UNICAST, ROUTE_REFRESH_REQUEST);
+                send_message(BGPMessage::RouteRefresh(request), socket, peer).await?;
+            }
+        },
+        SessionCommand::TrapsChanged => {
+            // Sessions that are not established yet get the current table with their initial advertisement
+            if peer.established {
+                advertise(socket, peer).await?;
+            }
+        },
+        SessionCommand::ReportStatistics => {
+            if peer.established {
+                let report = bmp::statistics_report(&peer.bmp_header(false), peer.rejected_prefixes, peer.adj_rib_in.len() as u64);
+                peer.shared.bmp.send(BmpEvent::Message(report));
+            }
+        },
+        SessionCommand::Shutdown(communication) => {
+            if peer.established && peer.graceful_restart_negotiated() {
+                return Err(BgpError::Restarting);
+            }
+            if peer.established {
+                withdraw_all(socket, peer).await?;
+            }
+            return Err(BgpError::AdministrativeShutdown { communication });
+        },
+        SessionCommand::Disable(address, communication) if address == peer.neighbor.address => {
+            return Err(BgpError::AdministrativeShutdown { communication });
+        },
+        SessionCommand::Reset(address, communication) if address == peer.neighbor.address => {
+            return Err(BgpError::AdministrativeReset { communication });
+        },
+        SessionCommand::SoftResetIn(address) if address == peer.neighbor.address && peer.established => {
+            soft_reset_in(socket, peer).await?;
+        },
+        SessionCommand::SoftResetOut(address) if address == peer.neighbor.address && peer.established => {
+            advertise(socket, peer).await?;
+            send_refresh(socket, peer, AFI_IPV4, SAFI_UNICAST).await?;
+            for afi in [AFI_IPV4, AFI_IPV6] {
+                if peer.flowspec_negotiated(afi) {
+                    send_refresh(socket, peer, afi, SAFI_FLOWSPEC).await?;
+                }
+            }
+        },
+        SessionCommand::Disable(..) | SessionCommand::Reset(..) | SessionCommand::SoftResetIn(_) | SessionCommand::SoftResetOut(_) => {},
+        SessionCommand::ConfigReloaded(previous) => {
+            let config = peer.shared.config();
+            match neighbor_change(peer.neighbor.address, &previous, &config) {
+                NeighborChange::Unchanged => {},
+                NeighborChange::Removed => return Err(BgpError::Deconfigured),
+                NeighborChange::Reset => return Err(BgpError::ConfigurationChanged),
+                NeighborChange::Update { import, export } => {
+                    info!(import, export, "Applying changed configuration");
+                    peer.neighbor = config.neighbor(peer.neighbor.address);
+                    if import && peer.established {
+                        soft_reset_in(socket, peer).await?;
+                    }
+                    if export && peer.established {
+                        advertise(socket, peer).await?;
+                    }
+                },
+            }
+        },
+    }
+    Ok(())
+}
+
+/// Advertises changes to the routes passed on from other neighbors
+async fn redistribute<S: AsyncWrite + Unpin>(socket: &mut S, peer: &mut Peer) -> Result<(), BgpError> {
+    if peer.established {
+        let fsm = info_span!("fsm", state = peer.state());
+        advertise(socket, peer).instrument(fsm).await?;
+        peer.report_metrics();
+    }
+    Ok(())
+}
+
+async fn process<S: AsyncRead + AsyncWrite + Unpin>(
+    socket: &mut S,
+    peer: &mut Peer,
+    commands: &mut broadcast::Receiver<SessionCommand>,
+) -> Result<(), BgpError> {
+    let session = Span::current();
+    let mut buf = Vec::with_capacity(BGP_MAX_MSG_SIZE);
+    let mut read_buf = [0; BGP_MAX_MSG_SIZE];
+    let mut routes_changed = peer.shared.route_server.subscribe();
+    let mut reflected_changed = peer.shared.route_reflector.subscribe();
+    let mut table_requests = peer.shared.sessions.register();
+    loop {
+        tokio::select! {
+            n = socket.read(&mut read_buf) => {
+                let n = n?;
+                if n == 0 {
+                    return Ok(());
+                }
+                buf.extend_from_slice(&read_buf[..n]);
+                while buf.len() >= BGP_HEADER_SIZE {
+                    let bgp_message_length = message_length(&buf);
+                    // A length outside the header and maximum size would never complete a message
+                    let valid_length = (BGP_HEADER_SIZE..=BGP_MAX_MSG_SIZE).contains(&bgp_message_length);
+                    if valid_length && buf.len() < bgp_message_length {
+                        break;
+                    }
+                    let raw: Vec<u8> = buf.drain(..if valid_length { bgp_message_length } else { BGP_HEADER_SIZE }).collect();
+                    let bgp_message = match BGPMessage::decode(&raw, peer.codec_options) {
+                        Ok(bgp_message) => bgp_message,
+                        Err(e) => {
+                            peer.shared.metrics.update(peer.neighbor.address, |metrics| metrics.decode_errors += 1);
+                            return Err(e);
+                        },
+                    };
+                    if let BGPMessage::Open(open) = &bgp_message {
+                        session.record("remote_as", open.sender_as);
+                    }
+                    let fsm = info_span!("fsm", state = peer.state());
+                    handle_message(&bgp_message, &raw, socket, peer).instrument(fsm).await?;
+                    peer.report_metrics();
+                }
+            },
+            command = commands.recv() => match command {
+                Ok(command) => {
+                    let fsm = info_span!("fsm", state = peer.state());
+                    handle_command(command, socket, peer).instrument(fsm).await?;
+                    peer.report_metrics();
+                },
+                // The missed commands may have included trap changes. Advertising only sends what
+                // differs from the Adj-RIB-Out, so catching up is cheap.
+                Err(broadcast::error::RecvError::Lagged(_)) => handle_command(SessionCommand::TrapsChanged, socket, peer).await?,
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            },
+            Some(request) = table_requests.recv() => {
+                let _ = request.send(if peer.established { Some(peer.table()) } else { None });
+            },
+            // Changes made in quick succession are advertised together
+            Ok(()) = routes_changed.changed(), if peer.neighbor.route_server_client => redistribute(socket, peer).await?,
+            Ok(()) = reflected_changed.changed(), if peer.internal() => redistribute(socket, peer).await?,
+        }
+    }
+}
+
+/// Collects the Adj-RIB-Ins of all established sessions. Every running session answers, a
+/// session that ends in the meantime drops its request instead.
+pub async fn collect_tables(shared: &Shared) -> Vec<PeerTable> {
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(TABLE_DUMP_TIMEOUT);
+    let mut tables = Vec::new();
+    for answer in shared.sessions.request_tables() {
+        match tokio::time::timeout_at(deadline, answer).await {
+            Ok(Ok(Some(table))) => tables.push(table),
+            Ok(Ok(None)) | Ok(Err(_)) => {},
+            Err(_) => {
+                warn!("Timed out waiting for the sessions' tables");
+                break;
+            },
+        }
+    }
+    tables
+}
+
+/// Sets up the session on a connection accepted from `address`. `None` if the neighbor is
+/// suspended or the socket could not be configured, the connection is closed when dropped.
+pub fn accept(socket: TcpStream, address: SocketAddr, shared: Arc<Shared>, commands: broadcast::Receiver<SessionCommand>) -> Option<impl Future<Output = ()>> {
+    if shared.suspended.is_suspended(address.ip()) {
+        info!(peer = %address.ip(), "Refusing connection from suspended neighbor");
+        return None;
+    }
+    let mut peer = Peer::new(address.ip(), shared);
+    if let Err(e) = socket::configure_session(&socket, &peer.neighbor) {
+        warn!(peer = %address.ip(), error = %e, "Refusing connection");
+        return None;
+    }
+    let local = match socket.local_addr() {
+        Ok(local) => local,
+        Err(e) => {
+            error!(peer = %address.ip(), error = %e, "Failed to get the local address");
+            return None;
+        },
+    };
+    peer.connection = Some(Connection { local, remote: address });
+    Some(run(socket, peer, commands))
+}
+
+/// Runs a session until the connection is closed. Errors are reported to the neighbor with a
+/// NOTIFICATION where applicable before the session is closed. Everything logged for the session
+/// is in a span with the neighbor's address and AS, with a nested span for the FSM state in which
+/// each message or command was handled.
+pub async fn run<S: AsyncRead + AsyncWrite + Unpin>(socket: S, peer: Peer, commands: broadcast::Receiver<SessionCommand>) {
+    let span = info_span!("session", peer = %peer.neighbor.address, remote_as = field::Empty);
+    run_session(socket, peer, commands).instrument(span).await
+}
+
+async fn run_session<S: AsyncRead + AsyncWrite + Unpin>(mut socket: S, mut peer: Peer, mut commands: broadcast::Receiver<SessionCommand>) {
+    peer.report_metrics();
+    let result = process(&mut socket, &mut peer, &mut commands).await;
+    peer.shared.metrics.session_closed(peer.neighbor.address);
+    let mut down_reason = match peer.received_notification.take() {
+        Some(notification) => PeerDownReason::RemoteNotification(notification),
+        None => PeerDownReason::RemoteNoData,
+    };
+    if let Err(e) = &result {
+        warn!(state = peer.state(), error = %e, "Closing session");
+        if let Some(notification) = e.notification() {
+            down_reason = PeerDownReason::LocalNotification(BGPMessage::Notification(notification.clone()).encode(peer.codec_options));
+            let _ = send_message(BGPMessage::Notification(notification), &mut socket, &peer).await;
+        }
+    }
+    if peer.established {
+        peer.shared.bmp.send(BmpEvent::PeerDown(peer.neighbor.address, bmp::peer_down(&peer.bmp_header(false), down_reason)));
+    }
+    let (shared, address) = (peer.shared.clone(), peer.neighbor.address);
+    // Sessions ended by a NOTIFICATION in either direction are not graceful restarts
+    let restart_until = match result {
+        Ok(()) | Err(BgpError::IoError(_)) => peer.session_lost(),
+        Err(_) => None,
+    };
+    match restart_until {
+        Some(until) => retain_redistributed(shared, address, until),
+        None => {
+            shared.route_server.remove(address);
+            shared.route_reflector.remove(address);
+        },
+    }
+}
+
+/// Keeps passing on the routes of a neighbor restarting gracefully, they are withdrawn from the
+/// other sessions if the neighbor is not back by `until`
+fn retain_redistributed(shared: Arc<Shared>, address: IpAddr, until: Instant) {
+    shared.route_server.mark_stale(address, until);
+    shared.route_reflector.mark_stale(address, until);
+    tokio::spawn(async move {
+        tokio::time::sleep_until(tokio::time::Instant::from_std(until)).await;
+        shared.route_server.remove_expired(address);
+        shared.route_reflector.remove_expired(address);
+    });
+}
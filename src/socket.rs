@@ -0,0 +1,264 @@
+//! Socket options for BGP sessions that std and tokio do not expose
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::os::unix::io::AsRawFd;
+
+use tokio::net::TcpListener;
+use tracing::info;
+
+use crate::bgp::utils::prefix::Prefix;
+use crate::config::{Config, NeighborConfig};
+
+/// Longest key Linux accepts for TCP_MD5SIG
+pub const TCP_MD5SIG_MAXKEYLEN: usize = 80;
+
+/// Socket options from linux/tcp.h to keep the SYN of accepted connections and read it back
+const TCP_SAVE_SYN: libc::c_int = 27;
+const TCP_SAVED_SYN: libc::c_int = 28;
+
+/// `struct tcp_md5sig` from linux/tcp.h
+#[repr(C)]
+struct TcpMd5Sig {
+    addr: libc::sockaddr_storage,
+    flags: u8,
+    prefix_length: u8,
+    key_length: u16,
+    pad: u32,
+    key: [u8; TCP_MD5SIG_MAXKEYLEN],
+}
+
+fn setsockopt<T>(socket: &impl AsRawFd, level: libc::c_int, name: libc::c_int, value: &T) -> io::Result<()> {
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            value as *const T as *const libc::c_void,
+            std::mem::size_of::<T>() as libc::socklen_t,
+        )
+    };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// IPv4 or IPv6 header of the SYN that opened the connection, saved because the listening
+/// socket has TCP_SAVE_SYN set. The kernel hands it out only once.
+fn saved_syn(socket: &impl AsRawFd) -> io::Result<Vec<u8>> {
+    let mut syn = vec![0u8; 512];
+    let mut length = syn.len() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            TCP_SAVED_SYN,
+            syn.as_mut_ptr() as *mut libc::c_void,
+            &mut length,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    syn.truncate(length as usize);
+    Ok(syn)
+}
+
+/// TTL or hop limit the SYN of an accepted connection arrived with
+fn syn_ttl(socket: &impl AsRawFd) -> io::Result<u8> {
+    let syn = saved_syn(socket)?;
+    let ttl = match syn.first().map(|octet| octet >> 4) {
+        Some(4) => syn.get(8),
+        Some(6) => syn.get(7),
+        _ => None,
+    };
+    ttl.copied().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "SYN of the connection was not saved"))
+}
+
+/// Signs segments to and from `address` with a TCP MD5 signature (RFC 2385). On a listening
+/// socket the key applies to connections accepted from the address. An empty key removes it.
+pub fn set_md5_key(socket: &impl AsRawFd, local: SocketAddr, address: IpAddr, key: &[u8]) -> io::Result<()> {
+    if key.len() > TCP_MD5SIG_MAXKEYLEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "TCP MD5 key is too long"));
+    }
+    let mut md5sig: TcpMd5Sig = unsafe { std::mem::zeroed() };
+    // IPv4 neighbors connect to dual-stack sockets with mapped addresses
+    let address = match (local, address) {
+        (SocketAddr::V6(_), IpAddr::V4(address)) => IpAddr::V6(address.to_ipv6_mapped()),
+        (_, address) => address,
+    };
+    match address {
+        IpAddr::V4(address) => {
+            let sin = &mut md5sig.addr as *mut _ as *mut libc::sockaddr_in;
+            unsafe {
+                (*sin).sin_family = libc::AF_INET as libc::sa_family_t;
+                (*sin).sin_addr.s_addr = u32::from(address).to_be();
+            }
+        },
+        IpAddr::V6(address) => {
+            let sin6 = &mut md5sig.addr as *mut _ as *mut libc::sockaddr_in6;
+            unsafe {
+                (*sin6).sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                (*sin6).sin6_addr.s6_addr = address.octets();
+            }
+        },
+    }
+    md5sig.key_length = key.len() as u16;
+    md5sig.key[..key.len()].copy_from_slice(key);
+    setsockopt(socket, libc::IPPROTO_TCP, libc::TCP_MD5SIG, &md5sig)
+}
+
+/// Sets the TTL or hop limit of sent segments
+pub fn set_ttl(socket: &impl AsRawFd, address: IpAddr, ttl: u8) -> io::Result<()> {
+    let ttl = ttl as libc::c_int;
+    match address {
+        IpAddr::V4(_) => setsockopt(socket, libc::IPPROTO_IP, libc::IP_TTL, &ttl),
+        IpAddr::V6(_) => setsockopt(socket, libc::IPPROTO_IPV6, libc::IPV6_UNICAST_HOPS, &ttl),
+    }
+}
+
+/// Makes the kernel drop received segments with a lower TTL or hop limit
+pub fn set_min_ttl(socket: &impl AsRawFd, address: IpAddr, ttl: u8) -> io::Result<()> {
+    let ttl = ttl as libc::c_int;
+    match address {
+        IpAddr::V4(_) => setsockopt(socket, libc::IPPROTO_IP, libc::IP_MINTTL, &ttl),
+        IpAddr::V6(_) => setsockopt(socket, libc::IPPROTO_IPV6, libc::IPV6_MINHOPCOUNT, &ttl),
+    }
+}
+
+/// Applies the neighbor's TTL security or multihop setting to an accepted connection. With TTL
+/// security the connection is refused if its SYN came from too far away, the caller closes it
+/// before reading anything. Later segments with a lower TTL are dropped by the kernel.
+pub fn configure_session(socket: &impl AsRawFd, neighbor: &NeighborConfig) -> io::Result<()> {
+    if let Some(ttl_security) = &neighbor.ttl_security {
+        let ttl = syn_ttl(socket)?;
+        if ttl < ttl_security.min_ttl() {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("TTL {} of the SYN is below the minimum of {}", ttl, ttl_security.min_ttl()),
+            ));
+        }
+        set_ttl(socket, neighbor.address, 255)?;
+        set_min_ttl(socket, neighbor.address, ttl_security.min_ttl())?;
+    } else if let Some(ttl) = neighbor.ebgp_multihop {
+        set_ttl(socket, neighbor.address, ttl)?;
+    }
+    Ok(())
+}
+
+/// Applies the neighbors' TCP MD5 passwords to the listening socket and keeps the SYN of
+/// accepted connections for the TTL security check
+pub fn configure_listener(listener: &TcpListener, config: &Config) -> io::Result<()> {
+    let local = listener.local_addr()?;
+    setsockopt(listener, libc::IPPROTO_TCP, TCP_SAVE_SYN, &(1 as libc::c_int))?;
+    for neighbor in &config.neighbors {
+        if let Some(password) = &neighbor.password {
+            set_md5_key(listener, local, neighbor.address, password.as_bytes())?;
+            info!(peer = %neighbor.address, "TCP MD5 signature enabled");
+        }
+    }
+    Ok(())
+}
+
+/// Brings the listening socket's TCP MD5 passwords up to date after a reload
+pub fn reconfigure_listener(listener: &TcpListener, previous: &Config, current: &Config) -> io::Result<()> {
+    let local = listener.local_addr()?;
+    for neighbor in &previous.neighbors {
+        if neighbor.password.is_some() && current.neighbor(neighbor.address).password.is_none() {
+            set_md5_key(listener, local, neighbor.address, &[])?;
+            info!(peer = %neighbor.address, "TCP MD5 signature disabled");
+        }
+    }
+    configure_listener(listener, current)
+}
+
+/// Subnet of the interface with the IPv4 address `address`, for third-party next hops
+pub fn interface_subnet(address: Ipv4Addr) -> Option<Prefix> {
+    let mut interfaces: *mut libc::ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut interfaces) } != 0 {
+        return None;
+    }
+    let mut subnet = None;
+    let mut interface = interfaces;
+    while !interface.is_null() {
+        let ifaddr = unsafe { &*interface };
+        interface = ifaddr.ifa_next;
+        if ifaddr.ifa_addr.is_null() || ifaddr.ifa_netmask.is_null()
+            || unsafe { (*ifaddr.ifa_addr).sa_family } != libc::AF_INET as libc::sa_family_t {
+            continue;
+        }
+        let (addr, netmask) = unsafe {
+            (&*(ifaddr.ifa_addr as *const libc::sockaddr_in), &*(ifaddr.ifa_netmask as *const libc::sockaddr_in))
+        };
+        if Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)) == address {
+            let netmask = u32::from_be(netmask.sin_addr.s_addr);
+            let network = u32::from(address) & netmask;
+            subnet = Some(Prefix { length: netmask.count_ones() as u8, prefix: network.to_be_bytes() });
+            break;
+        }
+    }
+    unsafe { libc::freeifaddrs(interfaces) };
+    subnet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use crate::config::TtlSecurityConfig;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpSocket, TcpStream};
+
+    #[tokio::test]
+    async fn test_md5_key() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local = listener.local_addr().unwrap();
+        set_md5_key(&listener, local, local.ip(), b"secret").unwrap();
+
+        let socket = TcpSocket::new_v4().unwrap();
+        set_md5_key(&socket, local, local.ip(), b"secret").unwrap();
+        socket.connect(local).await.unwrap();
+        listener.accept().await.unwrap();
+
+        // Unsigned SYNs are dropped, so the connection attempt never completes
+        let socket = TcpSocket::new_v4().unwrap();
+        assert!(tokio::time::timeout(Duration::from_millis(500), socket.connect(local)).await.is_err());
+
+        assert!(set_md5_key(&listener, local, local.ip(), &[0; TCP_MD5SIG_MAXKEYLEN + 1]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_ttl_security() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local = listener.local_addr().unwrap();
+        let mut neighbor = NeighborConfig::new(local.ip());
+        neighbor.ttl_security = Some(TtlSecurityConfig { hops: 0 });
+        let mut buf = [0; 1];
+
+        configure_listener(&listener, &Config::default()).unwrap();
+
+        // Sent with the default TTL of 64, refused and closed right after the handshake
+        let mut client = TcpStream::connect(local).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        assert_eq!(configure_session(&server, &neighbor).unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        drop(server);
+        let closed = tokio::time::timeout(Duration::from_millis(500), client.read(&mut buf)).await.unwrap();
+        assert!(matches!(closed, Ok(0) | Err(_)));
+
+        let socket = TcpSocket::new_v4().unwrap();
+        set_ttl(&socket, local.ip(), 255).unwrap();
+        let mut client = socket.connect(local).await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        configure_session(&server, &neighbor).unwrap();
+        client.write_all(b"x").await.unwrap();
+        assert_eq!(server.read(&mut buf).await.unwrap(), 1);
+    }
+
+    #[test]
+    fn test_interface_subnet() {
+        assert_eq!(interface_subnet(Ipv4Addr::LOCALHOST), Some("127.0.0.0/8".parse().unwrap()));
+        assert_eq!(interface_subnet(Ipv4Addr::new(192, 0, 2, 255)), None);
+    }
+}
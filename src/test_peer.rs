@@ -0,0 +1,724 @@
+//! Scripted BGP neighbor for exercising sessions end to end, either over an in-memory stream or
+//! a loopback TCP connection
+
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+
+use crate::bgp::{AFI_IPV4, BGP_HEADER_SIZE, BGP_MAX_MSG_SIZE, BGPMessage, CodecOptions, SAFI_UNICAST, message_length};
+use crate::bgp::capability::Capability;
+use crate::bgp::keepalive::BGPKeepalive;
+use crate::bgp::open::BGPOpen;
+use crate::bgp::update::BGPUpdate;
+use crate::peer::{Peer, Shared};
+use crate::session::{self, SessionCommand};
+use crate::socket;
+
+/// How long to wait for BGtraP before a test fails
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+/// OPEN for the neighbor, with a BGP identifier derived from its AS number
+pub fn open(sender_as: u16, capabilities: Vec<Capability>) -> BGPOpen {
+    BGPOpen::new(sender_as, 90, 0x0A00_0000 | sender_as as u32, capabilities)
+}
+
+/// The neighbor's end of a session run by `session::run`
+pub struct TestPeer<S = DuplexStream> {
+    stream: S,
+    buf: Vec<u8>,
+    /// Wire format for decoding what BGtraP sends and encoding what is sent to it
+    pub codec_options: CodecOptions,
+    /// Commands for the session, as broadcast by the control API or the signal handlers
+    pub commands: broadcast::Sender<SessionCommand>,
+    session: JoinHandle<()>,
+}
+
+impl TestPeer<DuplexStream> {
+    /// Starts a session with the neighbor `address` over an in-memory stream
+    pub fn start(address: IpAddr, shared: Arc<Shared>) -> TestPeer<DuplexStream> {
+        let (commands, _) = broadcast::channel(16);
+        TestPeer::attach(address, shared, &commands)
+    }
+
+    /// Starts a session that receives the commands broadcast to all other sessions on `commands`,
+    /// like the sessions accepted in `main`
+    pub fn attach(address: IpAddr, shared: Arc<Shared>, commands: &broadcast::Sender<SessionCommand>) -> TestPeer<DuplexStream> {
+        let (stream, session_stream) = tokio::io::duplex(BGP_MAX_MSG_SIZE * 4);
+        let session = tokio::spawn(session::run(session_stream, Peer::new(address, shared), commands.subscribe()));
+        TestPeer { stream, buf: vec![], codec_options: CodecOptions::default(), commands: commands.clone(), session }
+    }
+}
+
+impl TestPeer<TcpStream> {
+    /// Starts a session over a TCP connection from 127.0.0.1, accepted the same way as in `main`.
+    /// A refused connection is closed right away.
+    pub async fn connect(shared: Arc<Shared>) -> TestPeer<TcpStream> {
+        let (commands, _) = broadcast::channel(16);
+        TestPeer::connect_with(shared, &commands).await
+    }
+
+    /// Like `connect`, receiving the commands broadcast on `commands`
+    pub async fn connect_with(shared: Arc<Shared>, commands: &broadcast::Sender<SessionCommand>) -> TestPeer<TcpStream> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        socket::configure_listener(&listener, &shared.config()).unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (socket, address) = listener.accept().await.unwrap();
+        let session = session::accept(socket, address, shared, commands.subscribe());
+        let session = tokio::spawn(async move {
+            if let Some(session) = session {
+                session.await;
+            }
+        });
+        TestPeer { stream, buf: vec![], codec_options: CodecOptions::default(), commands: commands.clone(), session }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> TestPeer<S> {
+    pub async fn send(&mut self, message: BGPMessage) {
+        let buf = message.encode(self.codec_options);
+        self.send_raw(&buf).await;
+    }
+
+    /// Sends bytes as they are, e.g. for malformed messages
+    pub async fn send_raw(&mut self, buf: &[u8]) {
+        self.stream.write_all(buf).await.unwrap();
+    }
+
+    /// Next message from BGtraP, `None` if the session was closed
+    pub async fn try_recv(&mut self) -> Option<BGPMessage> {
+        let mut read_buf = [0; BGP_MAX_MSG_SIZE];
+        while self.buf.len() < BGP_HEADER_SIZE || self.buf.len() < message_length(&self.buf) {
+            let n = tokio::time::timeout(TIMEOUT, self.stream.read(&mut read_buf)).await
+                .expect("timed out waiting for a message")
+                .unwrap_or(0);
+            if n == 0 {
+                return None;
+            }
+            self.buf.extend_from_slice(&read_buf[..n]);
+        }
+        let raw: Vec<u8> = self.buf.drain(..message_length(&self.buf)).collect();
+        Some(BGPMessage::decode(&raw, self.codec_options).expect("malformed message from BGtraP"))
+    }
+
+    pub async fn recv(&mut self) -> BGPMessage {
+        self.try_recv().await.expect("session closed")
+    }
+
+    pub async fn recv_update(&mut self) -> BGPUpdate {
+        match self.recv().await {
+            BGPMessage::Update(update) => update,
+            message => panic!("expected an UPDATE, received {:?}", message),
+        }
+    }
+
+    /// Exchanges OPEN and KEEPALIVE messages and returns BGtraP's OPEN
+    pub async fn establish(&mut self, open: BGPOpen) -> BGPOpen {
+        self.send(BGPMessage::Open(open)).await;
+        let open = match self.recv().await {
+            BGPMessage::Open(open) => open,
+            message => panic!("expected an OPEN, received {:?}", message),
+        };
+        self.send(BGPMessage::Keepalive(BGPKeepalive::new())).await;
+        match self.recv().await {
+            BGPMessage::Keepalive(_) => open,
+            message => panic!("expected a KEEPALIVE, received {:?}", message),
+        }
+    }
+
+    /// Collects UPDATEs up to the IPv4 unicast End-of-RIB marker, which is left out
+    pub async fn recv_initial_table(&mut self) -> Vec<BGPUpdate> {
+        let mut updates = vec![];
+        loop {
+            let update = self.recv_update().await;
+            if update.end_of_rib_family() == Some((AFI_IPV4, SAFI_UNICAST)) {
+                return updates;
+            }
+            updates.push(update);
+        }
+    }
+
+    /// Waits for BGtraP to close the session
+    pub async fn expect_closed(mut self) {
+        if let Some(message) = self.try_recv().await {
+            panic!("expected the session to be closed, received {:?}", message);
+        }
+        tokio::time::timeout(TIMEOUT, &mut self.session).await
+            .expect("timed out waiting for the session to end")
+            .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bgp::capability::GracefulRestartFamily;
+    use crate::bgp::notification::{
+        BGPNotification, CEASE_ADMINISTRATIVE_RESET, CEASE_ADMINISTRATIVE_SHUTDOWN, CEASE_MAX_PREFIXES, CEASE_PEER_DECONFIGURED,
+        ERROR_CEASE, ERROR_OPEN_MESSAGE, ERROR_UPDATE_MESSAGE,
+        OPEN_BAD_PEER_AS, UPDATE_INVALID_NEXT_HOP, UPDATE_MALFORMED_ATTRIBUTE_LIST,
+    };
+    use crate::bgp::utils::path_attribute::{AttributeFlag, AttributeType, PathAttribute};
+    use crate::bgp::utils::prefix::Prefix;
+    use crate::config::Config;
+    use crate::control::{self, Request};
+    use crate::trap::TrapTable;
+
+    const NEIGHBOR: &str = "192.0.2.10";
+
+    fn shared(config: &str) -> Arc<Shared> {
+        let config: Config = toml::from_str(config).unwrap();
+        let traps = TrapTable::new(&config.trap);
+        Arc::new(Shared::new(Arc::new(config), traps))
+    }
+
+    fn prefixes(update: &BGPUpdate) -> Vec<Prefix> {
+        update.network_layer_reachability_information.iter().map(|nlri| nlri.prefix).collect()
+    }
+
+    fn update(prefixes: &[&str]) -> BGPMessage {
+        BGPMessage::Update(BGPUpdate {
+            withdrawn_routes: vec![],
+            path_attributes: vec![
+                PathAttribute { type_code: AttributeType::Origin, value: vec![0], flags: vec![AttributeFlag::Transitive] },
+                PathAttribute { type_code: AttributeType::ASPath, value: vec![2, 1, 0xFD, 0xE9], flags: vec![AttributeFlag::Transitive] },
+                PathAttribute { type_code: AttributeType::NextHop, value: vec![192, 0, 2, 10], flags: vec![AttributeFlag::Transitive] },
+            ],
+            network_layer_reachability_information: prefixes.iter().map(|prefix| prefix.parse::<Prefix>().unwrap().into()).collect(),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_initial_advertisement() {
+        let shared = shared(r#"
+            local-as = 65002
+            router-id = "192.0.2.1"
+
+            [trap]
+            next-hop = "192.0.2.66"
+            prefixes = ["10.0.0.1/32", "10.0.0.2/32"]
+        "#);
+        let mut peer = TestPeer::start(NEIGHBOR.parse().unwrap(), shared);
+        let open = peer.establish(open(65001, vec![])).await;
+        assert_eq!(open.sender_as, 65002);
+
+        let updates = peer.recv_initial_table().await;
+        let advertised: Vec<Prefix> = updates.iter().flat_map(prefixes).collect();
+        assert_eq!(advertised, vec!["10.0.0.1/32".parse().unwrap(), "10.0.0.2/32".parse().unwrap()]);
+        let next_hop = updates[0].path_attributes.iter().find(|attribute| attribute.type_code == AttributeType::NextHop).unwrap();
+        assert_eq!(next_hop.value, vec![192, 0, 2, 66]);
+    }
+
+    #[tokio::test]
+    async fn test_trap_injection() {
+        let shared = shared(r#"
+            local-as = 65002
+            router-id = "192.0.2.1"
+        "#);
+        let mut peer = TestPeer::start(NEIGHBOR.parse().unwrap(), shared.clone());
+        peer.establish(open(65001, vec![])).await;
+        peer.recv_initial_table().await;
+
+        let add = Request::TrapAdd { prefix: "198.51.100.1/32".parse().unwrap(), reason: None, ttl: None };
+        assert!(control::handle_request(add, &shared, &peer.commands).await.ok);
+        assert_eq!(prefixes(&peer.recv_update().await), vec!["198.51.100.1/32".parse().unwrap()]);
+
+        let del = Request::TrapDel { prefix: "198.51.100.1/32".parse().unwrap() };
+        assert!(control::handle_request(del, &shared, &peer.commands).await.ok);
+        let withdrawal = peer.recv_update().await;
+        assert_eq!(withdrawal.withdrawn_routes.iter().map(|nlri| nlri.prefix).collect::<Vec<_>>(), vec!["198.51.100.1/32".parse().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_peers() {
+        let shared = shared(r#"
+            local-as = 65002
+            router-id = "192.0.2.1"
+
+            [trap]
+            next-hop = "192.0.2.66"
+            prefixes = ["10.0.0.1/32"]
+        "#);
+        let (commands, _) = broadcast::channel(16);
+        let mut first = TestPeer::attach("192.0.2.10".parse().unwrap(), shared.clone(), &commands);
+        let mut second = TestPeer::attach("192.0.2.11".parse().unwrap(), shared.clone(), &commands);
+        // Not established yet, it gets the trap with its initial advertisement instead
+        let mut third = TestPeer::attach("192.0.2.12".parse().unwrap(), shared.clone(), &commands);
+        for peer in [&mut first, &mut second].iter_mut() {
+            peer.establish(open(65001, vec![])).await;
+            assert_eq!(peer.recv_initial_table().await.len(), 1);
+        }
+
+        let add = Request::TrapAdd { prefix: "198.51.100.1/32".parse().unwrap(), reason: None, ttl: None };
+        assert!(control::handle_request(add, &shared, &commands).await.ok);
+        for peer in [&mut first, &mut second].iter_mut() {
+            assert_eq!(prefixes(&peer.recv_update().await), vec!["198.51.100.1/32".parse().unwrap()]);
+        }
+
+        third.establish(open(65003, vec![])).await;
+        let advertised: Vec<Prefix> = third.recv_initial_table().await.iter().flat_map(prefixes).collect();
+        assert_eq!(advertised, vec!["10.0.0.1/32".parse().unwrap(), "198.51.100.1/32".parse().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn test_lagging_session_catches_up() {
+        let shared = shared(r#"
+            local-as = 65002
+            router-id = "192.0.2.1"
+        "#);
+        let mut peer = TestPeer::start(NEIGHBOR.parse().unwrap(), shared.clone());
+        peer.establish(open(65001, vec![])).await;
+        peer.recv_initial_table().await;
+
+        // The TrapsChanged for this change is pushed out of the channel before the session sees it
+        shared.traps.lock().unwrap().add_prefix("198.51.100.1/32".parse().unwrap());
+        let _ = peer.commands.send(SessionCommand::TrapsChanged);
+        for _ in 0..32 {
+            let _ = peer.commands.send(SessionCommand::ReportStatistics);
+        }
+        assert_eq!(prefixes(&peer.recv_update().await), vec!["198.51.100.1/32".parse().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn test_peer_as_mismatch() {
+        let shared = shared(r#"
+            local-as = 65002
+            router-id = "192.0.2.1"
+
+            [[neighbor]]
+            address = "192.0.2.10"
+            remote-as = 65001
+        "#);
+        let mut peer = TestPeer::start(NEIGHBOR.parse().unwrap(), shared);
+        peer.send(BGPMessage::Open(open(65003, vec![]))).await;
+        match peer.recv().await {
+            BGPMessage::Notification(notification) => {
+                assert_eq!((notification.error_code, notification.error_subcode), (ERROR_OPEN_MESSAGE, OPEN_BAD_PEER_AS));
+            },
+            message => panic!("expected a NOTIFICATION, received {:?}", message),
+        }
+        peer.expect_closed().await;
+    }
+
+    #[tokio::test]
+    async fn test_max_prefix() {
+        let shared = shared(r#"
+            local-as = 65002
+            router-id = "192.0.2.1"
+
+            [[neighbor]]
+            address = "127.0.0.1"
+            max-prefix = { limit = 2 }
+        "#);
+        let mut peer = TestPeer::connect(shared.clone()).await;
+        peer.establish(open(65001, vec![])).await;
+        peer.recv_initial_table().await;
+
+        peer.send(update(&["10.0.0.1/32", "10.0.0.2/32"])).await;
+        peer.send(update(&["10.0.0.3/32"])).await;
+        match peer.recv().await {
+            BGPMessage::Notification(BGPNotification { error_code, error_subcode, .. }) => {
+                assert_eq!((error_code, error_subcode), (ERROR_CEASE, CEASE_MAX_PREFIXES));
+            },
+            message => panic!("expected a NOTIFICATION, received {:?}", message),
+        }
+        peer.expect_closed().await;
+        assert!(shared.suspended.is_suspended("127.0.0.1".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_malformed_update() {
+        let shared = shared(r#"
+            local-as = 65002
+            router-id = "192.0.2.1"
+        "#);
+        let mut peer = TestPeer::start(NEIGHBOR.parse().unwrap(), shared.clone());
+        peer.establish(open(65001, vec![])).await;
+        peer.recv_initial_table().await;
+
+        // Withdrawn routes length pointing past the end of the message
+        let mut buf = update(&["10.0.0.1/32"]).encode(peer.codec_options);
+        buf[19..21].copy_from_slice(&[0xFF, 0xFF]);
+        peer.send_raw(&buf).await;
+        match peer.recv().await {
+            BGPMessage::Notification(notification) => {
+                assert_eq!((notification.error_code, notification.error_subcode), (ERROR_UPDATE_MESSAGE, UPDATE_MALFORMED_ATTRIBUTE_LIST));
+            },
+            message => panic!("expected a NOTIFICATION, received {:?}", message),
+        }
+        peer.expect_closed().await;
+        shared.metrics.update(NEIGHBOR.parse().unwrap(), |metrics| {
+            assert_eq!(metrics.decode_errors, 1);
+            assert_eq!(metrics.state, "Idle");
+            assert_eq!(metrics.notifications_sent.get(&(ERROR_UPDATE_MESSAGE, UPDATE_MALFORMED_ATTRIBUTE_LIST)), Some(&1));
+        });
+    }
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let shared = shared(r#"
+            local-as = 65002
+            router-id = "192.0.2.1"
+
+            [trap]
+            prefixes = ["10.0.0.1/32"]
+        "#);
+        let mut peer = TestPeer::start(NEIGHBOR.parse().unwrap(), shared);
+        peer.establish(open(65001, vec![])).await;
+        peer.recv_initial_table().await;
+
+        let _ = peer.commands.send(SessionCommand::Shutdown("Maintenance".to_string()));
+        let withdrawal = peer.recv_update().await;
+        assert_eq!(withdrawal.withdrawn_routes.iter().map(|nlri| nlri.prefix).collect::<Vec<_>>(), vec!["10.0.0.1/32".parse().unwrap()]);
+        match peer.recv().await {
+            BGPMessage::Notification(notification) => {
+                assert_eq!((notification.error_code, notification.error_subcode), (ERROR_CEASE, CEASE_ADMINISTRATIVE_SHUTDOWN));
+                assert_eq!(notification.shutdown_communication().unwrap(), "Maintenance");
+            },
+            message => panic!("expected a NOTIFICATION, received {:?}", message),
+        }
+        peer.expect_closed().await;
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_with_graceful_restart() {
+        let shared = shared(r#"
+            local-as = 65002
+            router-id = "192.0.2.1"
+
+            [graceful-restart]
+            restart-time = 120
+        "#);
+        let mut peer = TestPeer::start(NEIGHBOR.parse().unwrap(), shared);
+        let graceful_restart = Capability::GracefulRestart { restart_state: false, restart_time: 120, families: vec![] };
+        peer.establish(open(65001, vec![graceful_restart])).await;
+        peer.recv_initial_table().await;
+
+        // Closed without withdrawals or a NOTIFICATION so the neighbor keeps our routes
+        let _ = peer.commands.send(SessionCommand::Shutdown("Restarting".to_string()));
+        assert!(peer.try_recv().await.is_none());
+        peer.expect_closed().await;
+    }
+
+    #[tokio::test]
+    async fn test_neighbor_reset() {
+        let shared = shared(r#"
+            local-as = 65002
+            router-id = "192.0.2.1"
+        "#);
+        let mut peer = TestPeer::start(NEIGHBOR.parse().unwrap(), shared.clone());
+        peer.establish(open(65001, vec![])).await;
+        peer.recv_initial_table().await;
+
+        let other = Request::NeighborReset { address: "192.0.2.11".parse().unwrap(), message: String::new() };
+        assert!(control::handle_request(other, &shared, &peer.commands).await.ok);
+        let reset = Request::NeighborReset { address: NEIGHBOR.parse().unwrap(), message: String::new() };
+        assert!(control::handle_request(reset, &shared, &peer.commands).await.ok);
+        match peer.recv().await {
+            BGPMessage::Notification(notification) => {
+                assert_eq!((notification.error_code, notification.error_subcode), (ERROR_CEASE, CEASE_ADMINISTRATIVE_RESET));
+                assert!(notification.shutdown_communication().is_none());
+            },
+            message => panic!("expected a NOTIFICATION, received {:?}", message),
+        }
+        peer.expect_closed().await;
+        assert!(!shared.suspended.is_suspended(NEIGHBOR.parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_collect_tables() {
+        let shared = shared(r#"
+            local-as = 65002
+            router-id = "192.0.2.1"
+        "#);
+        let (commands, _) = broadcast::channel(16);
+        let mut established = TestPeer::attach(NEIGHBOR.parse().unwrap(), shared.clone(), &commands);
+        established.establish(open(65001, vec![])).await;
+        established.recv_initial_table().await;
+        let _idle = TestPeer::attach("192.0.2.11".parse().unwrap(), shared.clone(), &commands);
+        let mut closed = TestPeer::attach("192.0.2.12".parse().unwrap(), shared.clone(), &commands);
+        closed.send_raw(&[0; BGP_HEADER_SIZE]).await;
+        assert!(matches!(closed.recv().await, BGPMessage::Notification(_)));
+        closed.expect_closed().await;
+        // Receivers of the commands that are not sessions do not hold up the dump
+        let _receiver = commands.subscribe();
+
+        let tables = tokio::time::timeout(TIMEOUT, session::collect_tables(&shared)).await.unwrap();
+        let addresses: Vec<IpAddr> = tables.iter().map(|table| table.address).collect();
+        assert_eq!(addresses, vec![NEIGHBOR.parse::<IpAddr>().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn test_route_and_neighbor_list() {
+        let shared = shared(r#"
+            local-as = 65002
+            router-id = "192.0.2.1"
+        "#);
+        let mut peer = TestPeer::start(NEIGHBOR.parse().unwrap(), shared.clone());
+        peer.establish(open(65001, vec![])).await;
+        peer.recv_initial_table().await;
+        peer.send(update(&["10.0.0.1/32", "10.0.1.0/24"])).await;
+
+        // The update and the table dump reach the session independently
+        let list = |prefix: &str| Request::RouteList { prefix: Some(prefix.parse().unwrap()) };
+        let mut routes = vec![];
+        for _ in 0..100 {
+            routes = control::handle_request(list("10.0.0.0/24"), &shared, &peer.commands).await.routes.unwrap();
+            if !routes.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(routes.len(), 1);
+        assert_eq!(routes[0].prefix, "10.0.0.1/32".parse().unwrap());
+        assert_eq!(routes[0].next_hop, Some("192.0.2.10".parse().unwrap()));
+        assert_eq!(routes[0].as_path, "65001");
+        let all = Request::RouteList { prefix: None };
+        assert_eq!(control::handle_request(all, &shared, &peer.commands).await.routes.unwrap().len(), 2);
+
+        let neighbors = control::handle_request(Request::NeighborList, &shared, &peer.commands).await.neighbors.unwrap();
+        assert_eq!(neighbors.len(), 1);
+        assert_eq!((neighbors[0].state.as_str(), neighbors[0].remote_as), ("Established", 65001));
+        assert_eq!(neighbors[0].prefixes_received, 2);
+    }
+
+    #[tokio::test]
+    async fn test_next_hop() {
+        let shared = shared(r#"
+            local-as = 65002
+            router-id = "192.0.2.1"
+
+            [[neighbor]]
+            address = "127.0.0.1"
+            next-hop-self = true
+
+            [trap]
+            prefixes = ["10.0.0.1/32"]
+        "#);
+        let mut peer = TestPeer::connect(shared).await;
+        peer.establish(open(65001, vec![])).await;
+        let updates = peer.recv_initial_table().await;
+        let next_hop = updates[0].path_attributes.iter().find(|attribute| attribute.type_code == AttributeType::NextHop).unwrap();
+        assert_eq!(next_hop.value, vec![127, 0, 0, 1]);
+
+        // Our own address as the next hop
+        let mut message = update(&["10.0.1.0/24"]);
+        if let BGPMessage::Update(update) = &mut message {
+            update.path_attributes[2].value = vec![127, 0, 0, 1];
+        }
+        peer.send(message).await;
+        match peer.recv().await {
+            BGPMessage::Notification(notification) => {
+                assert_eq!((notification.error_code, notification.error_subcode), (ERROR_UPDATE_MESSAGE, UPDATE_INVALID_NEXT_HOP));
+            },
+            message => panic!("expected a NOTIFICATION, received {:?}", message),
+        }
+        peer.expect_closed().await;
+    }
+
+    #[tokio::test]
+    async fn test_route_server() {
+        let shared = shared(r#"
+            local-as = 65002
+            router-id = "192.0.2.1"
+
+            [[neighbor]]
+            address = "192.0.2.10"
+            route-server-client = true
+
+            [[neighbor]]
+            address = "192.0.2.11"
+            route-server-client = true
+        "#);
+        let (commands, _) = broadcast::channel(16);
+        let mut first = TestPeer::attach("192.0.2.10".parse().unwrap(), shared.clone(), &commands);
+        let mut second = TestPeer::attach("192.0.2.11".parse().unwrap(), shared.clone(), &commands);
+        first.establish(open(65001, vec![])).await;
+        first.recv_initial_table().await;
+        second.establish(open(65003, vec![])).await;
+        second.recv_initial_table().await;
+
+        first.send(update(&["10.0.1.0/24"])).await;
+        let update_received = second.recv_update().await;
+        assert_eq!(prefixes(&update_received), vec!["10.0.1.0/24".parse().unwrap()]);
+        // Passed on as received, without our AS or a different next hop
+        let attribute = |type_code| update_received.path_attributes.iter().find(|attribute| attribute.type_code == type_code).unwrap().value.clone();
+        assert_eq!(attribute(AttributeType::ASPath), vec![2, 1, 0xFD, 0xE9]);
+        assert_eq!(attribute(AttributeType::NextHop), vec![192, 0, 2, 10]);
+
+        // Not to be advertised to AS 65003
+        let mut not_to_second = update(&["10.0.1.0/24"]);
+        if let BGPMessage::Update(update) = &mut not_to_second {
+            update.path_attributes.push(PathAttribute::new(AttributeType::Communities, vec![0, 0, 0xFD, 0xEB]));
+        }
+        first.send(not_to_second).await;
+        let withdrawal = second.recv_update().await;
+        assert_eq!(withdrawal.withdrawn_routes.iter().map(|nlri| nlri.prefix).collect::<Vec<_>>(), vec!["10.0.1.0/24".parse().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn test_route_server_graceful_restart() {
+        let shared = shared(r#"
+            local-as = 65002
+            router-id = "192.0.2.1"
+
+            [graceful-restart]
+            restart-time = 120
+
+            [[neighbor]]
+            address = "192.0.2.10"
+            route-server-client = true
+
+            [[neighbor]]
+            address = "192.0.2.11"
+            route-server-client = true
+        "#);
+        let (commands, _) = broadcast::channel(16);
+        let mut first = TestPeer::attach("192.0.2.10".parse().unwrap(), shared.clone(), &commands);
+        let mut second = TestPeer::attach("192.0.2.11".parse().unwrap(), shared.clone(), &commands);
+        let graceful_restart = Capability::GracefulRestart {
+            restart_state: false,
+            restart_time: 1,
+            families: vec![GracefulRestartFamily { afi: AFI_IPV4, safi: SAFI_UNICAST, forwarding_preserved: true }],
+        };
+        first.establish(open(65001, vec![graceful_restart])).await;
+        first.recv_initial_table().await;
+        second.establish(open(65003, vec![])).await;
+        second.recv_initial_table().await;
+        first.send(update(&["10.0.1.0/24"])).await;
+        second.recv_update().await;
+
+        // The connection drops without a NOTIFICATION, the route stays until the restart time
+        // runs out
+        first.stream.shutdown().await.unwrap();
+        first.expect_closed().await;
+        assert_eq!(shared.route_server.routes_for("192.0.2.11".parse().unwrap(), 65003, 65002).len(), 1);
+        let withdrawal = second.recv_update().await;
+        assert_eq!(withdrawal.withdrawn_routes.iter().map(|nlri| nlri.prefix).collect::<Vec<_>>(), vec!["10.0.1.0/24".parse().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn test_route_reflector() {
+        let shared = shared(r#"
+            local-as = 65002
+            router-id = "192.0.2.1"
+
+            [[neighbor]]
+            address = "192.0.2.10"
+            route-reflector-client = true
+
+            [[neighbor]]
+            address = "192.0.2.11"
+        "#);
+        let (commands, _) = broadcast::channel(16);
+        let mut client = TestPeer::attach("192.0.2.10".parse().unwrap(), shared.clone(), &commands);
+        let mut non_client = TestPeer::attach("192.0.2.11".parse().unwrap(), shared.clone(), &commands);
+        // Both are in our AS, so the client needs a BGP identifier of its own
+        client.establish(BGPOpen::new(65002, 90, 0x0A00_0001, vec![])).await;
+        client.recv_initial_table().await;
+        non_client.establish(open(65002, vec![])).await;
+        non_client.recv_initial_table().await;
+
+        non_client.send(update(&["10.0.1.0/24"])).await;
+        let reflected = client.recv_update().await;
+        assert_eq!(prefixes(&reflected), vec!["10.0.1.0/24".parse().unwrap()]);
+        let attribute = |type_code| reflected.path_attributes.iter().find(|attribute| attribute.type_code == type_code).unwrap().value.clone();
+        // The non-client's BGP identifier from its OPEN
+        assert_eq!(attribute(AttributeType::OriginatorId), 0x0A00_FDEAu32.to_be_bytes().to_vec());
+        assert_eq!(attribute(AttributeType::ClusterList), vec![192, 0, 2, 1]);
+        assert_eq!(attribute(AttributeType::NextHop), vec![192, 0, 2, 10]);
+    }
+
+    #[tokio::test]
+    async fn test_reload() {
+        let path = std::env::temp_dir().join(format!("bgtrap-reload-{}.toml", std::process::id()));
+        let write_config = |export: &str, neighbors: &str| {
+            let config = format!(r#"
+                local-as = 65002
+                router-id = "192.0.2.1"
+
+                [trap]
+                prefixes = ["10.0.0.1/32"]
+
+                [policy.export]
+                default-action = "{}"
+                {}
+            "#, export, neighbors);
+            std::fs::write(&path, config).unwrap();
+        };
+        write_config("reject", r#"
+            [[neighbor]]
+            address = "192.0.2.10"
+            export-policy = "export"
+
+            [[neighbor]]
+            address = "192.0.2.11"
+        "#);
+        let config = Config::load(&path).unwrap();
+        let traps = TrapTable::new(&config.trap);
+        let mut shared = Shared::new(Arc::new(config), traps);
+        shared.config_path = Some(path.clone());
+        let shared = Arc::new(shared);
+
+        let (commands, _) = broadcast::channel(16);
+        let mut filtered = TestPeer::attach("192.0.2.10".parse().unwrap(), shared.clone(), &commands);
+        let mut removed = TestPeer::attach("192.0.2.11".parse().unwrap(), shared.clone(), &commands);
+        filtered.establish(open(65001, vec![])).await;
+        assert!(filtered.recv_initial_table().await.is_empty());
+        removed.establish(open(65001, vec![])).await;
+        removed.recv_initial_table().await;
+
+        write_config("accept", r#"
+            [[neighbor]]
+            address = "192.0.2.10"
+            export-policy = "export"
+        "#);
+        assert!(control::handle_request(Request::Reload, &shared, &commands).await.ok);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(prefixes(&filtered.recv_update().await), vec!["10.0.0.1/32".parse().unwrap()]);
+        match removed.recv().await {
+            BGPMessage::Notification(notification) => {
+                assert_eq!((notification.error_code, notification.error_subcode), (ERROR_CEASE, CEASE_PEER_DECONFIGURED));
+            },
+            message => panic!("expected a NOTIFICATION, received {:?}", message),
+        }
+        removed.expect_closed().await;
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_after_removal() {
+        let path = std::env::temp_dir().join(format!("bgtrap-removal-{}.toml", std::process::id()));
+        let write_config = |neighbors: &str| {
+            std::fs::write(&path, format!("local-as = 65002\nrouter-id = \"192.0.2.1\"\n{}", neighbors)).unwrap();
+        };
+        let neighbor = "[[neighbor]]\naddress = \"127.0.0.1\"\nremote-as = 65001\n";
+        write_config(neighbor);
+        let config = Config::load(&path).unwrap();
+        let traps = TrapTable::new(&config.trap);
+        let mut shared = Shared::new(Arc::new(config), traps);
+        shared.config_path = Some(path.clone());
+        let shared = Arc::new(shared);
+        let (commands, _) = broadcast::channel(16);
+
+        let mut peer = TestPeer::connect_with(shared.clone(), &commands).await;
+        peer.establish(open(65001, vec![])).await;
+        peer.recv_initial_table().await;
+        write_config("");
+        assert!(control::handle_request(Request::Reload, &shared, &commands).await.ok);
+        assert!(matches!(peer.recv().await, BGPMessage::Notification(_)));
+        peer.expect_closed().await;
+
+        // Refused instead of getting a session with the default settings
+        TestPeer::connect_with(shared.clone(), &commands).await.expect_closed().await;
+
+        write_config(neighbor);
+        assert!(control::handle_request(Request::Reload, &shared, &commands).await.ok);
+        std::fs::remove_file(&path).unwrap();
+        let mut peer = TestPeer::connect_with(shared.clone(), &commands).await;
+        peer.establish(open(65001, vec![])).await;
+    }
+}
@@ -0,0 +1,297 @@
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::bgp::SAFI_FLOWSPEC;
+use crate::bgp::update::BGPUpdate;
+use crate::bgp::utils::extended_community::compile_extended_communities;
+use crate::bgp::utils::flowspec::FlowSpecRule;
+use crate::bgp::utils::path_attribute::{AttributeFlag, AttributeType, PathAttribute};
+use crate::bgp::utils::prefix::Prefix;
+use crate::config::TrapConfig;
+use crate::rib::Route;
+
+#[derive(thiserror::Error, Debug)]
+pub enum TrapError {
+    #[error("Trap state file error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid trap state file: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+/// Why a prefix was trapped and until when, for traps added with this information
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TrapDetails {
+    pub prefix: Prefix,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// Seconds since the Unix epoch after which the trap is removed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<u64>,
+}
+
+/// Contents of the trap state file
+#[derive(Serialize, Deserialize)]
+struct TrapState {
+    prefixes: Vec<Prefix>,
+    #[serde(default)]
+    flowspec: Vec<FlowSpecRule>,
+    #[serde(default)]
+    details: Vec<TrapDetails>,
+}
+
+/// Prefixes BGtraP originates blackhole routes for, and FlowSpec rules for more selective
+/// filtering
+pub struct TrapTable {
+    next_hop: Ipv4Addr,
+    prefixes: Vec<Prefix>,
+    flowspec: Vec<FlowSpecRule>,
+    details: Vec<TrapDetails>,
+    state_file: Option<PathBuf>,
+    restored: bool,
+}
+
+impl TrapTable {
+    pub fn new(config: &TrapConfig) -> TrapTable {
+        TrapTable {
+            next_hop: config.next_hop,
+            prefixes: config.prefixes.clone(),
+            flowspec: vec![],
+            details: vec![],
+            state_file: config.state_file.clone(),
+            restored: false,
+        }
+    }
+
+    /// Creates the table from the configuration, adding any prefixes persisted in the state file
+    pub fn load(config: &TrapConfig) -> Result<TrapTable, TrapError> {
+        let mut table = TrapTable::new(config);
+        if let Some(path) = &table.state_file {
+            if path.exists() {
+                let state: TrapState = serde_json::from_slice(&std::fs::read(path)?)?;
+                for prefix in state.prefixes {
+                    if !table.prefixes.contains(&prefix) {
+                        table.prefixes.push(prefix);
+                    }
+                }
+                table.flowspec = state.flowspec;
+                table.details = state.details;
+                table.restored = true;
+            }
+        }
+        Ok(table)
+    }
+
+    /// Writes the table to the state file, if one is configured
+    pub fn save(&self) -> Result<(), TrapError> {
+        let path = match &self.state_file {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let state = TrapState { prefixes: self.prefixes.clone(), flowspec: self.flowspec.clone(), details: self.details.clone() };
+        let temporary = path.with_extension("tmp");
+        std::fs::write(&temporary, serde_json::to_vec_pretty(&state)?)?;
+        std::fs::rename(&temporary, path)?;
+        Ok(())
+    }
+
+    /// Whether the table was restored from a previous run, i.e. the daemon has restarted
+    pub fn restored(&self) -> bool {
+        self.restored
+    }
+
+    pub fn prefixes(&self) -> &[Prefix] {
+        &self.prefixes
+    }
+
+    /// Adds the prefix, returns false if it was already trapped
+    pub fn add_prefix(&mut self, prefix: Prefix) -> bool {
+        if self.prefixes.contains(&prefix) {
+            return false;
+        }
+        self.prefixes.push(prefix);
+        true
+    }
+
+    /// Removes the prefix, returns false if it was not trapped
+    pub fn remove_prefix(&mut self, prefix: &Prefix) -> bool {
+        let count = self.prefixes.len();
+        self.prefixes.retain(|trapped| trapped != prefix);
+        self.details.retain(|details| details.prefix != *prefix);
+        self.prefixes.len() != count
+    }
+
+    pub fn details(&self) -> &[TrapDetails] {
+        &self.details
+    }
+
+    /// Sets the reason and expiry of a trapped prefix, replacing what was set before
+    pub fn set_details(&mut self, details: TrapDetails) {
+        self.details.retain(|existing| existing.prefix != details.prefix);
+        if details.reason.is_some() || details.expires.is_some() {
+            self.details.push(details);
+        }
+    }
+
+    /// Removes the traps that have expired by `now` and returns their prefixes
+    pub fn expire(&mut self, now: SystemTime) -> Vec<Prefix> {
+        let now = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let expired: Vec<Prefix> = self.details.iter()
+            .filter(|details| details.expires.is_some_and(|expires| expires <= now))
+            .map(|details| details.prefix)
+            .collect();
+        for prefix in &expired {
+            self.remove_prefix(prefix);
+        }
+        expired
+    }
+
+    pub fn flowspec(&self) -> &[FlowSpecRule] {
+        &self.flowspec
+    }
+
+    /// Adds the rule, replacing the actions of an existing rule matching the same traffic
+    pub fn add_flowspec(&mut self, rule: FlowSpecRule) {
+        match self.flowspec.iter_mut().find(|existing| existing.same_match(&rule)) {
+            Some(existing) => *existing = rule,
+            None => self.flowspec.push(rule),
+        }
+    }
+
+    /// Removes the rule matching the same traffic, returns false if there was none
+    pub fn remove_flowspec(&mut self, rule: &FlowSpecRule) -> bool {
+        let count = self.flowspec.len();
+        self.flowspec.retain(|existing| !existing.same_match(rule));
+        self.flowspec.len() != count
+    }
+
+    /// Routes for all trapped prefixes as originated in our AS, before the AS path and
+    /// LOCAL_PREF are adjusted for each neighbor and its export policy is applied
+    pub fn routes(&self) -> Vec<Route> {
+        self.prefixes.iter().map(|prefix| Route {
+            prefix: *prefix,
+            path_id: 0,
+            path_attributes: vec![
+                PathAttribute { type_code: AttributeType::Origin, value: vec![2], flags: vec![AttributeFlag::Transitive]},
+                PathAttribute { type_code: AttributeType::ASPath, value: vec![], flags: vec![AttributeFlag::ExtendedLength, AttributeFlag::Transitive]},
+                PathAttribute { type_code: AttributeType::NextHop, value: self.next_hop.octets().to_vec(), flags: vec![AttributeFlag::Transitive]},
+                PathAttribute { type_code: AttributeType::LocalPref, value: vec![0, 0, 0, 100], flags: vec![AttributeFlag::Transitive]}
+            ],
+        }).collect()
+    }
+}
+
+/// UPDATE advertising a FlowSpec rule in MP_REACH_NLRI, without a next hop (RFC 8955)
+pub fn flowspec_update(rule: &FlowSpecRule) -> BGPUpdate {
+    let mut mp_reach = rule.afi().to_be_bytes().to_vec();
+    mp_reach.extend_from_slice(&[SAFI_FLOWSPEC, /* next hop length */ 0, /* reserved */ 0]);
+    mp_reach.extend(rule.compile_nlri());
+    let mut path_attributes = vec![
+        PathAttribute { type_code: AttributeType::Origin, value: vec![2], flags: vec![AttributeFlag::Transitive]},
+        PathAttribute { type_code: AttributeType::ASPath, value: vec![], flags: vec![AttributeFlag::ExtendedLength, AttributeFlag::Transitive]},
+        PathAttribute { type_code: AttributeType::LocalPref, value: vec![0, 0, 0, 100], flags: vec![AttributeFlag::Transitive]},
+        PathAttribute { type_code: AttributeType::MpReachNlri, value: mp_reach, flags: vec![AttributeFlag::Optional, AttributeFlag::ExtendedLength]},
+    ];
+    if !rule.actions.is_empty() {
+        path_attributes.push(PathAttribute {
+            type_code: AttributeType::ExtendedCommunities,
+            value: compile_extended_communities(&rule.extended_communities()),
+            flags: vec![AttributeFlag::Optional, AttributeFlag::Transitive],
+        });
+    }
+    BGPUpdate { withdrawn_routes: vec![], path_attributes, network_layer_reachability_information: vec![] }
+}
+
+/// UPDATE withdrawing FlowSpec rules of one address family in MP_UNREACH_NLRI
+pub fn flowspec_withdrawal(afi: u16, rules: &[FlowSpecRule]) -> BGPUpdate {
+    let mut mp_unreach = afi.to_be_bytes().to_vec();
+    mp_unreach.push(SAFI_FLOWSPEC);
+    for rule in rules {
+        mp_unreach.extend(rule.compile_nlri());
+    }
+    BGPUpdate {
+        withdrawn_routes: vec![],
+        path_attributes: vec![
+            PathAttribute { type_code: AttributeType::MpUnreachNlri, value: mp_unreach, flags: vec![AttributeFlag::Optional, AttributeFlag::ExtendedLength]},
+        ],
+        network_layer_reachability_information: vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bgp::AFI_IPV4;
+    use std::time::Duration;
+    use crate::bgp::utils::flowspec::FlowSpecAction;
+
+    #[test]
+    fn test_state_file() {
+        let state_file = std::env::temp_dir().join(format!("bgtrap-test-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&state_file);
+        let mut config = TrapConfig {
+            prefixes: vec!["10.0.0.1/32".parse().unwrap()],
+            state_file: Some(state_file.clone()),
+            ..Default::default()
+        };
+
+        let table = TrapTable::load(&config).unwrap();
+        assert!(!table.restored());
+        table.save().unwrap();
+
+        config.prefixes = vec!["10.0.0.2/32".parse().unwrap()];
+        let table = TrapTable::load(&config).unwrap();
+        assert!(table.restored());
+        assert_eq!(table.routes().iter().map(|route| route.prefix).collect::<Vec<_>>(), vec![
+            "10.0.0.2/32".parse().unwrap(),
+            "10.0.0.1/32".parse().unwrap(),
+        ]);
+
+        std::fs::remove_file(&state_file).unwrap();
+    }
+
+    #[test]
+    fn test_flowspec_rules() {
+        let mut table = TrapTable::new(&TrapConfig::default());
+        let rule = |actions| FlowSpecRule {
+            destination: Some("192.0.2.1/32".parse().unwrap()),
+            protocol: vec!["17".parse().unwrap()],
+            actions,
+            ..Default::default()
+        };
+        table.add_flowspec(rule(vec![FlowSpecAction::Discard]));
+        table.add_flowspec(rule(vec![FlowSpecAction::RateLimit(1000.0)]));
+        assert_eq!(table.flowspec(), &[rule(vec![FlowSpecAction::RateLimit(1000.0)])]);
+
+        let update = flowspec_update(&table.flowspec()[0]);
+        let mp_reach = update.path_attributes.iter().find(|attribute| attribute.type_code == AttributeType::MpReachNlri).unwrap();
+        assert_eq!(mp_reach.value, vec![0, 1, SAFI_FLOWSPEC, 0, 0, 9, 1, 32, 192, 0, 2, 1, 3, 0x81, 17]);
+        let withdrawal = flowspec_withdrawal(AFI_IPV4, table.flowspec());
+        assert_eq!(withdrawal.path_attributes[0].value, vec![0, 1, SAFI_FLOWSPEC, 9, 1, 32, 192, 0, 2, 1, 3, 0x81, 17]);
+
+        assert!(table.remove_flowspec(&rule(vec![])));
+        assert!(table.flowspec().is_empty());
+    }
+
+    #[test]
+    fn test_expiry() {
+        let mut table = TrapTable::new(&TrapConfig::default());
+        let now = SystemTime::now();
+        let expires = now.duration_since(UNIX_EPOCH).unwrap().as_secs() + 60;
+        for prefix in ["10.0.0.1/32", "10.0.0.2/32", "10.0.0.3/32"] {
+            table.add_prefix(prefix.parse().unwrap());
+        }
+        table.set_details(TrapDetails { prefix: "10.0.0.1/32".parse().unwrap(), reason: Some("DDoS".to_string()), expires: Some(expires) });
+        table.set_details(TrapDetails { prefix: "10.0.0.2/32".parse().unwrap(), reason: Some("Scanning".to_string()), expires: None });
+
+        assert!(table.expire(now).is_empty());
+        assert_eq!(table.expire(now + Duration::from_secs(60)), vec!["10.0.0.1/32".parse().unwrap()]);
+        assert_eq!(table.prefixes(), &["10.0.0.2/32".parse().unwrap(), "10.0.0.3/32".parse().unwrap()]);
+        assert_eq!(table.details().len(), 1);
+
+        assert!(table.remove_prefix(&"10.0.0.2/32".parse().unwrap()));
+        assert!(table.details().is_empty());
+    }
+}